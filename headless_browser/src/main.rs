@@ -4,8 +4,29 @@ use tikv_jemallocator::Jemalloc;
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
+use headless_browser_lib::config::{Config, ConfigError};
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     tracing_subscriber::fmt::init();
+
+    match Config::from_args(std::env::args().skip(1)) {
+        Ok(config) => {
+            headless_browser_lib::conf::set_config(config);
+        }
+        Err(ConfigError::Help) => {
+            println!("{}", ConfigError::Help);
+            return Ok(());
+        }
+        Err(e @ ConfigError::Flag(..)) => {
+            eprintln!("{}\n\n{}", e, ConfigError::Help);
+            std::process::exit(2);
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    }
+
     headless_browser_lib::run_main().await
 }
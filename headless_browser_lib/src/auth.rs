@@ -0,0 +1,459 @@
+use crate::config::Config;
+use hmac::{Hmac, Mac};
+use hyper::header::AUTHORIZATION;
+use hyper::{HeaderMap, Method, Uri};
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The query param of the signed url expiry in unix seconds.
+pub const EXPIRES_PARAM: &str = "expires";
+
+/// The query param of the signed url signature.
+pub const SIGNATURE_PARAM: &str = "signature";
+
+/// The access scope of a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Scope {
+    /// The read-only management routes.
+    Read,
+    /// The mutating management routes.
+    Write,
+    /// The CDP proxy.
+    Proxy,
+}
+
+impl Scope {
+    /// The scope of the management route. The DevTools routes opening, focusing, or closing targets mutate with any method.
+    pub(crate) fn of(method: &Method, path: &str) -> Self {
+        let target = path == "/json/new"
+            || path.starts_with("/json/activate/")
+            || path.starts_with("/json/close/");
+
+        if !target && (method == Method::GET || method == Method::HEAD) {
+            Scope::Read
+        } else {
+            Scope::Write
+        }
+    }
+}
+
+/// How a request was authorized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Grant {
+    /// The scope does not require authentication.
+    Open,
+    /// A bearer token of a key of the scope.
+    Bearer,
+    /// A signed url of a key of the scope.
+    Signed,
+}
+
+impl Grant {
+    /// The path and query to forward. Only the signed urls lose the signed url params, the other requests are forwarded as sent.
+    pub(crate) fn forwarded_path(self, uri: &Uri) -> String {
+        match self {
+            Grant::Signed => strip_signature(uri),
+            _ => uri
+                .path_and_query()
+                .map_or_else(|| uri.path().to_string(), |path| path.as_str().to_string()),
+        }
+    }
+}
+
+/// The hex HMAC-SHA256 signature of the request with the key. The signed message is `{METHOD} {path} {expires}` where the path
+/// includes the query without the signed url params ex: `GET /json/new?https://example.com 1767225600`.
+pub fn sign(key: &str, method: &str, path: &str, expires: u64) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("hmac takes any key size");
+    mac.update(format!("{} {} {}", method, path, expires).as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// The signed url of the request valid until the unix time expires. The path can include a query.
+pub fn sign_url(key: &str, method: &str, path: &str, expires: u64) -> String {
+    format!(
+        "{}{}{}={}&{}={}",
+        path,
+        if path.contains('?') { '&' } else { '?' },
+        EXPIRES_PARAM,
+        expires,
+        SIGNATURE_PARAM,
+        sign(key, method, path, expires)
+    )
+}
+
+/// The keys accepted for the scope. Empty when the scope does not require authentication.
+fn keys(config: &Config, scope: Scope) -> Vec<&String> {
+    match scope {
+        Scope::Read => config
+            .api_keys
+            .iter()
+            .chain(&config.api_write_keys)
+            .collect(),
+        Scope::Write if !config.api_write_keys.is_empty() => config.api_write_keys.iter().collect(),
+        Scope::Write => config.api_keys.iter().collect(),
+        Scope::Proxy => config.proxy_keys.iter().collect(),
+    }
+}
+
+/// Authorize the request with a bearer token or a signed url of a key of the scope. None when the request is not authorized.
+pub(crate) fn authorize(
+    config: &Config,
+    scope: Scope,
+    method: &Method,
+    uri: &Uri,
+    headers: &HeaderMap,
+) -> Option<Grant> {
+    let keys = keys(config, scope);
+
+    if keys.is_empty() {
+        return Some(Grant::Open);
+    }
+
+    let bearer = headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim_start().split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+        .map(|(_, token)| token.trim());
+
+    if let Some(token) = bearer {
+        return keys
+            .iter()
+            .any(|key| constant_time_eq(key.as_bytes(), token.as_bytes()))
+            .then_some(Grant::Bearer);
+    }
+
+    let (mut expires, mut signature) = (None, None);
+
+    for (name, value) in uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+    {
+        match name {
+            EXPIRES_PARAM => expires = value.parse::<u64>().ok(),
+            SIGNATURE_PARAM => signature = Some(value),
+            _ => (),
+        }
+    }
+
+    let (expires, signature) = match (expires, signature) {
+        (Some(expires), Some(signature)) => (expires, signature),
+        _ => return None,
+    };
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+
+    // the query is signed so the params of the url cannot be swapped.
+    let path = strip_signature(uri);
+
+    let signed = expires >= now
+        && keys.iter().any(|key| {
+            let expected = sign(key, method.as_str(), &path, expires);
+            constant_time_eq(expected.as_bytes(), signature.as_bytes())
+        });
+
+    signed.then_some(Grant::Signed)
+}
+
+/// The path and query without the signed url params.
+pub(crate) fn strip_signature(uri: &Uri) -> String {
+    let query: Vec<&str> = uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| {
+            let name = pair.split_once('=').map_or(*pair, |(name, _)| name);
+            !pair.is_empty() && name != EXPIRES_PARAM && name != SIGNATURE_PARAM
+        })
+        .collect();
+
+    if query.is_empty() {
+        uri.path().to_string()
+    } else {
+        format!("{}?{}", uri.path(), query.join("&"))
+    }
+}
+
+/// Compare the bytes in constant time for equal lengths.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    /// The config with a read key, a write key, and a proxy key.
+    fn config() -> Config {
+        Config {
+            api_keys: vec!["read".into()],
+            api_write_keys: vec!["write".into()],
+            proxy_keys: vec!["proxy".into()],
+            ..Default::default()
+        }
+    }
+
+    /// The unix time in seconds from now.
+    fn from_now(seconds: i64) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        now.saturating_add_signed(seconds)
+    }
+
+    /// The headers with the authorization value.
+    fn bearer(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_static(value));
+        headers
+    }
+
+    /// Authorize the request.
+    fn allowed(scope: Scope, method: Method, uri: &str, headers: &HeaderMap) -> bool {
+        authorize(&config(), scope, &method, &uri.parse().unwrap(), headers).is_some()
+    }
+
+    #[test]
+    fn no_keys_allows() {
+        let uri = "/instances".parse().unwrap();
+
+        assert_eq!(
+            authorize(
+                &Config::default(),
+                Scope::Write,
+                &Method::POST,
+                &uri,
+                &HeaderMap::new()
+            ),
+            Some(Grant::Open)
+        );
+    }
+
+    #[test]
+    fn missing_header() {
+        assert!(!allowed(
+            Scope::Read,
+            Method::GET,
+            "/instances",
+            &HeaderMap::new()
+        ));
+        assert!(!allowed(
+            Scope::Read,
+            Method::GET,
+            "/instances",
+            &bearer("Bearer")
+        ));
+        assert!(!allowed(
+            Scope::Read,
+            Method::GET,
+            "/instances",
+            &bearer("Basic read")
+        ));
+    }
+
+    #[test]
+    fn bearer_scopes() {
+        assert!(allowed(
+            Scope::Read,
+            Method::GET,
+            "/instances",
+            &bearer("Bearer read")
+        ));
+        assert!(allowed(
+            Scope::Read,
+            Method::GET,
+            "/instances",
+            &bearer("Bearer write")
+        ));
+        assert!(allowed(
+            Scope::Write,
+            Method::POST,
+            "/fork",
+            &bearer("Bearer write")
+        ));
+        assert!(!allowed(
+            Scope::Write,
+            Method::POST,
+            "/fork",
+            &bearer("Bearer read")
+        ));
+        assert!(!allowed(
+            Scope::Proxy,
+            Method::GET,
+            "/",
+            &bearer("Bearer read")
+        ));
+        assert!(!allowed(
+            Scope::Read,
+            Method::GET,
+            "/instances",
+            &bearer("Bearer reads")
+        ));
+    }
+
+    #[test]
+    fn bearer_scheme_is_case_insensitive() {
+        assert!(allowed(
+            Scope::Read,
+            Method::GET,
+            "/instances",
+            &bearer("bearer read")
+        ));
+        assert!(allowed(
+            Scope::Read,
+            Method::GET,
+            "/instances",
+            &bearer("BEARER  read ")
+        ));
+    }
+
+    #[test]
+    fn signed_urls() {
+        let expires = from_now(60);
+        let url = sign_url("write", "POST", "/fork", expires);
+        let none = HeaderMap::new();
+
+        assert!(allowed(Scope::Write, Method::POST, &url, &none));
+        // the signature is bound to the method.
+        assert!(!allowed(Scope::Write, Method::PUT, &url, &none));
+        // read keys cannot sign the write routes.
+        let url = sign_url("read", "POST", "/fork", expires);
+        assert!(!allowed(Scope::Write, Method::POST, &url, &none));
+    }
+
+    #[test]
+    fn expired_signature() {
+        let url = sign_url("read", "GET", "/instances", from_now(-1));
+
+        assert!(!allowed(Scope::Read, Method::GET, &url, &HeaderMap::new()));
+    }
+
+    #[test]
+    fn tampered_path_and_query() {
+        let expires = from_now(60);
+        let signature = sign("write", "PUT", "/json/new?https://a.com", expires);
+        let none = HeaderMap::new();
+        let url = |path: &str| {
+            format!(
+                "{}&{}={}&{}={}",
+                path, EXPIRES_PARAM, expires, SIGNATURE_PARAM, signature
+            )
+        };
+
+        assert!(allowed(
+            Scope::Write,
+            Method::PUT,
+            &url("/json/new?https://a.com"),
+            &none
+        ));
+        assert!(!allowed(
+            Scope::Write,
+            Method::PUT,
+            &url("/json/new?https://b.com"),
+            &none
+        ));
+        assert!(!allowed(
+            Scope::Write,
+            Method::PUT,
+            &url("/json/close?https://a.com"),
+            &none
+        ));
+
+        let url = format!(
+            "/json/new?{}={}&{}={}",
+            EXPIRES_PARAM,
+            expires + 1,
+            SIGNATURE_PARAM,
+            signature
+        );
+        assert!(!allowed(Scope::Write, Method::PUT, &url, &none));
+    }
+
+    #[test]
+    fn sign_url_with_query() {
+        let url = sign_url("read", "GET", "/json/new?https://a.com", 1);
+
+        assert!(url.starts_with("/json/new?https://a.com&expires=1&signature="));
+    }
+
+    #[test]
+    fn strip_signature_params() {
+        let strip = |uri: &str| strip_signature(&uri.parse().unwrap());
+
+        assert_eq!(strip("/json/version"), "/json/version");
+        assert_eq!(
+            strip("/json/version?expires=1&signature=ab"),
+            "/json/version"
+        );
+        assert_eq!(
+            strip("/json/new?https://a.com&expires=1&signature=ab"),
+            "/json/new?https://a.com"
+        );
+        assert_eq!(strip("/a?x=1&&signature=ab&y"), "/a?x=1&y");
+    }
+
+    #[test]
+    fn only_signed_requests_lose_the_signature() {
+        let uri: Uri = "/json/new?https://a.com/?expires=1&&signature=2"
+            .parse()
+            .unwrap();
+
+        assert_eq!(
+            Grant::Open.forwarded_path(&uri),
+            "/json/new?https://a.com/?expires=1&&signature=2"
+        );
+        assert_eq!(
+            Grant::Bearer.forwarded_path(&uri),
+            "/json/new?https://a.com/?expires=1&&signature=2"
+        );
+        assert_eq!(
+            Grant::Signed.forwarded_path(&uri),
+            "/json/new?https://a.com/?expires=1"
+        );
+
+        let expires = from_now(60);
+        let url = sign_url("write", "PUT", "/json/new?https://a.com", expires);
+        let uri: Uri = url.parse().unwrap();
+        let grant = authorize(
+            &config(),
+            Scope::Write,
+            &Method::PUT,
+            &uri,
+            &HeaderMap::new(),
+        );
+
+        assert_eq!(grant, Some(Grant::Signed));
+        assert_eq!(
+            grant.unwrap().forwarded_path(&uri),
+            "/json/new?https://a.com"
+        );
+
+        let grant = authorize(
+            &config(),
+            Scope::Write,
+            &Method::PUT,
+            &uri,
+            &bearer("Bearer write"),
+        );
+
+        assert_eq!(grant, Some(Grant::Bearer));
+    }
+
+    #[test]
+    fn scope_of_route() {
+        assert_eq!(Scope::of(&Method::GET, "/instances"), Scope::Read);
+        assert_eq!(Scope::of(&Method::HEAD, "/json/version"), Scope::Read);
+        assert_eq!(Scope::of(&Method::GET, "/json/list"), Scope::Read);
+        assert_eq!(Scope::of(&Method::POST, "/fork"), Scope::Write);
+        assert_eq!(Scope::of(&Method::PUT, "/json/new"), Scope::Write);
+        assert_eq!(Scope::of(&Method::GET, "/json/new"), Scope::Write);
+        assert_eq!(Scope::of(&Method::GET, "/json/activate/A"), Scope::Write);
+        assert_eq!(Scope::of(&Method::GET, "/json/close/A"), Scope::Write);
+    }
+}
@@ -0,0 +1,158 @@
+use crate::pool::Instance;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The load balancing strategy used to pick the instance for a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    /// Rotate through the instances in port order.
+    RoundRobin,
+    /// Pick the instance with the fewest active proxied connections.
+    #[default]
+    LeastConnections,
+    /// Pick two random instances and use the one with fewer active connections.
+    RandomTwoChoices,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().replace('_', "-").as_str() {
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "least-connections" => Ok(Strategy::LeastConnections),
+            "random-two-choices" => Ok(Strategy::RandomTwoChoices),
+            s => Err(format!("unknown load balancer strategy: {}", s)),
+        }
+    }
+}
+
+impl Strategy {
+    /// Select the index of the instance to use from the candidates.
+    pub fn select(&self, candidates: &[Instance], cursor: &AtomicUsize) -> Option<usize> {
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let index = match self {
+            Strategy::RoundRobin => cursor.fetch_add(1, Ordering::Relaxed) % candidates.len(),
+            Strategy::LeastConnections => {
+                // rotate the start so ties spread across the instances.
+                let offset = cursor.fetch_add(1, Ordering::Relaxed);
+                let len = candidates.len();
+
+                (0..len)
+                    .map(|i| (offset + i) % len)
+                    .min_by_key(|i| candidates[*i].active_connections())
+                    .unwrap_or_default()
+            }
+            Strategy::RandomTwoChoices => {
+                let a = rand::random_range(0..candidates.len());
+                let b = rand::random_range(0..candidates.len());
+
+                if candidates[b].active_connections() < candidates[a].active_connections() {
+                    b
+                } else {
+                    a
+                }
+            }
+        };
+
+        Some(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool::{Lease, Pool};
+
+    /// The pool with the instances and the leases held on each.
+    fn pool(active: &[usize]) -> (Vec<Instance>, Vec<Lease>) {
+        let pool = Pool::new(9300..=9310, &[], Strategy::default());
+        let mut leases = vec![];
+
+        for (index, active) in active.iter().enumerate() {
+            let pid = index as u32 + 1;
+            pool.insert(
+                pid,
+                9300 + pid,
+                Default::default(),
+                Default::default(),
+                None,
+            );
+
+            for _ in 0..*active {
+                leases.push(pool.acquire_instance(pid).unwrap());
+            }
+        }
+
+        (pool.instances(), leases)
+    }
+
+    /// The indexes selected in turn.
+    fn picks(strategy: Strategy, candidates: &[Instance], times: usize) -> Vec<usize> {
+        let cursor = AtomicUsize::new(0);
+
+        (0..times)
+            .map(|_| strategy.select(candidates, &cursor).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn round_robin_rotates_in_order() {
+        let (candidates, _leases) = pool(&[3, 0, 1]);
+
+        assert_eq!(picks(Strategy::RoundRobin, &candidates, 5), [0, 1, 2, 0, 1]);
+    }
+
+    #[test]
+    fn least_connections_rotates_the_ties() {
+        let (candidates, _leases) = pool(&[0, 0, 0]);
+        assert_eq!(
+            picks(Strategy::LeastConnections, &candidates, 4),
+            [0, 1, 2, 0]
+        );
+
+        let (candidates, _leases) = pool(&[2, 0, 1, 0]);
+        assert_eq!(
+            picks(Strategy::LeastConnections, &candidates, 4),
+            [1, 1, 3, 3]
+        );
+    }
+
+    #[test]
+    fn random_two_choices_prefers_the_idle_instance() {
+        let (candidates, _leases) = pool(&[5, 0]);
+        let picks = picks(Strategy::RandomTwoChoices, &candidates, 200);
+        let idle = picks.iter().filter(|index| **index == 1).count();
+
+        // the busy instance only wins when both choices land on it.
+        assert!(idle > 100, "{} of 200", idle);
+    }
+
+    #[test]
+    fn no_candidates_select_nothing() {
+        let cursor = AtomicUsize::new(0);
+
+        for strategy in [
+            Strategy::RoundRobin,
+            Strategy::LeastConnections,
+            Strategy::RandomTwoChoices,
+        ] {
+            assert_eq!(strategy.select(&[], &cursor), None);
+        }
+    }
+
+    #[test]
+    fn strategies_parse_by_name() {
+        assert_eq!("round_robin".parse(), Ok(Strategy::RoundRobin));
+        assert_eq!(
+            " Least-Connections ".parse(),
+            Ok(Strategy::LeastConnections)
+        );
+        assert_eq!("random-two-choices".parse(), Ok(Strategy::RandomTwoChoices));
+        assert!("fastest".parse::<Strategy>().is_err());
+    }
+}
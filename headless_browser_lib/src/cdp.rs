@@ -0,0 +1,571 @@
+use crate::auth::{self, Grant, Scope};
+use crate::connect_with_retries;
+use crate::policy::{self, Command};
+use crate::pool::Lease;
+use crate::routing;
+use crate::Server;
+use futures_util::{SinkExt, StreamExt};
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, UPGRADE};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::convert::Infallible;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Role, WebSocketConfig};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+/// The websocket to chrome.
+type ChromeSocket = WebSocketStream<TcpStream>;
+
+/// The websocket to the client.
+type ClientSocket = WebSocketStream<TokioIo<hyper::upgrade::Upgraded>>;
+
+/// Serve the proxy connection. The websocket upgrades are terminated and the other requests are forwarded to chrome.
+pub(crate) async fn serve_connection<S>(server: Server, stream: S)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = service_fn(move |req| {
+        let server = server.clone();
+        async move { Ok::<_, Infallible>(handle_request(server, req).await) }
+    });
+
+    if let Err(e) = http1::Builder::new()
+        .serve_connection(TokioIo::new(stream), service)
+        .with_upgrades()
+        .await
+    {
+        tracing::debug!("CDP proxy connection closed: {}", e);
+    }
+}
+
+/// Route the request to the session upgrade or the http forwarder.
+async fn handle_request(server: Server, mut req: Request<Incoming>) -> Response<Full<Bytes>> {
+    let grant = match auth::authorize(
+        &server.config,
+        Scope::Proxy,
+        req.method(),
+        req.uri(),
+        req.headers(),
+    ) {
+        Some(grant) => grant,
+        _ => {
+            server.metrics.auth_rejected.inc();
+            return crate::unauthorized();
+        }
+    };
+
+    // the credentials are not forwarded to chrome.
+    match grant {
+        Grant::Bearer => {
+            req.headers_mut().remove(hyper::header::AUTHORIZATION);
+        }
+        Grant::Signed => {
+            if let Ok(uri) = grant.forwarded_path(req.uri()).parse() {
+                *req.uri_mut() = uri;
+            }
+        }
+        Grant::Open => (),
+    }
+
+    let upgrade = req
+        .headers()
+        .get(UPGRADE)
+        .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"websocket"));
+
+    if upgrade {
+        upgrade_session(server, req).await
+    } else {
+        forward_request(server, req).await
+    }
+}
+
+/// Plain text response with the status.
+fn text_response(status: StatusCode, body: &'static str) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::from(body)));
+    *response.status_mut() = status;
+    response
+}
+
+/// Replace the instance after chrome refused the connection.
+fn replace_failed(server: &Server, pid: Option<u32>) {
+    match pid {
+        Some(pid) => {
+            server.drain_and_replace(pid);
+        }
+        _ => {
+            if server.instances.is_empty() {
+                server.fork(None);
+            }
+        }
+    }
+}
+
+/// Forward the http request to an instance. The request does not count as a session.
+async fn forward_request(server: Server, req: Request<Incoming>) -> Response<Full<Bytes>> {
+    let instance = server.instances.select();
+    let port = instance
+        .as_ref()
+        .map_or(server.config.chrome_port, |instance| instance.port);
+
+    let stream = match connect_with_retries(&server, &format!("127.0.0.1:{}", port)).await {
+        Some(stream) => stream,
+        _ => {
+            replace_failed(&server, instance.map(|instance| instance.pid));
+            return text_response(StatusCode::BAD_GATEWAY, "chrome is not reachable");
+        }
+    };
+
+    let (mut client, conn) = match hyper::client::conn::http1::handshake(TokioIo::new(stream)).await
+    {
+        Ok(handshake) => handshake,
+        Err(_) => return text_response(StatusCode::BAD_GATEWAY, "chrome is not reachable"),
+    };
+
+    tokio::spawn(async move {
+        let _ = conn.await;
+    });
+
+    let response = match client.send_request(req).await {
+        Ok(response) => response,
+        Err(_) => return text_response(StatusCode::BAD_GATEWAY, "chrome closed the request"),
+    };
+
+    let (parts, body) = response.into_parts();
+
+    match body.collect().await {
+        Ok(body) => {
+            let body = body.to_bytes();
+            server.metrics.bytes_to_client.add(body.len() as u64);
+            Response::from_parts(parts, Full::new(body))
+        }
+        Err(_) => text_response(StatusCode::BAD_GATEWAY, "chrome closed the request"),
+    }
+}
+
+/// The websocket limits of both sides of a session. The frames and messages can reach the max message size.
+fn socket_config(server: &Server) -> WebSocketConfig {
+    let max = server.config.cdp_max_message_mb.saturating_mul(1024 * 1024);
+
+    WebSocketConfig::default()
+        .max_message_size(Some(max))
+        .max_frame_size(Some(max))
+}
+
+/// Open the CDP session on an instance and upgrade the client connection.
+async fn upgrade_session(server: Server, mut req: Request<Incoming>) -> Response<Full<Bytes>> {
+    let accept = match req.headers().get(SEC_WEBSOCKET_KEY) {
+        Some(key) => derive_accept_key(key.as_bytes()),
+        _ => return text_response(StatusCode::BAD_REQUEST, "missing sec-websocket-key"),
+    };
+
+    let lease = match routing::acquire(&server, req.uri().path()).await {
+        Some(lease) => lease,
+        _ => return text_response(StatusCode::SERVICE_UNAVAILABLE, "no chrome instance"),
+    };
+
+    let max_sessions = server.config.cdp_max_sessions;
+
+    // the lease counts as an active connection until the session is accepted.
+    if max_sessions > 0 && lease.active_connections().saturating_sub(1) >= max_sessions {
+        return text_response(StatusCode::SERVICE_UNAVAILABLE, "too many sessions");
+    }
+
+    let path = req
+        .uri()
+        .path_and_query()
+        .map_or("/", |path| path.as_str())
+        .to_string();
+
+    let address = format!("127.0.0.1:{}", lease.port);
+
+    let chrome = match connect_with_retries(&server, &address).await {
+        Some(stream) => {
+            match tokio_tungstenite::client_async_with_config(
+                format!("ws://{}{}", address, path),
+                stream,
+                Some(socket_config(&server)),
+            )
+            .await
+            {
+                Ok((chrome, _)) => chrome,
+                Err(e) => {
+                    tracing::error!("CDP upgrade of {} failed: {}", path, e);
+                    return text_response(StatusCode::BAD_GATEWAY, "chrome refused the session");
+                }
+            }
+        }
+        _ => {
+            server.instances.mark_healthy(lease.port, false);
+            replace_failed(&server, Some(lease.pid));
+            return text_response(StatusCode::BAD_GATEWAY, "chrome is not reachable");
+        }
+    };
+
+    server.instances.mark_running(lease.port);
+    lease.accept();
+
+    let on_upgrade = hyper::upgrade::on(&mut req);
+
+    tokio::spawn(async move {
+        match on_upgrade.await {
+            Ok(upgraded) => {
+                let client = WebSocketStream::from_raw_socket(
+                    TokioIo::new(upgraded),
+                    Role::Server,
+                    Some(socket_config(&server)),
+                )
+                .await;
+
+                run_session(server, lease, path, client, chrome).await;
+            }
+            Err(e) => tracing::error!("CDP client upgrade failed: {}", e),
+        }
+    });
+
+    let mut response = text_response(StatusCode::SWITCHING_PROTOCOLS, "");
+    let headers = response.headers_mut();
+
+    headers.insert(
+        CONNECTION,
+        hyper::header::HeaderValue::from_static("Upgrade"),
+    );
+    headers.insert(
+        UPGRADE,
+        hyper::header::HeaderValue::from_static("websocket"),
+    );
+
+    if let Ok(accept) = accept.parse() {
+        headers.insert(SEC_WEBSOCKET_ACCEPT, accept);
+    }
+
+    response
+}
+
+/// Forward the CDP messages between the client and chrome until either side closes.
+/// The client receives a close frame when chrome goes away so recycled browsers end the sessions cleanly.
+async fn run_session(
+    server: Server,
+    lease: Lease,
+    path: String,
+    client: ClientSocket,
+    chrome: ChromeSocket,
+) {
+    server.metrics.cdp_sessions.inc();
+    tracing::info!("CDP session {} opened on chrome PID: {}", path, lease.pid);
+
+    let (mut client_tx, mut client_rx) = client.split();
+    let (mut chrome_tx, mut chrome_rx) = chrome.split();
+    let policy = policy::enabled(&server.config);
+
+    loop {
+        tokio::select! {
+            message = client_rx.next() => {
+                let message = match message {
+                    Some(Ok(message)) => message,
+                    _ => {
+                        let _ = chrome_tx.close().await;
+                        break;
+                    }
+                };
+
+                let data: &[u8] = match &message {
+                    Message::Text(text) => text.as_bytes(),
+                    Message::Binary(data) => data,
+                    // pings are answered by each side of the proxy.
+                    Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => continue,
+                    Message::Close(_) => {
+                        let _ = chrome_tx.send(message).await;
+                        break;
+                    }
+                };
+
+                server.metrics.bytes_to_chrome.add(data.len() as u64);
+
+                let command = serde_json::from_slice::<Command>(data);
+
+                if let Ok(command) = &command {
+                    tracing::debug!(
+                        "CDP {} {} on chrome PID: {}",
+                        command.id.as_ref().map(|id| id.to_string()).unwrap_or_default(),
+                        command.method.as_deref().unwrap_or_default(),
+                        lease.pid
+                    );
+                }
+
+                if policy {
+                    // messages the policy cannot read are rejected instead of trusting chrome to parse them the same way.
+                    let rejected = match &command {
+                        Ok(command) => policy::check(&server.config, command)
+                            .err()
+                            .map(|reason| policy::error_response(command, &reason)),
+                        _ => Some(policy::error_response(&Command::default(), "invalid CDP message")),
+                    };
+
+                    if let Some(response) = rejected {
+                        server.metrics.cdp_denied.inc();
+                        tracing::warn!("CDP command rejected on chrome PID: {} {}", lease.pid, response);
+
+                        if client_tx.send(Message::text(response)).await.is_err() {
+                            let _ = chrome_tx.close().await;
+                            break;
+                        }
+                        continue;
+                    }
+                }
+
+                server.metrics.cdp_messages_to_chrome.inc();
+
+                if chrome_tx.send(message).await.is_err() {
+                    close_client(&mut client_tx).await;
+                    break;
+                }
+            },
+            message = chrome_rx.next() => {
+                let message = match message {
+                    Some(Ok(message)) => message,
+                    _ => {
+                        close_client(&mut client_tx).await;
+                        break;
+                    }
+                };
+
+                match &message {
+                    Message::Text(text) => server.metrics.bytes_to_client.add(text.len() as u64),
+                    Message::Binary(data) => server.metrics.bytes_to_client.add(data.len() as u64),
+                    Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => continue,
+                    Message::Close(_) => {
+                        close_client(&mut client_tx).await;
+                        break;
+                    }
+                }
+
+                server.metrics.cdp_messages_to_client.inc();
+
+                if client_tx.send(message).await.is_err() {
+                    let _ = chrome_tx.close().await;
+                    break;
+                }
+            },
+        }
+    }
+
+    tracing::info!("CDP session {} closed on chrome PID: {}", path, lease.pid);
+}
+
+/// Close the client session after chrome went away.
+async fn close_client<S>(client_tx: &mut S)
+where
+    S: SinkExt<Message> + Unpin,
+{
+    let frame = CloseFrame {
+        code: CloseCode::Away,
+        reason: "browser closed".into(),
+    };
+
+    let _ = client_tx.send(Message::Close(Some(frame))).await;
+    let _ = client_tx.close().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, ProxyMode};
+    use crate::testing::FakeChrome;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// The cdp proxy server of the fake chrome.
+    fn server(chrome: &FakeChrome, proxy_keys: &[&str]) -> Server {
+        Server::new(Config {
+            chrome_port: chrome.port,
+            proxy_mode: ProxyMode::Cdp,
+            proxy_keys: proxy_keys.iter().map(|key| key.to_string()).collect(),
+            ..Default::default()
+        })
+    }
+
+    /// Send the raw request through the proxy and read the response.
+    async fn send(server: Server, request: &str) -> String {
+        let (mut client, stream) = tokio::io::duplex(64 * 1024);
+
+        tokio::spawn(serve_connection(server, stream));
+
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = vec![];
+        client.read_to_end(&mut response).await.unwrap();
+
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    #[tokio::test]
+    async fn unsigned_uri_is_forwarded_as_sent() {
+        let chrome = FakeChrome::start("a").await;
+        let path = "/json/version?a=1&&expires=2&signature=3&";
+
+        let response = send(
+            server(&chrome, &[]),
+            &format!(
+                "GET {} HTTP/1.1\r\nHost: localhost\r\nAuthorization: Basic abc\r\nConnection: close\r\n\r\n",
+                path
+            ),
+        )
+        .await;
+
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert_eq!(chrome.requests(), [format!("GET {}", path)]);
+    }
+
+    #[tokio::test]
+    async fn signed_uri_is_forwarded_without_the_signature() {
+        let chrome = FakeChrome::start("a").await;
+        let expires = u64::MAX / 2;
+        let path = auth::sign_url("proxy", "GET", "/json/version?a=1", expires);
+
+        let response = send(
+            server(&chrome, &["proxy"]),
+            &format!(
+                "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                path
+            ),
+        )
+        .await;
+
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert_eq!(chrome.requests(), ["GET /json/version?a=1"]);
+    }
+
+    #[tokio::test]
+    async fn unauthorized_requests_are_not_forwarded() {
+        let chrome = FakeChrome::start("a").await;
+
+        let response = send(
+            server(&chrome, &["proxy"]),
+            "GET /json/version HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+
+        assert!(response.starts_with("HTTP/1.1 401"), "{}", response);
+        assert!(chrome.requests().is_empty());
+    }
+
+    /// The cdp proxy server with the fake chrome in the pool.
+    fn pooled(chrome: &FakeChrome, config: Config) -> Server {
+        let server = Server::new(Config {
+            chrome_port: chrome.port,
+            proxy_mode: ProxyMode::Cdp,
+            ..config
+        });
+
+        server
+            .instances
+            .insert(1, chrome.port, Default::default(), Default::default(), None);
+        server.instances.mark_running(chrome.port);
+        server
+    }
+
+    /// Open a CDP session through the proxy.
+    async fn connect(
+        server: &Server,
+    ) -> Result<WebSocketStream<tokio::io::DuplexStream>, tokio_tungstenite::tungstenite::Error>
+    {
+        let (client, stream) = tokio::io::duplex(64 * 1024);
+        let config = WebSocketConfig::default()
+            .max_message_size(None)
+            .max_frame_size(None);
+
+        tokio::spawn(serve_connection(server.clone(), stream));
+
+        tokio_tungstenite::client_async_with_config(
+            "ws://localhost/devtools/browser/session",
+            client,
+            Some(config),
+        )
+        .await
+        .map(|(socket, _)| socket)
+    }
+
+    #[tokio::test]
+    async fn messages_past_the_websocket_defaults_are_forwarded() {
+        let chrome = FakeChrome::start("a").await;
+        let server = pooled(&chrome, Config::default());
+        let mut session = connect(&server).await.unwrap();
+
+        session
+            .send(Message::text(r#"{"id":1,"method":"Browser.getVersion"}"#))
+            .await
+            .unwrap();
+
+        let reply = session.next().await.unwrap().unwrap();
+        assert_eq!(
+            reply.into_text().unwrap().as_str(),
+            r#"{"id":1,"result":{"product":"a"}}"#
+        );
+
+        let screenshot = vec![7u8; 17 * 1024 * 1024];
+        session
+            .send(Message::binary(screenshot.clone()))
+            .await
+            .unwrap();
+
+        let reply = session.next().await.unwrap().unwrap();
+        assert_eq!(reply.into_data().len(), screenshot.len());
+        assert_eq!(server.instances.get(1).unwrap().sessions(), 1);
+    }
+
+    #[tokio::test]
+    async fn messages_past_the_max_end_the_session() {
+        let chrome = FakeChrome::start("a").await;
+        let server = pooled(
+            &chrome,
+            Config {
+                cdp_max_message_mb: 1,
+                ..Default::default()
+            },
+        );
+        let mut session = connect(&server).await.unwrap();
+
+        // the proxy can close the session before the whole message is sent.
+        let _ = session
+            .send(Message::binary(vec![7u8; 2 * 1024 * 1024]))
+            .await;
+
+        assert!(!matches!(
+            session.next().await,
+            Some(Ok(Message::Binary(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn sessions_past_the_cap_are_rejected() {
+        let chrome = FakeChrome::start("a").await;
+        let server = pooled(
+            &chrome,
+            Config {
+                cdp_max_sessions: 1,
+                ..Default::default()
+            },
+        );
+
+        let _session = connect(&server).await.unwrap();
+
+        match connect(&server).await {
+            Err(tokio_tungstenite::tungstenite::Error::Http(response)) => {
+                assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE)
+            }
+            other => panic!(
+                "expected a rejected session but got {:?}",
+                other.map(|_| ())
+            ),
+        }
+
+        assert_eq!(server.instances.get(1).unwrap().sessions(), 1);
+    }
+}
@@ -1,403 +1,293 @@
-use std::sync::atomic::{AtomicBool, AtomicU64};
+use crate::config::Config;
+use std::sync::OnceLock;
 
-/// The performance arg count.
-pub(crate) const PERF_ARGS: usize = 97;
+/// The process config.
+static CONFIG: OnceLock<Config> = OnceLock::new();
 
-#[cfg(any(test, feature = "testing"))]
-lazy_static::lazy_static! {
-    /// The chrome args to use test ( basic without anything used for testing ).
-    pub static ref CHROME_ARGS_TEST: [&'static str; 6] = {
-        let headless = std::env::args()
-        .nth(6)
-        .unwrap_or("true".into());
-
-        let headless = if headless != "false" {
-            match std::env::var("HEADLESS") {
-                Ok(h) => {
-                    if h == "false" {
-                        ""
-                    } else if h == "new" {
-                        "--headless=new"
-                    }else {
-                        "--headless"
-                    }
-                }
-                _ => "--headless"
-            }
-        } else {
-            ""
-        };
+/// Set the process config. Returns false if the config is already set or used.
+pub fn set_config(config: Config) -> bool {
+    CONFIG.set(config).is_ok()
+}
 
-        let port = if DEFAULT_PORT.eq(&9223) {
-            "--remote-debugging-port=9223"
-        } else if DEFAULT_PORT.eq(&9224) {
-            "--remote-debugging-port=9224"
-        } else {
-            "--remote-debugging-port=9222"
-        };
-
-        let use_gl = match std::env::var("CHROME_GL") {
-            Ok(h) => {
-                if h == "angle" {
-                    "--use-gl=angle"
-                } else {
-                    "--use-gl=swiftshader"
-                }
-            }
-            _ => "--use-gl=angle"
-        };
-
-        let gpu = std::env::var("ENABLE_GPU").unwrap_or_default() == "true";
-
-        let gpu_enabled = if gpu { "--enable-gpu" } else { "--disable-gpu" };
-        let gpu_enabled_sandboxed = if gpu { "--enable-gpu-sandbox" } else { "--disable-gpu-sandbox" };
-
-        [
-            // *SPECIAL*
-            "--remote-debugging-address=0.0.0.0",
-            port,
-            // *SPECIAL*
-            headless,
-            gpu_enabled,
-            gpu_enabled_sandboxed,
-            use_gl,
-        ]
+/// The process config. Loads from the config file and env variables if not set.
+pub fn try_config() -> Result<&'static Config, crate::config::ConfigError> {
+    match CONFIG.get() {
+        Some(config) => Ok(config),
+        _ => {
+            let config = Config::load()?;
+            Ok(CONFIG.get_or_init(|| config))
+        }
+    }
+}
+
+/// The process config. Loads from the config file and env variables if not set and panics if the config is invalid.
+pub fn config() -> &'static Config {
+    try_config().unwrap_or_else(|e| panic!("{}", e))
+}
+
+lazy_static::lazy_static! {
+    /// The chrome performance args used after the launch args.
+    pub static ref PERF_ARGS: Vec<&'static str> = vec![
+        "--no-first-run",
+        "--no-sandbox",
+        "--disable-setuid-sandbox",
+        "--no-zygote",
+        "--hide-scrollbars",
+        "--allow-running-insecure-content",
+        "--autoplay-policy=user-gesture-required",
+        "--ignore-certificate-errors",
+        "--no-default-browser-check",
+        "--disable-dev-shm-usage", // required or else container will crash not enough memory
+        "--disable-threaded-scrolling",
+        "--disable-cookie-encryption",
+        "--disable-demo-mode",
+        "--disable-dinosaur-easter-egg",
+        "--disable-fetching-hints-at-navigation-start",
+        "--disable-site-isolation-trials",
+        "--disable-web-security",
+        "--disable-threaded-animation",
+        "--disable-sync",
+        "--disable-print-preview",
+        "--disable-search-engine-choice-screen",
+        "--disable-partial-raster",
+        "--disable-in-process-stack-traces",
+        "--use-angle=swiftshader",
+        "--disable-low-res-tiling",
+        "--disable-speech-api",
+        "--disable-oobe-chromevox-hint-timer-for-testing",
+        "--disable-smooth-scrolling",
+        "--disable-default-apps",
+        "--disable-prompt-on-repost",
+        "--disable-domain-reliability",
+        "--enable-dom-distiller",
+        "--enable-distillability-service",
+        "--disable-component-update",
+        "--disable-background-timer-throttling",
+        "--disable-breakpad",
+        "--disable-crash-reporter",
+        "--disable-software-rasterizer",
+        "--disable-asynchronous-spellchecking",
+        "--disable-extensions",
+        "--disable-html5-camera",
+        "--noerrdialogs",
+        "--disable-popup-blocking",
+        "--disable-hang-monitor",
+        "--disable-checker-imaging",
+        "--enable-surface-synchronization",
+        "--disable-image-animation-resync",
+        "--disable-client-side-phishing-detection",
+        "--disable-component-extensions-with-background-pages",
+        "--run-all-compositor-stages-before-draw",
+        "--disable-background-networking",
+        "--disable-renderer-backgrounding",
+        "--disable-field-trial-config",
+        "--disable-back-forward-cache",
+        "--disable-backgrounding-occluded-windows",
+        "--log-level=3",
+        "--enable-logging=stderr",
+        "--font-render-hinting=none",
+        "--block-new-web-contents",
+        "--no-subproc-heap-profiling",
+        "--no-pre-read-main-dll",
+        "--disable-stack-profiler",
+        "--disable-libassistant-logfile",
+        "--ip-protection-proxy-opt-out",
+        "--unsafely-disable-devtools-self-xss-warning",
+        "--enable-features=PdfOopif,SharedArrayBuffer,NetworkService,NetworkServiceInProcess",
+        "--metrics-recording-only",
+        "--use-mock-keychain",
+        "--force-color-profile=srgb",
+        "--disable-infobars",
+        "--mute-audio",
+        "--disable-datasaver-prompt",
+        "--no-service-autorun",
+        "--password-store=basic",
+        "--export-tagged-pdf",
+        "--no-pings",
+        "--rusty-png",
+        "--disable-histogram-customizer",
+        "--window-size=800,600",
+        "--disable-vulkan-fallback-to-gl-for-testing",
+        "--disable-vulkan-surface",
+        "--disable-webrtc",
+        "--disable-oopr-debug-crash-dump",
+        "--disable-pnacl-crash-throttling",
+        "--disable-renderer-accessibility",
+        &crate::render_conf::RENDER_PROCESS_LIMIT,
+        "--disable-pushstate-throttle",
+        "--disable-blink-features=AutomationControlled",
+        "--disable-ipc-flooding-protection", // we do not need to throttle navigation for https://github.com/spider-rs/spider/commit/9ff5bbd7a2656b8edb84b62843b72ae9d09af079#diff-75ce697faf0d37c3dff4a3a19e7524798b3cb5487f8f54beb5d04c4d48e34234R446.
+        // --deterministic-mode 10-20% drop in perf
+        // "--blink-settings=primaryHoverType=2,availableHoverTypes=2,primaryPointerType=4,availablePointerTypes=4",
+        "--disable-features=PaintHolding,HttpsUpgrades,DeferRendererTasksAfterInput,LensOverlay,ThirdPartyStoragePartitioning,IsolateSandboxedIframes,ProcessPerSiteUpToMainFrameThreshold,site-per-process,WebUIJSErrorReportingExtended,DIPS,InterestFeedContentSuggestions,PrivacySandboxSettings4,AutofillServerCommunication,CalculateNativeWinOcclusion,OptimizationHints,AudioServiceOutOfProcess,IsolateOrigins,ImprovedCookieControls,LazyFrameLoading,GlobalMediaControls,DestroyProfileOnBrowserClose,MediaRouter,DialMediaRouteProvider,AcceptCHFrame,AutoExpandDetailsElement,CertificateTransparencyComponentUpdater,AvoidUnnecessaryBeforeUnloadCheckSync,Translate",
+    ];
+    /// The chrome remote debugging port of the process config.
+    pub static ref DEFAULT_PORT: u32 = config().chrome_port;
+    /// The management server port of the process config.
+    pub static ref DEFAULT_PORT_SERVER: u16 = config().server_port;
+    /// The chrome args of the process config.
+    pub static ref CHROME_ARGS: Vec<String> = {
+        let mut args = launch_args(config(), *DEFAULT_PORT);
+        args.extend(PERF_ARGS.iter().map(|arg| arg.to_string()));
+        merge_flags(args, &config().chrome_flags, &config().remove_chrome_flags)
     };
+    /// The chrome launch path of the process config.
+    pub static ref CHROME_PATH: String = chrome_path(config());
 }
 
+#[cfg(any(test, feature = "testing"))]
 lazy_static::lazy_static! {
-    /// Is the instance healthy?
-    pub static ref IS_HEALTHY: AtomicBool = AtomicBool::new(true);
-    pub static ref CHROME_INSTANCES: dashmap::DashSet<u32> = dashmap::DashSet::new();
-    pub static ref DEFAULT_PORT: u32 = {
-        let default_port = std::env::args()
-            .nth(4)
-            .unwrap_or("9223".into())
-            .parse::<u32>()
-            .unwrap_or_default();
-
-        let default_port = if default_port == 0 {
-            9223
-        } else {
-            default_port
-        };
+    /// The chrome args to use test ( basic without anything used for testing ).
+    pub static ref CHROME_ARGS_TEST: Vec<String> = merge_flags(
+        launch_args(config(), *DEFAULT_PORT),
+        &config().chrome_flags,
+        &config().remove_chrome_flags,
+    );
+}
 
-        default_port
+/// The chrome launch args for the remote debugging port.
+pub fn launch_args(config: &Config, port: u32) -> Vec<String> {
+    let address = if config.chrome_address.is_empty() {
+        "0.0.0.0"
+    } else {
+        &config.chrome_address
     };
-    pub static ref DEFAULT_PORT_SERVER: u16 = {
-        let default_port = std::env::args()
-            .nth(5)
-            .unwrap_or("6000".into())
-            .parse::<u16>()
-            .unwrap_or_default();
-        let default_port = if default_port == 0 {
-            6000
-        } else {
-            default_port
-        };
 
-        default_port
-    };
-    /// Is a brave instance?
-    pub(crate) static ref BRAVE_INSTANCE: bool = {
-        CHROME_PATH.ends_with("Brave Browser")
-        || CHROME_PATH.ends_with("brave-browser")
-    };
-    /// Is a lightpanda instance?
-    pub(crate) static ref LIGHT_PANDA: bool = {
-        CHROME_PATH.ends_with("lightpanda-aarch64-macos")
-        || CHROME_PATH.ends_with("lightpanda-x86_64-linux")
+    let use_gl = if config.use_gl == "angle" {
+        "--use-gl=angle"
+    } else {
+        "--use-gl=swiftshader"
     };
-    /// The chrome args to use.
-    pub static ref CHROME_ARGS: [&'static str; PERF_ARGS] = {
-        let headless = std::env::args()
-        .nth(6)
-        .unwrap_or("true".into());
-
-        let headless = if headless != "false" {
-            match std::env::var("HEADLESS") {
-                Ok(h) => {
-                    if h == "false" {
-                        ""
-                    } else if h == "new" {
-                        "--headless=new"
-                    }else {
-                        "--headless"
-                    }
-                }
-                _ => "--headless"
-            }
-        } else {
-            ""
-        };
 
-        let port = if DEFAULT_PORT.eq(&9223) {
-            "--remote-debugging-port=9223"
-        } else if DEFAULT_PORT.eq(&9224) {
-            "--remote-debugging-port=9224"
-        } else {
-            "--remote-debugging-port=9222"
-        };
-
-        let use_gl = match std::env::var("CHROME_GL") {
-            Ok(h) => {
-                if h == "angle" {
-                    "--use-gl=angle"
-                } else {
-                    "--use-gl=swiftshader"
-                }
-            }
-            _ => "--use-gl=angle"
-        };
-
-        let gpu = std::env::var("ENABLE_GPU").unwrap_or_default() == "true";
-
-        let gpu_enabled = if gpu { "--enable-gpu" } else { "--disable-gpu" };
-        let gpu_enabled_sandboxed = if gpu { "--enable-gpu-sandbox" } else { "--disable-gpu-sandbox" };
-
-        [
-            // *SPECIAL*
-            "--remote-debugging-address=0.0.0.0",
-            port,
-            // *SPECIAL*
-            headless,
-            gpu_enabled,
-            gpu_enabled_sandboxed,
-            use_gl,
-            "--no-first-run",
-            "--no-sandbox",
-            "--disable-setuid-sandbox",
-            "--no-zygote",
-            "--hide-scrollbars",
-            "--user-data-dir=~/.config/google-chrome",
-            "--allow-running-insecure-content",
-            "--autoplay-policy=user-gesture-required",
-            "--ignore-certificate-errors",
-            "--no-default-browser-check",
-            "--disable-dev-shm-usage", // required or else container will crash not enough memory
-            "--disable-threaded-scrolling",
-            "--disable-cookie-encryption",
-            "--disable-demo-mode",
-            "--disable-dinosaur-easter-egg",
-            "--disable-fetching-hints-at-navigation-start",
-            "--disable-site-isolation-trials",
-            "--disable-web-security",
-            "--disable-threaded-animation",
-            "--disable-sync",
-            "--disable-print-preview",
-            "--disable-search-engine-choice-screen",
-            "--disable-partial-raster",
-            "--disable-in-process-stack-traces",
-            "--use-angle=swiftshader",
-            "--disable-low-res-tiling",
-            "--disable-speech-api",
-            "--disable-oobe-chromevox-hint-timer-for-testing",
-            "--disable-smooth-scrolling",
-            "--disable-default-apps",
-            "--disable-prompt-on-repost",
-            "--disable-domain-reliability",
-            "--enable-dom-distiller",
-            "--enable-distillability-service",
-            "--disable-component-update",
-            "--disable-background-timer-throttling",
-            "--disable-breakpad",
-            "--disable-crash-reporter",
-            "--disable-software-rasterizer",
-            "--disable-asynchronous-spellchecking",
-            "--disable-extensions",
-            "--disable-html5-camera",
-            "--noerrdialogs",
-            "--disable-popup-blocking",
-            "--disable-hang-monitor",
-            "--disable-checker-imaging",
-            "--enable-surface-synchronization",
-            "--disable-image-animation-resync",
-            "--disable-client-side-phishing-detection",
-            "--disable-component-extensions-with-background-pages",
-            "--run-all-compositor-stages-before-draw",
-            "--disable-background-networking",
-            "--disable-renderer-backgrounding",
-            "--disable-field-trial-config",
-            "--disable-back-forward-cache",
-            "--disable-backgrounding-occluded-windows",
-            "--log-level=3",
-            "--enable-logging=stderr",
-            "--font-render-hinting=none",
-            "--block-new-web-contents",
-            "--no-subproc-heap-profiling",
-            "--no-pre-read-main-dll",
-            "--disable-stack-profiler",
-            "--disable-libassistant-logfile",
-            "--ip-protection-proxy-opt-out",
-            "--unsafely-disable-devtools-self-xss-warning",
-            "--enable-features=PdfOopif,SharedArrayBuffer,NetworkService,NetworkServiceInProcess",
-            "--metrics-recording-only",
-            "--use-mock-keychain",
-            "--force-color-profile=srgb",
-            "--disable-infobars",
-            "--mute-audio",
-            "--disable-datasaver-prompt",
-            "--no-service-autorun",
-            "--password-store=basic",
-            "--export-tagged-pdf",
-            "--no-pings",
-            "--rusty-png",
-            "--disable-histogram-customizer",
-            "--window-size=800,600",
-            "--disable-vulkan-fallback-to-gl-for-testing",
-            "--disable-vulkan-surface",
-            "--disable-webrtc",
-            "--disable-oopr-debug-crash-dump",
-            "--disable-pnacl-crash-throttling",
-            "--disable-renderer-accessibility",
-            &crate::render_conf::RENDER_PROCESS_LIMIT,
-            "--disable-pushstate-throttle",
-            "--disable-blink-features=AutomationControlled",
-            "--disable-ipc-flooding-protection", // we do not need to throttle navigation for https://github.com/spider-rs/spider/commit/9ff5bbd7a2656b8edb84b62843b72ae9d09af079#diff-75ce697faf0d37c3dff4a3a19e7524798b3cb5487f8f54beb5d04c4d48e34234R446.
-            // --deterministic-mode 10-20% drop in perf
-            // "--blink-settings=primaryHoverType=2,availableHoverTypes=2,primaryPointerType=4,availablePointerTypes=4",
-            "--disable-features=PaintHolding,HttpsUpgrades,DeferRendererTasksAfterInput,LensOverlay,ThirdPartyStoragePartitioning,IsolateSandboxedIframes,ProcessPerSiteUpToMainFrameThreshold,site-per-process,WebUIJSErrorReportingExtended,DIPS,InterestFeedContentSuggestions,PrivacySandboxSettings4,AutofillServerCommunication,CalculateNativeWinOcclusion,OptimizationHints,AudioServiceOutOfProcess,IsolateOrigins,ImprovedCookieControls,LazyFrameLoading,GlobalMediaControls,DestroyProfileOnBrowserClose,MediaRouter,DialMediaRouteProvider,AcceptCHFrame,AutoExpandDetailsElement,CertificateTransparencyComponentUpdater,AvoidUnnecessaryBeforeUnloadCheckSync,Translate",
-        ]
+    let gpu = config.enable_gpu;
+
+    let gpu_enabled = if gpu { "--enable-gpu" } else { "--disable-gpu" };
+    let gpu_enabled_sandboxed = if gpu {
+        "--enable-gpu-sandbox"
+    } else {
+        "--disable-gpu-sandbox"
     };
 
-    /// The light panda args to use.
-    pub static ref LIGHTPANDA_ARGS: [&'static str; 2] = {
-        let port = if DEFAULT_PORT.eq(&9223) {
-            "--port=9223"
-        } else if DEFAULT_PORT.eq(&9224) {
-            "--port=9224"
-        } else {
-            "--port=9222"
-        };
+    vec![
+        // *SPECIAL*
+        format!("--remote-debugging-address={}", address),
+        format!("--remote-debugging-port={}", port),
+        // *SPECIAL*
+        config.headless.flag().into(),
+        gpu_enabled.into(),
+        gpu_enabled_sandboxed.into(),
+        use_gl.into(),
+    ]
+}
 
-        [
-            "--host=0.0.0.0",
-            port,
-        ]
-    };
-    /// Return base target and replacement. Target port is the port for chrome.
-    pub(crate) static ref TARGET_REPLACEMENT: (&'static [u8; 5], &'static[u8; 5]) = {
-        if *DEFAULT_PORT == 9223 {
-            let target_port = b":9223";
-            let proxy_port = b":9222";
+/// The chrome args to launch with merged with the flags of the config. The performance args are left out with `test_no_args`.
+pub fn chrome_args(config: &Config, port: u32) -> Vec<String> {
+    let mut args = launch_args(config, port);
 
-            (target_port, proxy_port)
-        } else {
-            // we need to allow dynamic ports instead of defaulting to standard and xfvb offport.
-            let target_port = b":9224";
-            let proxy_port = b":9223";
+    if !config.test_no_args {
+        args.extend(PERF_ARGS.iter().map(|arg| arg.to_string()));
+    }
 
-            (target_port, proxy_port)
-        }
-    };
-    /// The hostname of the machine to replace 127.0.0.1 when making request to /json/version on port 6000.
-    pub(crate) static ref HOST_NAME: String = {
-        let mut hostname = String::new();
+    merge_flags(args, &config.chrome_flags, &config.remove_chrome_flags)
+}
 
-        if let Ok(name) = std::env::var("HOSTNAME_OVERRIDE") {
-            if !name.is_empty() {
-                hostname = name;
-            }
-        }
+/// The flags set from the server config that cannot be added or removed.
+pub(crate) const MANAGED_FLAGS: [&str; 2] =
+    ["--remote-debugging-address", "--remote-debugging-port"];
 
-        if hostname.is_empty() {
-            if let Ok(name) = std::env::var("HOSTNAME") {
-                if !name.is_empty() {
-                    hostname = name;
-                }
-            }
-        }
+/// The flags holding comma separated feature lists with the opposite flag.
+const FEATURE_FLAGS: [(&str, &str); 4] = [
+    ("--enable-features", "--disable-features"),
+    ("--disable-features", "--enable-features"),
+    ("--enable-blink-features", "--disable-blink-features"),
+    ("--disable-blink-features", "--enable-blink-features"),
+];
 
-        hostname
-    };
-    /// The main endpoint for entry.
-    pub(crate) static ref ENDPOINT_BASE: String = {
-        format!("http://127.0.0.1:{}", *DEFAULT_PORT)
-    };
-    /// The main endpoint json/version.
-    pub(crate) static ref ENDPOINT: String = {
-        format!("http://127.0.0.1:{}/json/version", *DEFAULT_PORT)
-    };
-    /// The chrome launch path.
-    pub static ref CHROME_PATH: String = {
-        // cargo bench will always pass in the first arg
-        let default_path = std::env::args().nth(1).unwrap_or_default();
-        let trimmed_path = default_path.trim();
-
-        // handle testing and default to OS
-        if default_path.is_empty() || trimmed_path == "--nocapture" || trimmed_path == "--bench" {
-            let chrome_path = match std::env::var("CHROME_PATH") {
-                Ok(p) => p,
-                _ => Default::default()
-            };
-
-            if chrome_path.is_empty() {
-                get_default_chrome_bin().to_string()
-            } else {
-                chrome_path
+/// The name of the flag without the value.
+pub(crate) fn flag_name(flag: &str) -> &str {
+    flag.split_once('=').map_or(flag, |(name, _)| name)
+}
+
+/// Merge the flags with the args. The removed flags are dropped by name, an added flag replaces the arg with the same name,
+/// and the features of a feature flag are appended to the list and dropped from the opposite list.
+pub fn merge_flags(mut args: Vec<String>, add: &[String], remove: &[String]) -> Vec<String> {
+    args.retain(|arg| !remove.iter().any(|flag| flag_name(flag) == flag_name(arg)));
+
+    for flag in add {
+        let name = flag_name(flag);
+
+        match FEATURE_FLAGS.iter().find(|(feature, _)| *feature == name) {
+            Some((feature, opposite)) => {
+                let features: Vec<&str> = flag
+                    .split_once('=')
+                    .map(|(_, v)| v.split(',').filter(|f| !f.is_empty()).collect())
+                    .unwrap_or_default();
+
+                edit_list(&mut args, opposite, |list| {
+                    list.retain(|f| !features.contains(&f.as_str()))
+                });
+                edit_list(&mut args, feature, |list| {
+                    for f in &features {
+                        if !list.iter().any(|l| l == f) {
+                            list.push(f.to_string());
+                        }
+                    }
+                });
             }
-        } else {
-            default_path
+            _ => match args.iter().position(|arg| flag_name(arg) == name) {
+                Some(pos) => args[pos] = flag.clone(),
+                _ => args.push(flag.clone()),
+            },
         }
-    };
-    /// The chrome address.
-    pub(crate) static ref CHROME_ADDRESS: String = {
-        let mut host_address = std::env::args().nth(2).unwrap_or("127.0.0.1".to_string()).to_string();
+    }
 
-        if host_address.is_empty() {
-            host_address = String::from("127.0.0.1").into()
-        }
+    args
+}
 
-        host_address
-    };
-    pub(crate) static ref CACHEABLE: AtomicBool = {
-        AtomicBool::new(true)
-    };
-    /// The last cache date period.
-    pub(crate) static ref LAST_CACHE: AtomicU64 = {
-        AtomicU64::new(0)
-    };
-    /// Debug the json version endpoint.
-    pub(crate) static ref DEBUG_JSON: bool = std::env::var("DEBUG_JSON").unwrap_or_default() == "true";
-    /// Test headless without args.
-    pub(crate) static ref TEST_NO_ARGS: bool = std::env::var("TEST_NO_ARGS").unwrap_or_default() == "true";
-    /// Entry port to the proxy.
-    pub(crate) static ref ENTRY: &'static str = {
-        if crate::TARGET_REPLACEMENT.0 == b":9223" {
-            "0.0.0.0:9222"
-        } else {
-            "0.0.0.0:9223"
-        }
-    };
-    /// Target chrome server.
-    pub(crate) static ref TARGET: &'static str = {
-        if crate::TARGET_REPLACEMENT.1 == b":9222" {
-            "0.0.0.0:9223"
-        } else {
-            "0.0.0.0:9224"
+/// Edit the comma separated values of the flag. The flag is added when missing and removed when left empty.
+fn edit_list(args: &mut Vec<String>, name: &str, edit: impl FnOnce(&mut Vec<String>)) {
+    let pos = args.iter().position(|arg| flag_name(arg) == name);
+
+    let mut list: Vec<String> = pos
+        .and_then(|pos| args[pos].split_once('='))
+        .map(|(_, v)| {
+            v.split(',')
+                .filter(|f| !f.is_empty())
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    edit(&mut list);
+
+    let flag = format!("{}={}", name, list.join(","));
+
+    match pos {
+        Some(pos) if list.is_empty() => {
+            args.remove(pos);
         }
-    };
-    /// The buffer size.
-    pub(crate) static ref BUFFER_SIZE: usize = {
-        let buffer_size = std::env::var("BUFFER_SIZE")
-            .ok()
-            .and_then(|s| s.parse().ok())
-            .unwrap_or(131072); // Default to 128kb
-        buffer_size
-    };
-    /// 10 sec cache
-    pub(crate) static ref TEN_SECONDS: std::time::Duration = {
-        std::time::Duration::from_secs(10)
-    };
+        Some(pos) => args[pos] = flag,
+        _ if !list.is_empty() => args.push(flag),
+        _ => (),
+    }
 }
 
-/// Get the default chrome bin location per OS.
-fn get_default_chrome_bin() -> &'static str {
-    let brave = match std::env::var("BRAVE_ENABLED") {
-        Ok(v) => v == "true",
-        _ => false,
-    };
+/// The chrome launch path. Defaults to the OS chrome location.
+pub fn chrome_path(config: &Config) -> String {
+    match &config.chrome_path {
+        Some(chrome_path) => chrome_path.clone(),
+        _ => get_default_chrome_bin(config.brave).to_string(),
+    }
+}
+
+/// Is the chrome path a lightpanda instance?
+pub(crate) fn is_light_panda(chrome_path: &str) -> bool {
+    chrome_path.ends_with("lightpanda-aarch64-macos")
+        || chrome_path.ends_with("lightpanda-x86_64-linux")
+}
 
+/// Get the default chrome bin location per OS.
+fn get_default_chrome_bin(brave: bool) -> &'static str {
     if cfg!(target_os = "windows") {
         if brave {
             "brave-browser.exe"
@@ -424,3 +314,106 @@ fn get_default_chrome_bin() -> &'static str {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The owned flags.
+    fn flags(flags: &[&str]) -> Vec<String> {
+        flags.iter().map(|flag| flag.to_string()).collect()
+    }
+
+    #[test]
+    fn add_appends_new_flags() {
+        let args = merge_flags(flags(&["--mute-audio"]), &flags(&["--no-pings"]), &[]);
+
+        assert_eq!(args, ["--mute-audio", "--no-pings"]);
+    }
+
+    #[test]
+    fn add_overrides_in_place() {
+        let args = merge_flags(
+            flags(&["--window-size=800,600", "--mute-audio"]),
+            &flags(&["--window-size=1024,768"]),
+            &[],
+        );
+
+        assert_eq!(args, ["--window-size=1024,768", "--mute-audio"]);
+    }
+
+    #[test]
+    fn flag_with_and_without_value_dedup() {
+        let args = merge_flags(flags(&["--headless"]), &flags(&["--headless=new"]), &[]);
+        assert_eq!(args, ["--headless=new"]);
+
+        let args = merge_flags(flags(&["--headless=new"]), &flags(&["--headless"]), &[]);
+        assert_eq!(args, ["--headless"]);
+    }
+
+    #[test]
+    fn remove_by_name() {
+        let args = merge_flags(
+            flags(&[
+                "--window-size=800,600",
+                "--mute-audio",
+                "--disable-extensions",
+            ]),
+            &[],
+            &flags(&["--window-size", "--disable-extensions=true"]),
+        );
+
+        assert_eq!(args, ["--mute-audio"]);
+    }
+
+    #[test]
+    fn remove_then_add() {
+        let args = merge_flags(
+            flags(&["--window-size=800,600"]),
+            &flags(&["--window-size=1024,768"]),
+            &flags(&["--window-size"]),
+        );
+
+        assert_eq!(args, ["--window-size=1024,768"]);
+    }
+
+    #[test]
+    fn features_merge_without_duplicates() {
+        let args = merge_flags(
+            flags(&["--enable-features=A,B", "--disable-features=C,D"]),
+            &flags(&["--enable-features=B,C,E"]),
+            &[],
+        );
+
+        assert_eq!(args, ["--enable-features=A,B,C,E", "--disable-features=D"]);
+    }
+
+    #[test]
+    fn features_emptied_drop_the_flag() {
+        let args = merge_flags(
+            flags(&["--enable-features=A,B", "--mute-audio"]),
+            &flags(&["--disable-features=A,B"]),
+            &[],
+        );
+
+        assert_eq!(args, ["--mute-audio", "--disable-features=A,B"]);
+    }
+
+    #[test]
+    fn blink_features_merge() {
+        let args = merge_flags(
+            flags(&["--disable-blink-features=AutomationControlled"]),
+            &flags(&["--enable-blink-features=AutomationControlled,,Other"]),
+            &[],
+        );
+
+        assert_eq!(args, ["--enable-blink-features=AutomationControlled,Other"]);
+    }
+
+    #[test]
+    fn flag_names() {
+        assert_eq!(flag_name("--window-size=800,600"), "--window-size");
+        assert_eq!(flag_name("--headless"), "--headless");
+        assert_eq!(flag_name("--user-agent=a=b"), "--user-agent");
+    }
+}
@@ -0,0 +1,953 @@
+use crate::balancer::Strategy;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// The env variable with the path of the config file.
+pub const CONFIG_ENV: &str = "CHROME_SERVER_CONFIG";
+
+/// The command line usage.
+pub const USAGE: &str = "Usage: headless_browser [OPTIONS]
+
+Options:
+      --config <PATH>              TOML or JSON config file [env: CHROME_SERVER_CONFIG]
+      --chrome-path <PATH>         The chrome executable [env: CHROME_PATH]
+      --address <ADDRESS>          The chrome remote debugging address [env: REMOTE_ADDRESS]
+      --launch <init|ignore>       Launch chrome on startup [env: CHROME_INIT]
+      --port <PORT>                The chrome remote debugging port [env: DEFAULT_PORT]
+      --server-port <PORT>         The management server port [env: DEFAULT_PORT_SERVER]
+      --proxy-port <PORT>          The proxy entry port [env: PROXY_PORT]
+      --port-range <START-END>     The ports handed out to forked instances [env: CHROME_PORT_RANGE]
+      --headless <true|false|new>  The headless mode [env: HEADLESS]
+      --hostname <HOST>            The host advertised by /json/version [env: HOSTNAME_OVERRIDE]
+      --load-balancer <STRATEGY>   round-robin, least-connections, or random-two-choices [env: LOAD_BALANCER]
+      --proxy-mode <raw|cdp>       Forward raw bytes or CDP messages [env: PROXY_MODE]
+      --profiles-dir <PATH>        The directory of the named profiles [env: PROFILES_DIR]
+      --tls-cert <PATH>            The PEM certificate chain enabling TLS [env: TLS_CERT]
+      --tls-key <PATH>             The PEM private key of the certificate [env: TLS_KEY]
+      --chrome-flag <FLAG>         Add or override a chrome flag, repeatable [env: CHROME_FLAGS]
+      --remove-chrome-flag <FLAG>  Remove a built-in chrome flag, repeatable [env: CHROME_FLAGS_REMOVE]
+  -h, --help                       Print help";
+
+/// The chrome headless mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HeadlessMode {
+    /// Use `--headless`.
+    #[default]
+    #[serde(alias = "true")]
+    Shell,
+    /// Use `--headless=new`.
+    New,
+    /// Run with a display.
+    #[serde(alias = "false")]
+    Off,
+}
+
+impl FromStr for HeadlessMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "true" | "shell" => Ok(HeadlessMode::Shell),
+            "new" => Ok(HeadlessMode::New),
+            "false" | "off" => Ok(HeadlessMode::Off),
+            s => Err(format!("expected true, false, or new but got {}", s)),
+        }
+    }
+}
+
+impl HeadlessMode {
+    /// The chrome flag for the mode.
+    pub fn flag(&self) -> &'static str {
+        match self {
+            HeadlessMode::Shell => "--headless",
+            HeadlessMode::New => "--headless=new",
+            HeadlessMode::Off => "",
+        }
+    }
+}
+
+/// The proxy forwarding mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyMode {
+    /// Forward the raw bytes.
+    #[default]
+    Raw,
+    /// Terminate the websocket upgrade and forward the CDP messages.
+    Cdp,
+}
+
+impl FromStr for ProxyMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "raw" => Ok(ProxyMode::Raw),
+            "cdp" => Ok(ProxyMode::Cdp),
+            s => Err(format!("expected raw or cdp but got {}", s)),
+        }
+    }
+}
+
+/// The server configuration. Values load from the defaults, then the config file, then the env variables, then the command line flags.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The chrome executable. Defaults to the OS chrome location.
+    pub chrome_path: Option<String>,
+    /// Use brave as the default chrome executable.
+    pub brave: bool,
+    /// The chrome remote debugging address.
+    pub chrome_address: String,
+    /// Launch chrome on startup.
+    pub init: bool,
+    /// The chrome remote debugging port.
+    pub chrome_port: u32,
+    /// The management server port.
+    pub server_port: u16,
+    /// The proxy entry port. Defaults to 9222 or 9223 when chrome uses 9222 or any port other than 9223.
+    pub proxy_port: Option<u16>,
+    /// The inclusive port range handed out to forked instances. Defaults to 100 ports starting at the chrome port.
+    pub port_range: Option<[u32; 2]>,
+    /// The chrome headless mode.
+    pub headless: HeadlessMode,
+    /// The chrome gl implementation, angle or swiftshader.
+    pub use_gl: String,
+    /// Enable the gpu.
+    pub enable_gpu: bool,
+    /// The host advertised by /json/version instead of 127.0.0.1.
+    pub hostname: Option<String>,
+    /// The port advertised by /json/version. Defaults to the proxy port.
+    pub advertised_port: Option<u16>,
+    /// The websocket scheme advertised by /json/version, ws or wss. Defaults to wss with TLS.
+    pub advertised_scheme: Option<String>,
+    /// Advertise the host of the request `Host` header in /json/version.
+    pub advertise_request_host: bool,
+    /// The proxies trusted to set the `X-Forwarded-Host`, `X-Forwarded-Proto`, and `X-Forwarded-Port` headers as ip addresses or CIDR ranges.
+    pub trusted_proxies: Vec<String>,
+    /// The proxy load balancing strategy.
+    pub load_balancer: Strategy,
+    /// The proxy forwarding mode.
+    pub proxy_mode: ProxyMode,
+    /// The concurrent CDP sessions per instance in the cdp proxy mode. 0 is unlimited.
+    pub cdp_max_sessions: usize,
+    /// The largest CDP message in megabytes forwarded in the cdp proxy mode. Chrome sends screenshots and snapshots well past the websocket defaults.
+    pub cdp_max_message_mb: usize,
+    /// The CDP methods allowed in the cdp proxy mode ex: `Page.*`. Empty allows every method.
+    pub cdp_allow: Vec<String>,
+    /// The CDP methods rejected in the cdp proxy mode ex: `Browser.close`. The deny list wins over the allow list.
+    pub cdp_deny: Vec<String>,
+    /// The directory the downloads set with `setDownloadBehavior` have to stay in.
+    pub cdp_download_dir: Option<String>,
+    /// The keys of the management routes sent as a bearer token or used to sign urls. Empty disables the authentication.
+    pub api_keys: Vec<String>,
+    /// The keys of the mutating management routes. Defaults to the `api_keys`.
+    pub api_write_keys: Vec<String>,
+    /// The keys of the proxy. Empty disables the authentication.
+    pub proxy_keys: Vec<String>,
+    /// The PEM certificate chain enabling TLS on the management server and proxy. The certificate reloads when the file changes.
+    pub tls_cert: Option<String>,
+    /// The PEM private key of the certificate.
+    pub tls_key: Option<String>,
+    /// The proxy buffer size in bytes.
+    pub buffer_size: usize,
+    /// The seconds a replaced instance keeps serving its active connections before shutdown.
+    pub drain_timeout: u64,
+    /// The base milliseconds before respawning a crashed instance.
+    pub restart_backoff: u64,
+    /// The consecutive crashes before an instance is no longer respawned.
+    pub crash_loop_limit: u32,
+    /// The seconds an instance has to stay up to reset the consecutive crashes.
+    pub crash_loop_window: u64,
+    /// The seconds an instance has to exit after SIGTERM before it is killed.
+    pub shutdown_grace: u64,
+    /// The seconds between resource usage samples of the instances. 0 samples on request only.
+    pub usage_interval: u64,
+    /// Recycle an instance when the process tree memory exceeds the megabytes. 0 disables.
+    pub recycle_memory_mb: u64,
+    /// Recycle an instance after the proxied sessions. 0 disables.
+    pub recycle_sessions: u64,
+    /// Recycle an instance after the seconds up. 0 disables.
+    pub recycle_uptime: u64,
+    /// The seconds between the health probes of the instances. 0 disables the probes.
+    pub health_interval: u64,
+    /// The milliseconds a health probe has to complete.
+    pub health_timeout: u64,
+    /// The consecutive probe failures before an instance is not ready.
+    pub ready_failure_threshold: u32,
+    /// The consecutive probe failures before an instance is not live.
+    pub live_failure_threshold: u32,
+    /// Log the /json/version responses.
+    pub debug_json: bool,
+    /// Launch chrome without the performance args.
+    pub test_no_args: bool,
+    /// The chrome flags added to the launch args. A flag replaces the built-in flag with the same name and the feature lists merge with the built-in lists.
+    pub chrome_flags: Vec<String>,
+    /// The built-in chrome flags removed by name ex: `--disable-extensions`.
+    pub remove_chrome_flags: Vec<String>,
+    /// The directory of the named profiles. Defaults to `$HOME/.config/headless_browser/profiles`.
+    pub profiles_dir: Option<String>,
+    /// The chrome flags the launch options of a fork can set by name ex: `--window-size`. Empty rejects the flags of the launch options.
+    pub fork_flags: Vec<String>,
+    /// The directory the `user_data_dir` of the launch options has to stay in. None rejects the `user_data_dir` of the launch options.
+    pub user_data_base_dir: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            chrome_path: None,
+            brave: false,
+            chrome_address: "127.0.0.1".into(),
+            init: true,
+            chrome_port: 9223,
+            server_port: 6000,
+            proxy_port: None,
+            port_range: None,
+            headless: HeadlessMode::default(),
+            use_gl: "angle".into(),
+            enable_gpu: false,
+            hostname: None,
+            advertised_port: None,
+            advertised_scheme: None,
+            advertise_request_host: false,
+            trusted_proxies: vec![],
+            load_balancer: Strategy::default(),
+            proxy_mode: ProxyMode::default(),
+            cdp_max_sessions: 0,
+            cdp_max_message_mb: 256,
+            cdp_allow: vec![],
+            cdp_deny: vec![],
+            cdp_download_dir: None,
+            api_keys: vec![],
+            api_write_keys: vec![],
+            proxy_keys: vec![],
+            tls_cert: None,
+            tls_key: None,
+            buffer_size: 131072,
+            drain_timeout: 60,
+            restart_backoff: 500,
+            crash_loop_limit: 5,
+            crash_loop_window: 60,
+            shutdown_grace: 5,
+            usage_interval: 0,
+            recycle_memory_mb: 0,
+            recycle_sessions: 0,
+            recycle_uptime: 0,
+            health_interval: 10,
+            health_timeout: 5000,
+            ready_failure_threshold: 1,
+            live_failure_threshold: 3,
+            debug_json: false,
+            test_no_args: false,
+            chrome_flags: vec![],
+            remove_chrome_flags: vec![],
+            profiles_dir: None,
+            fork_flags: vec![],
+            user_data_base_dir: None,
+        }
+    }
+}
+
+/// The error loading or validating the config.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The config file could not be read.
+    Io(PathBuf, std::io::Error),
+    /// The config file could not be parsed.
+    Parse(PathBuf, String),
+    /// An env variable has an invalid value.
+    Env(&'static str, String),
+    /// A command line flag is unknown or has an invalid value.
+    Flag(String, String),
+    /// The help was requested.
+    Help,
+    /// The config values are invalid.
+    Invalid(String),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => {
+                write!(f, "failed to read config {}: {}", path.display(), e)
+            }
+            ConfigError::Parse(path, e) => {
+                write!(f, "failed to parse config {}: {}", path.display(), e)
+            }
+            ConfigError::Env(name, e) => write!(f, "invalid env {}: {}", name, e),
+            ConfigError::Flag(flag, e) => write!(f, "invalid flag {}: {}", flag, e),
+            ConfigError::Help => write!(f, "{}", USAGE),
+            ConfigError::Invalid(e) => write!(f, "invalid config: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Load the config from the file set by `CHROME_SERVER_CONFIG` and the env variables.
+    pub fn load() -> Result<Self, ConfigError> {
+        let mut config = match non_empty_env(CONFIG_ENV) {
+            Some(path) => Self::from_file(path)?,
+            _ => Self::default(),
+        };
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Load the config from the command line flags. The flags override the file and env variables.
+    pub fn from_args<I, S>(args: I) -> Result<Self, ConfigError>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let args: Vec<String> = args.into_iter().map(Into::into).collect();
+        let mut flags = vec![];
+        let mut config_path = non_empty_env(CONFIG_ENV);
+        let mut iter = args.into_iter();
+
+        while let Some(arg) = iter.next() {
+            if arg == "-h" || arg == "--help" {
+                return Err(ConfigError::Help);
+            }
+
+            let (flag, value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag.to_string(), value.to_string()),
+                _ => {
+                    if !arg.starts_with("--") {
+                        return Err(ConfigError::Flag(arg, "unexpected argument".into()));
+                    }
+                    match iter.next() {
+                        Some(value) => (arg, value),
+                        _ => return Err(ConfigError::Flag(arg, "missing value".into())),
+                    }
+                }
+            };
+
+            if flag == "--config" {
+                config_path = Some(value);
+            } else {
+                flags.push((flag, value));
+            }
+        }
+
+        let mut config = match config_path {
+            Some(path) => Self::from_file(path)?,
+            _ => Self::default(),
+        };
+
+        config.apply_env()?;
+
+        for (flag, value) in flags {
+            config.apply_flag(&flag, &value)?;
+        }
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Load the config file. Files ending with `.json` are parsed as JSON and everything else as TOML.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path).map_err(|e| ConfigError::Io(path.into(), e))?;
+
+        if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::from_str(&content)
+                .map_err(|e| ConfigError::Parse(path.into(), e.to_string()))
+        } else {
+            toml::from_str(&content).map_err(|e| ConfigError::Parse(path.into(), e.to_string()))
+        }
+    }
+
+    /// Override the values with the env variables set.
+    pub fn apply_env(&mut self) -> Result<(), ConfigError> {
+        if let Some(v) = non_empty_env("CHROME_PATH") {
+            self.chrome_path = Some(v);
+        }
+        if let Some(v) = non_empty_env("BRAVE_ENABLED") {
+            self.brave = parse_env("BRAVE_ENABLED", &v, parse_bool)?;
+        }
+        if let Some(v) = non_empty_env("REMOTE_ADDRESS") {
+            self.chrome_address = v;
+        }
+        if let Some(v) = non_empty_env("CHROME_INIT") {
+            // any value other than true or init skips the launch.
+            self.init = v == "true" || v == "init";
+        }
+        if let Some(v) = non_empty_env("DEFAULT_PORT") {
+            self.chrome_port = parse_env("DEFAULT_PORT", &v, parse_num)?;
+        }
+        if let Some(v) = non_empty_env("DEFAULT_PORT_SERVER") {
+            self.server_port = parse_env("DEFAULT_PORT_SERVER", &v, parse_num)?;
+        }
+        if let Some(v) = non_empty_env("PROXY_PORT") {
+            self.proxy_port = Some(parse_env("PROXY_PORT", &v, parse_num)?);
+        }
+        if let Some(v) = non_empty_env("CHROME_PORT_RANGE") {
+            self.port_range = Some(parse_env("CHROME_PORT_RANGE", &v, parse_range)?);
+        }
+        if let Some(v) = non_empty_env("HEADLESS") {
+            self.headless = parse_env("HEADLESS", &v, |v| v.parse())?;
+        }
+        if let Some(v) = non_empty_env("CHROME_GL") {
+            self.use_gl = v;
+        }
+        if let Some(v) = non_empty_env("ENABLE_GPU") {
+            self.enable_gpu = parse_env("ENABLE_GPU", &v, parse_bool)?;
+        }
+        if let Some(v) = non_empty_env("HOSTNAME_OVERRIDE").or_else(|| non_empty_env("HOSTNAME")) {
+            self.hostname = Some(v);
+        }
+        if let Some(v) = non_empty_env("ADVERTISED_PORT") {
+            self.advertised_port = Some(parse_env("ADVERTISED_PORT", &v, parse_num)?);
+        }
+        if let Some(v) = non_empty_env("ADVERTISED_SCHEME") {
+            self.advertised_scheme = Some(v);
+        }
+        if let Some(v) = non_empty_env("ADVERTISE_REQUEST_HOST") {
+            self.advertise_request_host = parse_env("ADVERTISE_REQUEST_HOST", &v, parse_bool)?;
+        }
+        if let Some(v) = non_empty_env("TRUSTED_PROXIES") {
+            self.trusted_proxies = v.split_whitespace().map(String::from).collect();
+        }
+        if let Some(v) = non_empty_env("LOAD_BALANCER") {
+            self.load_balancer = parse_env("LOAD_BALANCER", &v, |v| v.parse())?;
+        }
+        if let Some(v) = non_empty_env("PROXY_MODE") {
+            self.proxy_mode = parse_env("PROXY_MODE", &v, |v| v.parse())?;
+        }
+        if let Some(v) = non_empty_env("CDP_MAX_SESSIONS") {
+            self.cdp_max_sessions = parse_env("CDP_MAX_SESSIONS", &v, parse_num)?;
+        }
+        if let Some(v) = non_empty_env("CDP_MAX_MESSAGE_MB") {
+            self.cdp_max_message_mb = parse_env("CDP_MAX_MESSAGE_MB", &v, parse_num)?;
+        }
+        if let Some(v) = non_empty_env("CDP_ALLOW") {
+            self.cdp_allow = v.split_whitespace().map(String::from).collect();
+        }
+        if let Some(v) = non_empty_env("CDP_DENY") {
+            self.cdp_deny = v.split_whitespace().map(String::from).collect();
+        }
+        if let Some(v) = non_empty_env("CDP_DOWNLOAD_DIR") {
+            self.cdp_download_dir = Some(v);
+        }
+        if let Some(v) = non_empty_env("API_KEYS") {
+            self.api_keys = v.split_whitespace().map(String::from).collect();
+        }
+        if let Some(v) = non_empty_env("API_WRITE_KEYS") {
+            self.api_write_keys = v.split_whitespace().map(String::from).collect();
+        }
+        if let Some(v) = non_empty_env("PROXY_KEYS") {
+            self.proxy_keys = v.split_whitespace().map(String::from).collect();
+        }
+        if let Some(v) = non_empty_env("TLS_CERT") {
+            self.tls_cert = Some(v);
+        }
+        if let Some(v) = non_empty_env("TLS_KEY") {
+            self.tls_key = Some(v);
+        }
+        if let Some(v) = non_empty_env("BUFFER_SIZE") {
+            self.buffer_size = parse_env("BUFFER_SIZE", &v, parse_num)?;
+        }
+        if let Some(v) = non_empty_env("DRAIN_TIMEOUT") {
+            self.drain_timeout = parse_env("DRAIN_TIMEOUT", &v, parse_num)?;
+        }
+        if let Some(v) = non_empty_env("RESTART_BACKOFF") {
+            self.restart_backoff = parse_env("RESTART_BACKOFF", &v, parse_num)?;
+        }
+        if let Some(v) = non_empty_env("CRASH_LOOP_LIMIT") {
+            self.crash_loop_limit = parse_env("CRASH_LOOP_LIMIT", &v, parse_num)?;
+        }
+        if let Some(v) = non_empty_env("CRASH_LOOP_WINDOW") {
+            self.crash_loop_window = parse_env("CRASH_LOOP_WINDOW", &v, parse_num)?;
+        }
+        if let Some(v) = non_empty_env("SHUTDOWN_GRACE") {
+            self.shutdown_grace = parse_env("SHUTDOWN_GRACE", &v, parse_num)?;
+        }
+        if let Some(v) = non_empty_env("USAGE_INTERVAL") {
+            self.usage_interval = parse_env("USAGE_INTERVAL", &v, parse_num)?;
+        }
+        if let Some(v) = non_empty_env("RECYCLE_MEMORY_MB") {
+            self.recycle_memory_mb = parse_env("RECYCLE_MEMORY_MB", &v, parse_num)?;
+        }
+        if let Some(v) = non_empty_env("RECYCLE_SESSIONS") {
+            self.recycle_sessions = parse_env("RECYCLE_SESSIONS", &v, parse_num)?;
+        }
+        if let Some(v) = non_empty_env("RECYCLE_UPTIME") {
+            self.recycle_uptime = parse_env("RECYCLE_UPTIME", &v, parse_num)?;
+        }
+        if let Some(v) = non_empty_env("HEALTH_INTERVAL") {
+            self.health_interval = parse_env("HEALTH_INTERVAL", &v, parse_num)?;
+        }
+        if let Some(v) = non_empty_env("HEALTH_TIMEOUT") {
+            self.health_timeout = parse_env("HEALTH_TIMEOUT", &v, parse_num)?;
+        }
+        if let Some(v) = non_empty_env("READY_FAILURE_THRESHOLD") {
+            self.ready_failure_threshold = parse_env("READY_FAILURE_THRESHOLD", &v, parse_num)?;
+        }
+        if let Some(v) = non_empty_env("LIVE_FAILURE_THRESHOLD") {
+            self.live_failure_threshold = parse_env("LIVE_FAILURE_THRESHOLD", &v, parse_num)?;
+        }
+        if let Some(v) = non_empty_env("DEBUG_JSON") {
+            self.debug_json = parse_env("DEBUG_JSON", &v, parse_bool)?;
+        }
+        if let Some(v) = non_empty_env("TEST_NO_ARGS") {
+            self.test_no_args = parse_env("TEST_NO_ARGS", &v, parse_bool)?;
+        }
+        if let Some(v) = non_empty_env("CHROME_FLAGS") {
+            self.chrome_flags = v.split_whitespace().map(String::from).collect();
+        }
+        if let Some(v) = non_empty_env("CHROME_FLAGS_REMOVE") {
+            self.remove_chrome_flags = v.split_whitespace().map(String::from).collect();
+        }
+        if let Some(v) = non_empty_env("PROFILES_DIR") {
+            self.profiles_dir = Some(v);
+        }
+        if let Some(v) = non_empty_env("FORK_FLAGS") {
+            self.fork_flags = v.split_whitespace().map(String::from).collect();
+        }
+        if let Some(v) = non_empty_env("USER_DATA_BASE_DIR") {
+            self.user_data_base_dir = Some(v);
+        }
+
+        Ok(())
+    }
+
+    /// Override the value of the command line flag.
+    fn apply_flag(&mut self, flag: &str, value: &str) -> Result<(), ConfigError> {
+        let invalid = |e: String| ConfigError::Flag(flag.to_string(), e);
+
+        match flag {
+            "--chrome-path" => self.chrome_path = Some(value.to_string()),
+            "--address" => self.chrome_address = value.to_string(),
+            "--launch" => {
+                self.init = match value {
+                    "init" => true,
+                    "ignore" => false,
+                    v => return Err(invalid(format!("expected init or ignore but got {}", v))),
+                }
+            }
+            "--port" => self.chrome_port = parse_num(value).map_err(invalid)?,
+            "--server-port" => self.server_port = parse_num(value).map_err(invalid)?,
+            "--proxy-port" => self.proxy_port = Some(parse_num(value).map_err(invalid)?),
+            "--port-range" => self.port_range = Some(parse_range(value).map_err(invalid)?),
+            "--headless" => self.headless = value.parse().map_err(invalid)?,
+            "--hostname" => self.hostname = Some(value.to_string()),
+            "--load-balancer" => self.load_balancer = value.parse().map_err(invalid)?,
+            "--proxy-mode" => self.proxy_mode = value.parse().map_err(invalid)?,
+            "--profiles-dir" => self.profiles_dir = Some(value.to_string()),
+            "--tls-cert" => self.tls_cert = Some(value.to_string()),
+            "--tls-key" => self.tls_key = Some(value.to_string()),
+            "--chrome-flag" => self.chrome_flags.push(value.to_string()),
+            "--remove-chrome-flag" => self.remove_chrome_flags.push(value.to_string()),
+            _ => return Err(invalid("unknown flag".into())),
+        }
+
+        Ok(())
+    }
+
+    /// Validate the config values.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |e: String| Err(ConfigError::Invalid(e));
+
+        if self.chrome_port == 0 || self.chrome_port > u16::MAX.into() {
+            return invalid(format!(
+                "chrome_port {} is not a valid port",
+                self.chrome_port
+            ));
+        }
+        if self.server_port == 0 {
+            return invalid("server_port cannot be 0".into());
+        }
+
+        let proxy_port = self.proxy_port();
+
+        if proxy_port == 0 {
+            return invalid("proxy_port cannot be 0".into());
+        }
+        if u32::from(proxy_port) == self.chrome_port {
+            return invalid(format!(
+                "proxy_port {} cannot be the chrome_port",
+                proxy_port
+            ));
+        }
+        if proxy_port == self.server_port {
+            return invalid(format!(
+                "proxy_port {} cannot be the server_port",
+                proxy_port
+            ));
+        }
+        if let Some([start, end]) = self.port_range {
+            if start == 0 || start > end || end > u16::MAX.into() {
+                return invalid(format!("port_range {}-{} is not a valid range", start, end));
+            }
+        }
+        if self.use_gl != "angle" && self.use_gl != "swiftshader" {
+            return invalid(format!(
+                "use_gl must be angle or swiftshader but got {}",
+                self.use_gl
+            ));
+        }
+        if self.buffer_size == 0 {
+            return invalid("buffer_size cannot be 0".into());
+        }
+        if self.recycle_memory_mb > 0 && self.usage_interval == 0 {
+            return invalid("recycle_memory_mb requires a usage_interval".into());
+        }
+        if self.cdp_max_sessions > 0 && self.proxy_mode != ProxyMode::Cdp {
+            return invalid("cdp_max_sessions requires the cdp proxy_mode".into());
+        }
+        if self.cdp_max_message_mb == 0 {
+            return invalid("cdp_max_message_mb cannot be 0".into());
+        }
+        if crate::policy::enabled(self) && self.proxy_mode != ProxyMode::Cdp {
+            return invalid("the CDP policy requires the cdp proxy_mode".into());
+        }
+        for pattern in self.cdp_allow.iter().chain(&self.cdp_deny) {
+            crate::policy::validate_pattern(pattern).map_err(ConfigError::Invalid)?;
+        }
+        if self
+            .api_keys
+            .iter()
+            .chain(&self.api_write_keys)
+            .chain(&self.proxy_keys)
+            .any(|key| key.trim().is_empty())
+        {
+            return invalid("the keys cannot be empty".into());
+        }
+        if let Some(scheme) = &self.advertised_scheme {
+            if scheme != "ws" && scheme != "wss" {
+                return invalid(format!(
+                    "advertised_scheme must be ws or wss but got {}",
+                    scheme
+                ));
+            }
+        }
+        if self.advertised_port == Some(0) {
+            return invalid("advertised_port cannot be 0".into());
+        }
+        for proxy in &self.trusted_proxies {
+            crate::forwarded::validate_proxy(proxy).map_err(ConfigError::Invalid)?;
+        }
+        if !self.trusted_proxies.is_empty() && !self.advertise_request_host {
+            return invalid("trusted_proxies requires advertise_request_host".into());
+        }
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            return invalid("tls_cert and tls_key have to be set together".into());
+        }
+        if let Some(dir) = &self.cdp_download_dir {
+            if !Path::new(dir).is_absolute() {
+                return invalid(format!("cdp_download_dir {} must be absolute", dir));
+            }
+        }
+        if self.health_timeout == 0 {
+            return invalid("health_timeout cannot be 0".into());
+        }
+        if self.ready_failure_threshold == 0 || self.live_failure_threshold == 0 {
+            return invalid("the failure thresholds cannot be 0".into());
+        }
+        for flag in self.chrome_flags.iter().chain(&self.remove_chrome_flags) {
+            if !flag.starts_with("--") {
+                return invalid(format!("chrome flag {} must start with --", flag));
+            }
+            if crate::conf::MANAGED_FLAGS.contains(&crate::conf::flag_name(flag)) {
+                return invalid(format!("chrome flag {} is set by the server", flag));
+            }
+        }
+        for flag in &self.fork_flags {
+            let name = crate::conf::flag_name(flag);
+
+            if !flag.starts_with("--") {
+                return invalid(format!("fork flag {} must start with --", flag));
+            }
+            if crate::conf::MANAGED_FLAGS.contains(&name)
+                || crate::launch::UNSAFE_FLAGS.contains(&name)
+                || name == "--user-data-dir"
+            {
+                return invalid(format!("fork flag {} cannot be allowed", flag));
+            }
+        }
+        if let Some(dir) = &self.user_data_base_dir {
+            if !Path::new(dir).is_absolute() {
+                return invalid(format!("user_data_base_dir {} must be absolute", dir));
+            }
+        }
+        if let Some(path) = &self.chrome_path {
+            if path.contains(std::path::MAIN_SEPARATOR) && !Path::new(path).exists() {
+                tracing::warn!("chrome_path {} does not exist", path);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The proxy entry port.
+    pub fn proxy_port(&self) -> u16 {
+        self.proxy_port
+            .unwrap_or(if self.chrome_port == 9223 { 9222 } else { 9223 })
+    }
+
+    /// The directory of the named profiles.
+    pub fn profiles_dir(&self) -> PathBuf {
+        match &self.profiles_dir {
+            Some(dir) => dir.into(),
+            _ => match std::env::var_os("HOME") {
+                Some(home) => Path::new(&home).join(".config/headless_browser/profiles"),
+                _ => std::env::temp_dir().join("headless_browser/profiles"),
+            },
+        }
+    }
+
+    /// The inclusive port range handed out to forked instances.
+    pub fn port_range(&self) -> std::ops::RangeInclusive<u32> {
+        match self.port_range {
+            Some([start, end]) => start..=end,
+            _ => self.chrome_port..=self.chrome_port + 99,
+        }
+    }
+}
+
+/// The env variable if set and not empty.
+fn non_empty_env(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+/// Parse the env variable value.
+fn parse_env<T>(
+    name: &'static str,
+    value: &str,
+    parse: impl Fn(&str) -> Result<T, String>,
+) -> Result<T, ConfigError> {
+    parse(value).map_err(|e| ConfigError::Env(name, e))
+}
+
+/// Parse a boolean value.
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value.trim() {
+        "true" | "1" => Ok(true),
+        "false" | "0" => Ok(false),
+        v => Err(format!("expected true or false but got {}", v)),
+    }
+}
+
+/// Parse a number value.
+fn parse_num<T: FromStr>(value: &str) -> Result<T, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| format!("expected a number but got {}", value))
+}
+
+/// Parse a port range ex: 9223-9322.
+fn parse_range(value: &str) -> Result<[u32; 2], String> {
+    match value.split_once('-') {
+        Some((start, end)) => Ok([parse_num(start)?, parse_num(end)?]),
+        _ => Err(format!("expected START-END but got {}", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Serializes the tests reading the process env.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    /// Write the config file to the temp dir.
+    fn config_file(name: &str, content: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("headless_browser-{}-{}", std::process::id(), name));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn flag_values_with_space_or_equals() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let config = Config::from_args(["--port", "9300", "--server-port=6100"]).unwrap();
+
+        assert_eq!(config.chrome_port, 9300);
+        assert_eq!(config.server_port, 6100);
+    }
+
+    #[test]
+    fn repeated_flags_append() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let config = Config::from_args([
+            "--chrome-flag",
+            "--window-size=1024,768",
+            "--chrome-flag=--mute-audio",
+        ])
+        .unwrap();
+
+        assert_eq!(
+            config.chrome_flags,
+            ["--window-size=1024,768", "--mute-audio"]
+        );
+    }
+
+    #[test]
+    fn invalid_flags() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+
+        assert!(matches!(
+            Config::from_args(["--port"]),
+            Err(ConfigError::Flag(flag, _)) if flag == "--port"
+        ));
+        assert!(matches!(
+            Config::from_args(["9300"]),
+            Err(ConfigError::Flag(flag, _)) if flag == "9300"
+        ));
+        assert!(matches!(
+            Config::from_args(["--unknown=1"]),
+            Err(ConfigError::Flag(flag, _)) if flag == "--unknown"
+        ));
+        assert!(matches!(
+            Config::from_args(["--port=chrome"]),
+            Err(ConfigError::Flag(flag, _)) if flag == "--port"
+        ));
+        assert!(matches!(
+            Config::from_args(["--launch", "maybe"]),
+            Err(ConfigError::Flag(flag, _)) if flag == "--launch"
+        ));
+        assert!(matches!(
+            Config::from_args(["--help"]),
+            Err(ConfigError::Help)
+        ));
+    }
+
+    #[test]
+    fn file_then_env_then_flags() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let path = config_file(
+            "precedence.toml",
+            "chrome_port = 9300\nserver_port = 6100\nproxy_port = 9400\n",
+        );
+
+        std::env::set_var("DEFAULT_PORT_SERVER", "6200");
+        std::env::set_var("PROXY_PORT", "9500");
+
+        let config = Config::from_args([
+            "--config".to_string(),
+            path.display().to_string(),
+            "--proxy-port=9600".to_string(),
+        ]);
+
+        std::env::remove_var("DEFAULT_PORT_SERVER");
+        std::env::remove_var("PROXY_PORT");
+        let _ = std::fs::remove_file(&path);
+
+        let config = config.unwrap();
+
+        assert_eq!(config.chrome_port, 9300);
+        assert_eq!(config.server_port, 6200);
+        assert_eq!(config.proxy_port, Some(9600));
+    }
+
+    #[test]
+    fn invalid_env() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+
+        std::env::set_var("DEFAULT_PORT", "chrome");
+        let config = Config::from_args(Vec::<String>::new());
+        std::env::remove_var("DEFAULT_PORT");
+
+        assert!(matches!(config, Err(ConfigError::Env("DEFAULT_PORT", _))));
+    }
+
+    #[test]
+    fn unknown_fields_rejected() {
+        let toml = config_file("unknown.toml", "chrome_port = 9300\nchrome_prot = 9301\n");
+        let json = config_file(
+            "unknown.json",
+            r#"{ "chrome_port": 9300, "unknown": true }"#,
+        );
+
+        let from_toml = Config::from_file(&toml);
+        let from_json = Config::from_file(&json);
+
+        let _ = std::fs::remove_file(&toml);
+        let _ = std::fs::remove_file(&json);
+
+        assert!(matches!(from_toml, Err(ConfigError::Parse(..))));
+        assert!(matches!(from_json, Err(ConfigError::Parse(..))));
+    }
+
+    #[test]
+    fn json_file() {
+        let path = config_file(
+            "valid.json",
+            r#"{ "chrome_port": 9300, "headless": "new" }"#,
+        );
+        let config = Config::from_file(&path);
+        let _ = std::fs::remove_file(&path);
+
+        let config = config.unwrap();
+
+        assert_eq!(config.chrome_port, 9300);
+        assert_eq!(config.headless, HeadlessMode::New);
+    }
+
+    #[test]
+    fn default_is_valid() {
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[test]
+    fn cross_field_rules() {
+        let invalid = |edit: fn(&mut Config)| {
+            let mut config = Config::default();
+            edit(&mut config);
+            matches!(config.validate(), Err(ConfigError::Invalid(_)))
+        };
+
+        assert!(invalid(|c| c.proxy_port = Some(9223)));
+        assert!(invalid(|c| c.proxy_port = Some(6000)));
+        assert!(invalid(|c| c.chrome_port = 70000));
+        assert!(invalid(|c| c.port_range = Some([9400, 9300])));
+        assert!(invalid(|c| c.use_gl = "vulkan".into()));
+        assert!(invalid(|c| {
+            c.recycle_memory_mb = 512;
+            c.usage_interval = 0;
+        }));
+        assert!(invalid(|c| c.cdp_max_sessions = 4));
+        assert!(invalid(|c| c.cdp_max_message_mb = 0));
+        assert!(invalid(|c| c.cdp_deny = vec!["Browser.close".into()]));
+        assert!(invalid(|c| c.api_keys = vec![" ".into()]));
+        assert!(invalid(|c| c.advertised_scheme = Some("https".into())));
+        assert!(invalid(|c| c.trusted_proxies = vec!["10.0.0.0/8".into()]));
+        assert!(invalid(|c| c.tls_cert = Some("cert.pem".into())));
+        assert!(invalid(|c| c.cdp_download_dir = Some("downloads".into())));
+        assert!(invalid(|c| c.live_failure_threshold = 0));
+        assert!(invalid(|c| c.chrome_flags = vec!["mute-audio".into()]));
+        assert!(invalid(
+            |c| c.remove_chrome_flags = vec!["--remote-debugging-port".into()]
+        ));
+        assert!(invalid(|c| c.fork_flags = vec!["--gpu-launcher".into()]));
+        assert!(invalid(|c| c.fork_flags = vec!["--user-data-dir".into()]));
+        assert!(invalid(|c| c.user_data_base_dir = Some("profiles".into())));
+
+        let config = Config {
+            proxy_mode: ProxyMode::Cdp,
+            cdp_max_sessions: 4,
+            cdp_deny: vec!["Browser.*".into()],
+            proxy_keys: vec!["secret".into()],
+            ..Default::default()
+        };
+
+        assert!(config.validate().is_ok());
+
+        let config = Config {
+            proxy_keys: vec!["secret".into()],
+            ..Default::default()
+        };
+
+        assert!(config.validate().is_ok());
+    }
+}
@@ -0,0 +1,340 @@
+use crate::modify::{self, Advertised};
+use crate::routing;
+use crate::{connect_with_retries, json_error, Server};
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::header::{HeaderValue, CONTENT_TYPE, HOST};
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::convert::Infallible;
+use std::time::Duration;
+use tokio::time::timeout;
+
+/// The time an instance has to answer a discovery request.
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The response of chrome.
+pub(crate) struct Reply {
+    /// The status code.
+    pub status: StatusCode,
+    /// The content type.
+    pub content_type: Option<HeaderValue>,
+    /// The body.
+    pub body: Bytes,
+}
+
+impl Reply {
+    /// The response to the client. The websocket urls of json bodies are rewritten to the advertised address.
+    fn into_response(self, advertised: Option<&Advertised>) -> Response<Full<Bytes>> {
+        let body = match advertised {
+            Some(advertised) if self.status.is_success() => {
+                modify::modify_json_output(self.body, advertised)
+            }
+            _ => self.body,
+        };
+
+        let mut resp = Response::new(Full::new(body));
+
+        *resp.status_mut() = self.status;
+
+        if let Some(content_type) = self.content_type {
+            resp.headers_mut().insert(CONTENT_TYPE, content_type);
+        }
+
+        resp
+    }
+}
+
+/// Send the discovery request to the instance on the port.
+pub(crate) async fn fetch(
+    server: &Server,
+    port: u32,
+    method: &Method,
+    path: &str,
+) -> Option<Reply> {
+    let request = async {
+        let address = format!("127.0.0.1:{}", port);
+        let stream = connect_with_retries(server, &address).await?;
+        let (mut client, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+            .await
+            .ok()?;
+
+        tokio::task::spawn(async move {
+            if let Err(err) = conn.await {
+                tracing::error!("Connection failed: {:?}", err);
+            }
+        });
+
+        // chrome only answers the requests with an ip or localhost host.
+        let req = Request::builder()
+            .method(method)
+            .uri(path)
+            .header(HOST, &address)
+            .body(http_body_util::Empty::<Bytes>::new())
+            .ok()?;
+
+        let resp = client.send_request(req).await.ok()?;
+        let status = resp.status();
+        let content_type = resp.headers().get(CONTENT_TYPE).cloned();
+        let body = resp.into_body().collect().await.ok()?.to_bytes();
+
+        Some(Reply {
+            status,
+            content_type,
+            body,
+        })
+    };
+
+    timeout(DISCOVERY_TIMEOUT, request).await.ok().flatten()
+}
+
+/// The ports of the instances accepting connections. The chrome port is used without instances.
+fn ports(server: &Server) -> Vec<u32> {
+    let ports: Vec<u32> = server
+        .instances
+        .instances()
+        .into_iter()
+        .filter(|i| i.state != crate::pool::InstanceState::Draining)
+        .map(|i| i.port)
+        .collect();
+
+    if ports.is_empty() {
+        vec![server.config.chrome_port]
+    } else {
+        ports
+    }
+}
+
+/// The bad gateway response of an instance that did not answer.
+fn unreachable() -> Response<Full<Bytes>> {
+    json_error(StatusCode::BAD_GATEWAY, "chrome is not reachable")
+}
+
+/// Json list handler. The instances are asked at once and the targets of every instance are listed in port order.
+pub(crate) async fn list_handler(
+    server: &Server,
+    advertised: Option<Advertised>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let mut targets = vec![];
+    let mut answered = false;
+    let ports = ports(server);
+    let replies = futures_util::future::join_all(
+        ports
+            .iter()
+            .map(|port| fetch(server, *port, &Method::GET, "/json/list")),
+    )
+    .await;
+
+    for (port, reply) in ports.into_iter().zip(replies) {
+        if let Some(reply) = reply {
+            if let Ok(serde_json::Value::Array(list)) =
+                serde_json::from_slice::<serde_json::Value>(&reply.body)
+            {
+                answered = true;
+                routing::reconcile(server, port, &reply.body);
+                targets.extend(list);
+            }
+        }
+    }
+
+    if !answered {
+        return Ok(unreachable());
+    }
+
+    let reply = Reply {
+        status: StatusCode::OK,
+        content_type: Some(HeaderValue::from_static("application/json")),
+        body: serde_json::to_vec(&targets).unwrap_or_default().into(),
+    };
+
+    Ok(reply.into_response(advertised.as_ref()))
+}
+
+/// Json new handler. The target opens on the instance selected by the balancer.
+pub(crate) async fn new_handler(
+    server: &Server,
+    method: &Method,
+    path: &str,
+    advertised: Option<Advertised>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let port = server
+        .instances
+        .next_port()
+        .unwrap_or(server.config.chrome_port);
+
+    match fetch(server, port, method, path).await {
+        Some(reply) => {
+            if reply.status.is_success() {
+                routing::learn(server, port, &reply.body);
+            }
+            Ok(reply.into_response(advertised.as_ref()))
+        }
+        _ => Ok(unreachable()),
+    }
+}
+
+/// Json protocol handler.
+pub(crate) async fn protocol_handler(server: &Server) -> Result<Response<Full<Bytes>>, Infallible> {
+    let port = server
+        .instances
+        .next_port()
+        .unwrap_or(server.config.chrome_port);
+
+    match fetch(server, port, &Method::GET, "/json/protocol").await {
+        Some(reply) => Ok(reply.into_response(None)),
+        _ => Ok(unreachable()),
+    }
+}
+
+/// Json activate and close handler. The request goes to the instances in turn starting with the known owner until the one owning the target answers.
+pub(crate) async fn target_handler(
+    server: &Server,
+    method: &Method,
+    path: &str,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let mut last = None;
+    let mut ports = ports(server);
+
+    // the known owner is asked first.
+    if let Some(owner) = path
+        .rsplit('/')
+        .next()
+        .and_then(|id| server.instances.target_owner(id))
+    {
+        ports.retain(|port| *port != owner.port);
+        ports.insert(0, owner.port);
+    }
+
+    for port in ports {
+        if let Some(reply) = fetch(server, port, method, path).await {
+            if reply.status != StatusCode::NOT_FOUND {
+                if reply.status.is_success() {
+                    routing::forget(server, path);
+                }
+                return Ok(reply.into_response(None));
+            }
+            last = Some(reply);
+        }
+    }
+
+    match last {
+        Some(reply) => Ok(reply.into_response(None)),
+        _ => Ok(unreachable()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::testing::FakeChrome;
+
+    /// The server balancing the fake chromes.
+    fn server(chromes: &[&FakeChrome]) -> Server {
+        let server = Server::new(Config::default());
+
+        for (pid, chrome) in chromes.iter().enumerate() {
+            server.instances.insert(
+                pid as u32 + 1,
+                chrome.port,
+                Default::default(),
+                Default::default(),
+                None,
+            );
+            server.instances.mark_running(chrome.port);
+        }
+
+        server
+    }
+
+    /// The body of the response.
+    async fn body(response: Response<Full<Bytes>>) -> serde_json::Value {
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&body).unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn list_merges_the_targets_of_every_instance() {
+        let (a, b) = (FakeChrome::start("a").await, FakeChrome::start("b").await);
+        let server = server(&[&a, &b]);
+        let (first, second) = (a.open(), b.open());
+
+        let response = list_handler(&server, None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let ids: Vec<String> = body(response)
+            .await
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|target| target["id"].as_str().unwrap().to_string())
+            .collect();
+
+        let mut expected = vec![(a.port, first.clone()), (b.port, second.clone())];
+        expected.sort();
+        assert_eq!(
+            ids,
+            expected.into_iter().map(|(_, id)| id).collect::<Vec<_>>()
+        );
+        assert_eq!(server.instances.target_owner(&first).unwrap().port, a.port);
+        assert_eq!(server.instances.target_owner(&second).unwrap().port, b.port);
+    }
+
+    #[tokio::test]
+    async fn list_fails_when_no_instance_answers() {
+        let server = Server::new(Config {
+            chrome_port: crate::testing::closed_port(),
+            ..Default::default()
+        });
+
+        let response = list_handler(&server, None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn new_target_is_owned_by_the_selected_instance() {
+        let chrome = FakeChrome::start("a").await;
+        let server = server(&[&chrome]);
+
+        let response = new_handler(&server, &Method::PUT, "/json/new?about:blank", None)
+            .await
+            .unwrap();
+        let id = body(response).await["id"].as_str().unwrap().to_string();
+
+        assert_eq!(chrome.requests(), ["PUT /json/new?about:blank"]);
+        assert_eq!(
+            server.instances.target_owner(&id).unwrap().port,
+            chrome.port
+        );
+    }
+
+    #[tokio::test]
+    async fn target_requests_ask_the_owner_first() {
+        let (a, b) = (FakeChrome::start("a").await, FakeChrome::start("b").await);
+        let server = server(&[&a, &b]);
+        let id = b.open();
+        server.instances.record_target(&id, b.port, true);
+
+        let path = format!("/json/close/{}", id);
+        let response = target_handler(&server, &Method::GET, &path).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(a.requests().is_empty());
+        assert_eq!(b.requests(), [format!("GET {}", path)]);
+        assert!(server.instances.target_owner(&id).is_none());
+    }
+
+    #[tokio::test]
+    async fn unknown_targets_are_asked_of_every_instance() {
+        let (a, b) = (FakeChrome::start("a").await, FakeChrome::start("b").await);
+        let server = server(&[&a, &b]);
+
+        let response = target_handler(&server, &Method::GET, "/json/activate/missing")
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(a.requests(), ["GET /json/activate/missing"]);
+        assert_eq!(b.requests(), ["GET /json/activate/missing"]);
+    }
+}
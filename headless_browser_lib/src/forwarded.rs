@@ -0,0 +1,253 @@
+use crate::config::Config;
+use crate::modify::Advertised;
+use hyper::header::HOST;
+use hyper::http::uri::Authority;
+use hyper::HeaderMap;
+use std::net::IpAddr;
+
+/// Advertise the host the client used. The `Host` header sets the host and the forwarded headers of the trusted proxies take precedence.
+/// The port of the `Host` header is the management port so only `X-Forwarded-Port` changes the port.
+pub(crate) fn apply(
+    advertised: &mut Advertised,
+    config: &Config,
+    headers: &HeaderMap,
+    peer: IpAddr,
+) {
+    let trusted = config
+        .trusted_proxies
+        .iter()
+        .any(|proxy| contains(proxy, peer));
+
+    // the first value is the address the client used when the request passed several proxies.
+    let forwarded = |name: &str| {
+        if !trusted {
+            return None;
+        }
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(',').next())
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+    };
+
+    let host = forwarded("x-forwarded-host")
+        .or_else(|| headers.get(HOST).and_then(|v| v.to_str().ok()))
+        .and_then(|v| v.parse::<Authority>().ok());
+
+    if let Some(host) = host {
+        advertised.host = Some(host.host().to_string());
+    }
+    if let Some(port) = forwarded("x-forwarded-port").and_then(|v| v.parse().ok()) {
+        advertised.port = port;
+    }
+    match forwarded("x-forwarded-proto") {
+        Some("https" | "wss") => advertised.secure = true,
+        Some("http" | "ws") => advertised.secure = false,
+        _ => (),
+    }
+}
+
+/// Validate the trusted proxy address or CIDR range.
+pub(crate) fn validate_proxy(proxy: &str) -> Result<(), String> {
+    match parse(proxy) {
+        Some(_) => Ok(()),
+        _ => Err(format!(
+            "trusted proxy {} must be an ip address or CIDR range",
+            proxy
+        )),
+    }
+}
+
+/// The network and prefix length of the address or CIDR range.
+fn parse(proxy: &str) -> Option<(IpAddr, u32)> {
+    let (ip, prefix) = match proxy.split_once('/') {
+        Some((ip, prefix)) => (
+            ip.parse::<IpAddr>().ok()?,
+            Some(prefix.parse::<u32>().ok()?),
+        ),
+        _ => (proxy.parse::<IpAddr>().ok()?, None),
+    };
+
+    let bits = if ip.is_ipv4() { 32 } else { 128 };
+    let prefix = prefix.unwrap_or(bits);
+
+    (prefix <= bits).then_some((ip, prefix))
+}
+
+/// Is the peer in the address or CIDR range?
+fn contains(proxy: &str, peer: IpAddr) -> bool {
+    let (network, prefix) = match parse(proxy) {
+        Some(network) => network,
+        _ => return false,
+    };
+
+    match (network, peer.to_canonical()) {
+        (IpAddr::V4(network), IpAddr::V4(peer)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            u32::from(network) & mask == u32::from(peer) & mask
+        }
+        (IpAddr::V6(network), IpAddr::V6(peer)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            u128::from(network) & mask == u128::from(peer) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    /// The peer address.
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    /// The headers of the request.
+    fn headers(values: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in values {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    /// The advertised address for the request from the peer with the trusted proxies.
+    fn advertise(trusted: &[&str], headers: &HeaderMap, peer: &str) -> Advertised {
+        let config = Config {
+            advertise_request_host: true,
+            trusted_proxies: trusted.iter().map(|p| p.to_string()).collect(),
+            ..Default::default()
+        };
+        let mut advertised = Advertised {
+            host: Some("configured".into()),
+            port: 9222,
+            secure: false,
+        };
+
+        apply(&mut advertised, &config, headers, ip(peer));
+
+        advertised
+    }
+
+    #[test]
+    fn ipv4_ranges() {
+        assert!(contains("10.0.0.0/8", ip("10.1.2.3")));
+        assert!(!contains("10.0.0.0/8", ip("11.0.0.1")));
+        assert!(contains("192.168.1.0/24", ip("192.168.1.255")));
+        assert!(!contains("192.168.1.0/24", ip("192.168.2.1")));
+        assert!(contains("10.0.0.1/32", ip("10.0.0.1")));
+        assert!(!contains("10.0.0.1/32", ip("10.0.0.2")));
+        assert!(contains("10.0.0.1", ip("10.0.0.1")));
+        assert!(!contains("10.0.0.1", ip("10.0.0.2")));
+        assert!(contains("0.0.0.0/0", ip("203.0.113.7")));
+    }
+
+    #[test]
+    fn ipv6_ranges() {
+        assert!(contains("fd00::/8", ip("fd12:3456::1")));
+        assert!(!contains("fd00::/8", ip("fe80::1")));
+        assert!(contains("2001:db8::/32", ip("2001:db8:ffff::1")));
+        assert!(contains("::1/128", ip("::1")));
+        assert!(!contains("::1/128", ip("::2")));
+        assert!(contains("::/0", ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn mapped_and_mixed_families() {
+        // ipv4 peers accepted on a dual stack socket are ipv4 mapped.
+        assert!(contains("10.0.0.0/8", ip("::ffff:10.0.0.1")));
+        assert!(!contains("::/0", ip("10.0.0.1")));
+        assert!(!contains("0.0.0.0/0", ip("::1")));
+    }
+
+    #[test]
+    fn proxy_validation() {
+        assert!(validate_proxy("10.0.0.0/8").is_ok());
+        assert!(validate_proxy("::1").is_ok());
+        assert!(validate_proxy("fd00::/128").is_ok());
+        assert!(validate_proxy("10.0.0.0/33").is_err());
+        assert!(validate_proxy("fd00::/129").is_err());
+        assert!(validate_proxy("10.0.0.0/").is_err());
+        assert!(validate_proxy("proxy.local").is_err());
+        assert!(!contains("proxy.local", ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn host_header() {
+        let advertised = advertise(&[], &headers(&[("host", "example.com:6000")]), "10.0.0.1");
+
+        assert_eq!(advertised.host.as_deref(), Some("example.com"));
+        assert_eq!(advertised.port, 9222);
+        assert!(!advertised.secure);
+    }
+
+    #[test]
+    fn untrusted_peer_forwarded_headers() {
+        let headers = headers(&[
+            ("host", "internal:6000"),
+            ("x-forwarded-host", "evil.com"),
+            ("x-forwarded-port", "1234"),
+            ("x-forwarded-proto", "https"),
+        ]);
+        let advertised = advertise(&["10.0.0.0/8"], &headers, "203.0.113.7");
+
+        assert_eq!(advertised.host.as_deref(), Some("internal"));
+        assert_eq!(advertised.port, 9222);
+        assert!(!advertised.secure);
+    }
+
+    #[test]
+    fn trusted_peer_forwarded_headers() {
+        let headers = headers(&[
+            ("host", "internal:6000"),
+            ("x-forwarded-host", "example.com"),
+            ("x-forwarded-port", "443"),
+            ("x-forwarded-proto", "https"),
+        ]);
+        let advertised = advertise(&["10.0.0.0/8"], &headers, "10.0.0.1");
+
+        assert_eq!(advertised.host.as_deref(), Some("example.com"));
+        assert_eq!(advertised.port, 443);
+        assert!(advertised.secure);
+    }
+
+    #[test]
+    fn multi_value_headers_use_the_first_value() {
+        let headers = headers(&[
+            ("x-forwarded-host", "example.com, proxy-1.internal"),
+            ("x-forwarded-host", "proxy-2.internal"),
+            ("x-forwarded-port", " 8443 ,80"),
+            ("x-forwarded-proto", "wss,http"),
+        ]);
+        let advertised = advertise(&["::1"], &headers, "::1");
+
+        assert_eq!(advertised.host.as_deref(), Some("example.com"));
+        assert_eq!(advertised.port, 8443);
+        assert!(advertised.secure);
+    }
+
+    #[test]
+    fn invalid_forwarded_values_are_ignored() {
+        let headers = headers(&[
+            ("host", "internal"),
+            ("x-forwarded-host", ""),
+            ("x-forwarded-port", "port"),
+            ("x-forwarded-proto", "gopher"),
+        ]);
+        let advertised = advertise(&["10.0.0.1"], &headers, "10.0.0.1");
+
+        assert_eq!(advertised.host.as_deref(), Some("internal"));
+        assert_eq!(advertised.port, 9222);
+        assert!(!advertised.secure);
+    }
+
+    #[test]
+    fn missing_host_keeps_the_configured_host() {
+        let advertised = advertise(&[], &HeaderMap::new(), "10.0.0.1");
+
+        assert_eq!(advertised.host.as_deref(), Some("configured"));
+    }
+}
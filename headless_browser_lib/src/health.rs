@@ -0,0 +1,95 @@
+use futures_util::{SinkExt, StreamExt};
+use http_body_util::{BodyExt, Empty};
+use hyper::{body::Bytes, Request};
+use hyper_util::rt::TokioIo;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+
+/// The CDP command id of the probe.
+const PROBE_ID: u64 = 1;
+
+/// Probe the instance with a CDP `Browser.getVersion` round trip on the browser target. Returns the browser product.
+pub(crate) async fn probe(port: u32, timeout: Duration) -> Result<String, String> {
+    tokio::time::timeout(timeout, browser_version(port))
+        .await
+        .map_err(|_| format!("timed out after {}ms", timeout.as_millis()))?
+}
+
+/// The browser product reported by `Browser.getVersion`.
+async fn browser_version(port: u32) -> Result<String, String> {
+    let url = browser_url(port).await?;
+
+    let (mut socket, _) = tokio_tungstenite::connect_async(url.as_str())
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let command = serde_json::json!({ "id": PROBE_ID, "method": "Browser.getVersion" });
+
+    socket
+        .send(Message::text(command.to_string()))
+        .await
+        .map_err(|e| e.to_string())?;
+
+    while let Some(message) = socket.next().await {
+        let text = match message.map_err(|e| e.to_string())? {
+            Message::Text(text) => text,
+            _ => continue,
+        };
+
+        let response: serde_json::Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+
+        // events can arrive before the response.
+        if response.get("id").and_then(|id| id.as_u64()) != Some(PROBE_ID) {
+            continue;
+        }
+
+        let _ = socket.close(None).await;
+
+        return match response.pointer("/result/product").and_then(|p| p.as_str()) {
+            Some(product) => Ok(product.to_string()),
+            _ => Err(format!("unexpected response {}", response)),
+        };
+    }
+
+    Err("connection closed before the response".into())
+}
+
+/// The browser target websocket url from /json/version.
+async fn browser_url(port: u32) -> Result<String, String> {
+    let address = format!("127.0.0.1:{}", port);
+    let stream = TcpStream::connect(&address)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let (mut client, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .map_err(|e| e.to_string())?;
+
+    tokio::spawn(async move {
+        let _ = conn.await;
+    });
+
+    let req = Request::builder()
+        .uri("/json/version")
+        .header(hyper::header::HOST, &address)
+        .body(Empty::<Bytes>::new())
+        .map_err(|e| e.to_string())?;
+
+    let body = client
+        .send_request(req)
+        .await
+        .map_err(|e| e.to_string())?
+        .into_body()
+        .collect()
+        .await
+        .map_err(|e| e.to_string())?
+        .to_bytes();
+
+    let version: serde_json::Value = serde_json::from_slice(&body).map_err(|e| e.to_string())?;
+
+    match version.get("webSocketDebuggerUrl").and_then(|u| u.as_str()) {
+        Some(url) => Ok(url.to_string()),
+        _ => Err("/json/version has no webSocketDebuggerUrl".into()),
+    }
+}
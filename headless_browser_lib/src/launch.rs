@@ -0,0 +1,313 @@
+use crate::conf::{flag_name, merge_flags, MANAGED_FLAGS};
+use crate::config::{Config, HeadlessMode};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// The flags that run a command or an executable from a path. The flags are never accepted in the launch options.
+pub(crate) const UNSAFE_FLAGS: [&str; 8] = [
+    "--renderer-cmd-prefix",
+    "--gpu-launcher",
+    "--utility-cmd-prefix",
+    "--browser-subprocess-path",
+    "--zygote-cmd-prefix",
+    "--nacl-loader-cmd-prefix",
+    "--ppapi-plugin-launcher",
+    "--plugin-launcher",
+];
+
+/// The launch options of a single instance. The options are applied over the chrome args of the server config.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LaunchOptions {
+    /// The chrome flags added to the launch args. A flag replaces the arg with the same name and the feature lists merge.
+    pub flags: Vec<String>,
+    /// The proxy server for the browser traffic ex: `http://proxy.example.com:8080`.
+    pub proxy_server: Option<String>,
+    /// The user agent.
+    pub user_agent: Option<String>,
+    /// The window width and height.
+    pub window_size: Option<[u32; 2]>,
+    /// The headless mode.
+    pub headless: Option<HeadlessMode>,
+    /// The browser locale ex: `en-US`.
+    pub locale: Option<String>,
+    /// The timezone set with the `TZ` env variable ex: `America/New_York`.
+    pub timezone: Option<String>,
+    /// The profile directory in the `user_data_base_dir` of the config. Defaults to a temporary directory removed when the instance exits.
+    pub user_data_dir: Option<String>,
+    /// The named profile kept across instances in the profiles directory.
+    pub profile: Option<String>,
+}
+
+impl LaunchOptions {
+    /// Validate the options against the config. The flags and the options setting a flag have to be in the `fork_flags` of
+    /// the config and the `user_data_dir` has to be a subdirectory of the `user_data_base_dir`.
+    pub fn validate(&self, config: &Config) -> Result<(), String> {
+        for flag in &self.flags {
+            let name = flag_name(flag);
+
+            if !flag.starts_with("--") {
+                return Err(format!("chrome flag {} must start with --", flag));
+            }
+            if MANAGED_FLAGS.contains(&name) {
+                return Err(format!("chrome flag {} is set by the server", flag));
+            }
+            if name == "--user-data-dir" {
+                return Err(format!("chrome flag {} is set with user_data_dir", flag));
+            }
+            if UNSAFE_FLAGS.contains(&name) {
+                return Err(format!("chrome flag {} is not allowed", flag));
+            }
+            if !config.fork_flags.iter().any(|f| flag_name(f) == name) {
+                return Err(format!("chrome flag {} is not in fork_flags", flag));
+            }
+        }
+        let options = [
+            ("proxy_server", "--proxy-server", &self.proxy_server),
+            ("user_agent", "--user-agent", &self.user_agent),
+            ("locale", "--lang", &self.locale),
+        ];
+
+        for (option, flag, value) in options {
+            if value.is_some() && !config.fork_flags.iter().any(|f| flag_name(f) == flag) {
+                return Err(format!("{} requires {} in fork_flags", option, flag));
+            }
+        }
+        if let Some([width, height]) = self.window_size {
+            if width == 0 || height == 0 {
+                return Err(format!(
+                    "window_size {}x{} is not a valid size",
+                    width, height
+                ));
+            }
+        }
+
+        let values = [
+            ("proxy_server", &self.proxy_server),
+            ("user_agent", &self.user_agent),
+            ("locale", &self.locale),
+            ("timezone", &self.timezone),
+            ("user_data_dir", &self.user_data_dir),
+            ("profile", &self.profile),
+        ];
+
+        for (name, value) in values {
+            if value.as_ref().is_some_and(|v| v.trim().is_empty()) {
+                return Err(format!("{} cannot be empty", name));
+            }
+        }
+
+        if let Some(dir) = &self.user_data_dir {
+            let base = match &config.user_data_base_dir {
+                Some(base) => Path::new(base),
+                _ => return Err("user_data_dir requires the user_data_base_dir config".into()),
+            };
+
+            let resolved = base.join(dir);
+
+            if !crate::policy::is_within(base, &resolved) || resolved == base {
+                return Err(format!(
+                    "user_data_dir {} must be a subdirectory of {}",
+                    dir,
+                    base.display()
+                ));
+            }
+        }
+
+        if let Some(profile) = &self.profile {
+            if self.user_data_dir.is_some() {
+                return Err("profile and user_data_dir cannot both be set".into());
+            }
+            if profile.starts_with('.')
+                || !profile
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+            {
+                return Err(format!(
+                    "profile {} can only use letters, numbers, -, _, and .",
+                    profile
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The profile directory requested by the options. The `user_data_dir` is relative to the `user_data_base_dir` of the config.
+    pub fn profile_dir(&self, config: &Config) -> Option<PathBuf> {
+        match (&self.user_data_dir, &self.profile) {
+            (Some(dir), _) => {
+                Some(Path::new(config.user_data_base_dir.as_deref().unwrap_or_default()).join(dir))
+            }
+            (_, Some(profile)) => Some(config.profiles_dir().join(profile)),
+            _ => None,
+        }
+    }
+
+    /// Apply the options to the chrome args of the config.
+    pub fn apply(&self, config: &Config, args: Vec<String>) -> Vec<String> {
+        let mut flags = vec![];
+
+        if let Some(proxy_server) = &self.proxy_server {
+            flags.push(format!("--proxy-server={}", proxy_server));
+        }
+        if let Some(user_agent) = &self.user_agent {
+            flags.push(format!("--user-agent={}", user_agent));
+        }
+        if let Some([width, height]) = self.window_size {
+            flags.push(format!("--window-size={},{}", width, height));
+        }
+        if let Some(locale) = &self.locale {
+            flags.push(format!("--lang={}", locale));
+            flags.push(format!("--accept-lang={}", locale));
+        }
+        if let Some(dir) = self.user_data_dir.as_ref().and(self.profile_dir(config)) {
+            flags.push(format!("--user-data-dir={}", dir.display()));
+        }
+
+        // the explicit flags win over the named options.
+        flags.extend(self.flags.iter().cloned());
+
+        let mut args = merge_flags(args, &flags, &[]);
+
+        if let Some(headless) = self.headless {
+            args.retain(|arg| flag_name(arg) != "--headless");
+
+            if !headless.flag().is_empty() {
+                args.push(headless.flag().into());
+            }
+        }
+
+        args
+    }
+
+    /// The env variables of the chrome process.
+    pub fn envs(&self) -> Vec<(&'static str, String)> {
+        let mut envs = vec![];
+
+        if let Some(timezone) = &self.timezone {
+            envs.push(("TZ", timezone.clone()));
+        }
+        if let Some(locale) = &self.locale {
+            envs.push(("LANG", format!("{}.UTF-8", locale.replace('-', "_"))));
+        }
+
+        envs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The config allowing the window size flag and the profiles in `/srv/profiles`.
+    fn config() -> Config {
+        Config {
+            fork_flags: vec!["--window-size".into(), "--renderer-cmd-prefix".into()],
+            user_data_base_dir: Some("/srv/profiles".into()),
+            ..Default::default()
+        }
+    }
+
+    /// The options with the flags.
+    fn with_flags(flags: &[&str]) -> LaunchOptions {
+        LaunchOptions {
+            flags: flags.iter().map(|flag| flag.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    /// The options with the user data dir.
+    fn with_dir(dir: &str) -> LaunchOptions {
+        LaunchOptions {
+            user_data_dir: Some(dir.into()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn flags_need_the_allowlist() {
+        assert!(with_flags(&["--window-size=1024,768"])
+            .validate(&config())
+            .is_ok());
+        assert!(with_flags(&["--window-size=1024,768"])
+            .validate(&Config::default())
+            .is_err());
+        assert!(with_flags(&["--mute-audio"]).validate(&config()).is_err());
+    }
+
+    #[test]
+    fn unsafe_flags_are_rejected() {
+        // allowed in the config for the test, still rejected.
+        assert!(with_flags(&["--renderer-cmd-prefix=/bin/sh -c id"])
+            .validate(&config())
+            .is_err());
+        assert!(with_flags(&["--gpu-launcher=/bin/sh"])
+            .validate(&config())
+            .is_err());
+        assert!(with_flags(&["--user-data-dir=/etc"])
+            .validate(&config())
+            .is_err());
+        assert!(with_flags(&["--remote-debugging-port=1"])
+            .validate(&config())
+            .is_err());
+    }
+
+    #[test]
+    fn user_data_dir_stays_in_the_base() {
+        assert!(with_dir("crawl-1").validate(&config()).is_ok());
+        assert!(with_dir("/srv/profiles/crawl-1")
+            .validate(&config())
+            .is_ok());
+        assert!(with_dir("../etc").validate(&config()).is_err());
+        assert!(with_dir("crawl/../../etc").validate(&config()).is_err());
+        assert!(with_dir("/etc").validate(&config()).is_err());
+        assert!(with_dir("crawl-1").validate(&Config::default()).is_err());
+    }
+
+    #[test]
+    fn user_data_dir_cannot_be_the_base() {
+        assert!(with_dir("/srv/profiles").validate(&config()).is_err());
+        assert!(with_dir("/srv/profiles/").validate(&config()).is_err());
+        assert!(with_dir(".").validate(&config()).is_err());
+        assert!(with_dir("crawl-1/..").validate(&config()).is_err());
+    }
+
+    #[test]
+    fn typed_flags_need_the_allowlist() {
+        let options = [
+            LaunchOptions {
+                proxy_server: Some("http://proxy:8080".into()),
+                ..Default::default()
+            },
+            LaunchOptions {
+                user_agent: Some("crawler".into()),
+                ..Default::default()
+            },
+            LaunchOptions {
+                locale: Some("en-US".into()),
+                ..Default::default()
+            },
+        ];
+        let allowed = Config {
+            fork_flags: vec![
+                "--proxy-server".into(),
+                "--user-agent".into(),
+                "--lang".into(),
+            ],
+            ..Default::default()
+        };
+
+        for options in options {
+            assert!(options.validate(&config()).is_err());
+            assert!(options.validate(&allowed).is_ok());
+        }
+    }
+
+    #[test]
+    fn user_data_dir_resolves_in_the_base() {
+        let args = with_dir("crawl-1").apply(&config(), vec![]);
+
+        assert_eq!(args, ["--user-data-dir=/srv/profiles/crawl-1"]);
+    }
+}
@@ -1,35 +1,58 @@
-use cached::proc_macro::once;
-
+/// Bearer token and signed url authentication.
+pub mod auth;
+/// Load balancing strategies across chrome instances.
+pub mod balancer;
+/// WebSocket aware CDP proxy.
+mod cdp;
 /// Chrome configuration.
 pub mod conf;
+/// Server configuration loading and validation.
+pub mod config;
+/// DevTools HTTP discovery endpoints.
+mod discovery;
+/// Advertised host from the request and forwarded headers.
+mod forwarded;
+/// Instance health probes.
+mod health;
+/// Per instance launch options.
+pub mod launch;
+/// Prometheus metrics.
+pub mod metrics;
 /// Chrome json modifiers.
 mod modify;
+/// CDP method policy of the proxy.
+mod policy;
+/// Chrome instance pool with port allocation.
+pub mod pool;
 /// Proxy forwarder TCP to chrome instances.
 pub mod proxy;
 /// Chrome renderer configuration.
 mod render_conf;
+/// Routing of the browser and target websockets to the owning instance.
+mod routing;
+/// The runtime constructed chrome server.
+pub mod server;
+/// Chrome process supervision.
+mod supervisor;
+/// Fake chrome instances for the tests.
+#[cfg(test)]
+mod testing;
+/// TLS termination of the listeners.
+mod tls;
+/// Chrome process tree resource usage.
+pub mod usage;
 
-use conf::{
-    CACHEABLE, CHROME_ADDRESS, CHROME_ARGS, CHROME_INSTANCES, CHROME_PATH, DEBUG_JSON,
-    DEFAULT_PORT, DEFAULT_PORT_SERVER, ENDPOINT, HOST_NAME, IS_HEALTHY, LAST_CACHE,
-    LIGHTPANDA_ARGS, LIGHT_PANDA, TARGET_REPLACEMENT,
-};
 use core::sync::atomic::Ordering;
 use http_body_util::Full;
 use hyper::{
     body::{Bytes, Incoming},
-    server::conn::http1,
-    service::service_fn,
     Method, Request, Response, StatusCode,
 };
 use hyper_util::rt::TokioIo;
+pub use server::{Server, ServerBuilder, ShutdownHandle};
 use std::convert::Infallible;
-use std::net::SocketAddr;
-use std::process::Command;
-use tokio::{
-    net::{TcpListener, TcpStream},
-    signal,
-};
+use std::sync::OnceLock;
+use tokio::net::TcpStream;
 
 use std::time::Duration;
 use tokio::time::{sleep, timeout};
@@ -46,8 +69,11 @@ const EMPTY_RESPONSE: Bytes = Bytes::from_static(
 }"#,
 );
 
+/// The max size of a request body.
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
 /// Attempt the connection.
-async fn connect_with_retries(address: &str) -> Option<TcpStream> {
+async fn connect_with_retries(server: &Server, address: &str) -> Option<TcpStream> {
     let mut attempts = 0;
     let mut connection_failed = false;
 
@@ -65,8 +91,9 @@ async fn connect_with_retries(address: &str) -> Option<TcpStream> {
                         connection_failed = true;
                     }
                     // empty prevent connections retrying
-                    if attempts >= 10 && CHROME_INSTANCES.is_empty() {
+                    if attempts >= 10 && server.instances.is_empty() {
                         tracing::warn!("ConnectionRefused: {}. Attempt {} of 8", e, attempts);
+                        server.metrics.connect_failures.inc();
                         return None;
                     }
                 }
@@ -78,9 +105,12 @@ async fn connect_with_retries(address: &str) -> Option<TcpStream> {
         }
 
         if attempts >= 20 {
+            server.metrics.connect_failures.inc();
             return None;
         }
 
+        server.metrics.connect_retries.inc();
+
         let rng = rand::random_range(if connection_failed {
             80..=150
         } else {
@@ -91,108 +121,147 @@ async fn connect_with_retries(address: &str) -> Option<TcpStream> {
     }
 }
 
-/// Shutdown the chrome instance by process id.
-#[cfg(target_os = "windows")]
-pub fn shutdown(pid: &u32) {
-    let _ = Command::new("taskkill")
-        .args(["/PID", &pid.to_string(), "/F"])
-        .spawn();
+/// Ask the chrome instance and the child processes to terminate by process id.
+#[cfg(not(unix))]
+pub fn terminate(pid: &u32) -> std::io::Result<()> {
+    taskkill(pid, false)
 }
 
-/// Shutdown the chrome instance by process id.
-#[cfg(not(target_os = "windows"))]
-pub fn shutdown(pid: &u32) {
-    let _ = Command::new("kill").args(["-9", &pid.to_string()]).spawn();
+/// Ask the chrome instance and the child processes to terminate by process id.
+#[cfg(unix)]
+pub fn terminate(pid: &u32) -> std::io::Result<()> {
+    signal(*pid, libc::SIGTERM)
 }
 
-#[cfg(test)]
-/// Arguments to test headless without any extra args. Only applies during 'cargo test'.
-pub fn get_chrome_args_test() -> [&'static str; 6] {
-    *crate::conf::CHROME_ARGS_TEST
+/// Shutdown the chrome instance and the child processes by process id.
+#[cfg(not(unix))]
+pub fn shutdown(pid: &u32) -> std::io::Result<()> {
+    taskkill(pid, true)
 }
 
-#[cfg(not(test))]
-/// Arguments to test headless without any extra args. Only applies during 'cargo test'.
-pub fn get_chrome_args_test() -> [&'static str; crate::conf::PERF_ARGS] {
-    *crate::conf::CHROME_ARGS
+/// Shutdown the chrome instance and the child processes by process id.
+#[cfg(unix)]
+pub fn shutdown(pid: &u32) -> std::io::Result<()> {
+    signal(*pid, libc::SIGKILL)
 }
 
-/// Fork a chrome process.
-pub fn fork(port: Option<u32>) -> String {
-    let id = if !*LIGHT_PANDA {
-        let mut command = Command::new(&*CHROME_PATH);
-
-        let cmd = if *crate::conf::TEST_NO_ARGS {
-            let mut chrome_args = get_chrome_args_test().map(|e| e.to_string());
-            if !CHROME_ADDRESS.is_empty() {
-                chrome_args[0] =
-                    format!("--remote-debugging-address={}", &CHROME_ADDRESS.to_string());
-            }
-            if let Some(port) = port {
-                chrome_args[1] = format!("--remote-debugging-port={}", &port.to_string());
-            }
-            command.args(&chrome_args)
-        } else {
-            let mut chrome_args = CHROME_ARGS.map(|e| e.to_string());
-            if !CHROME_ADDRESS.is_empty() {
-                chrome_args[0] =
-                    format!("--remote-debugging-address={}", &CHROME_ADDRESS.to_string());
-            }
-            if let Some(port) = port {
-                chrome_args[1] = format!("--remote-debugging-port={}", &port.to_string());
-            }
-            command.args(&chrome_args)
-        };
-
-        let id = match cmd.spawn() {
-            Ok(child) => {
-                let cid = child.id();
-                tracing::info!("Chrome PID: {}", cid);
-                cid
-            }
-            Err(e) => {
-                tracing::error!("{} command didn't start {:?}", &*CHROME_PATH, e);
-                0
-            }
-        };
+/// Send the signal to the process group led by the instance or the process if it does not lead a group.
+#[cfg(unix)]
+fn signal(pid: u32, signal: libc::c_int) -> std::io::Result<()> {
+    // pid 0 would signal our own process group.
+    let pid = match libc::pid_t::try_from(pid) {
+        Ok(pid) if pid > 0 => pid,
+        _ => return Err(std::io::ErrorKind::InvalidInput.into()),
+    };
 
-        id
+    // SAFETY: kill only sends a signal and has no memory safety requirements.
+    if unsafe { libc::kill(-pid, signal) } == 0 || unsafe { libc::kill(pid, signal) } == 0 {
+        Ok(())
     } else {
-        let panda_args = LIGHTPANDA_ARGS.map(|e| e.to_string());
-        let mut command = Command::new(&*CHROME_PATH);
+        Err(std::io::Error::last_os_error())
+    }
+}
 
-        let host = panda_args[0].replace("--host=", "");
-        let port = panda_args[1].replace("--port=", "");
+/// Kill the processes left in the process group led by the reaped instance.
+#[cfg(unix)]
+pub(crate) fn kill_process_group(pid: u32) {
+    if let Ok(pid) = libc::pid_t::try_from(pid) {
+        if pid > 0 {
+            // SAFETY: kill only sends a signal and has no memory safety requirements.
+            unsafe { libc::kill(-pid, libc::SIGKILL) };
+        }
+    }
+}
 
-        let id = if let Ok(child) = command
-            .args(["--port", &port])
-            .args(["--host", &host])
-            .spawn()
-        {
-            let cid = child.id();
+/// Kill the processes left in the process group led by the reaped instance.
+#[cfg(not(unix))]
+pub(crate) fn kill_process_group(_pid: u32) {}
 
-            tracing::info!("Chrome PID: {}", cid);
+/// Terminate the process tree with taskkill.
+#[cfg(not(unix))]
+fn taskkill(pid: &u32, force: bool) -> std::io::Result<()> {
+    let pid = pid.to_string();
+    let mut args = vec!["/PID", &pid, "/T"];
 
-            cid
-        } else {
-            tracing::error!("chrome command didn't start");
-            0
-        };
+    if force {
+        args.push("/F");
+    }
 
-        id
-    };
+    let status = std::process::Command::new("taskkill").args(args).status()?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(std::io::Error::other(format!(
+            "taskkill exited with {}",
+            status
+        )))
+    }
+}
+
+/// The error forking a chrome process.
+#[derive(Debug)]
+pub enum ForkError {
+    /// The requested port is already used by the pool.
+    PortInUse(u32),
+    /// The port range has no free ports left.
+    NoPortAvailable,
+    /// The chrome command did not start.
+    Spawn(std::io::Error),
+    /// The launch options are invalid.
+    Invalid(String),
+    /// The profile directory is used by another instance.
+    ProfileInUse(std::path::PathBuf),
+}
+
+impl std::fmt::Display for ForkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ForkError::PortInUse(port) => write!(f, "port {} is already in use", port),
+            ForkError::NoPortAvailable => write!(f, "no remote debugging port available"),
+            ForkError::Spawn(e) => write!(f, "chrome command didn't start: {}", e),
+            ForkError::Invalid(e) => write!(f, "invalid launch options: {}", e),
+            ForkError::ProfileInUse(dir) => {
+                write!(f, "profile {} is used by another instance", dir.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ForkError {}
 
-    CHROME_INSTANCES.insert(id.into());
+/// The server used by `run_main` and the free functions. Built from the process config on first use.
+pub fn default_server() -> &'static Server {
+    static SERVER: OnceLock<Server> = OnceLock::new();
+    SERVER.get_or_init(|| Server::new(conf::config().clone()))
+}
+
+/// Fork a chrome process on the default server. The debugging port is allocated from the pool when not provided. Returns the pid or 0 on failure,
+/// including outside of a tokio runtime.
+pub fn fork(port: Option<u32>) -> String {
+    default_server().fork(port)
+}
 
-    id.to_string()
+/// Fork a chrome process on the default server. The debugging port is allocated from the pool when not provided. The fork fails outside of a
+/// tokio runtime.
+pub fn try_fork(port: Option<u32>) -> Result<pool::Instance, ForkError> {
+    default_server().try_fork(port)
 }
 
 /// Get json endpoint for chrome instance proxying.
-async fn version_handler_bytes_base(endpoint_path: Option<&str>) -> Option<Bytes> {
+async fn version_handler_bytes_base(
+    server: &Server,
+    endpoint_path: Option<&str>,
+    port: u32,
+) -> Option<Bytes> {
     use http_body_util::BodyExt;
 
-    let url = endpoint_path
-        .unwrap_or(&ENDPOINT.as_str())
+    let endpoint = match endpoint_path {
+        Some(endpoint) => endpoint.to_string(),
+        _ => format!("http://127.0.0.1:{}/json/version", port),
+    };
+
+    let url = endpoint
         .parse::<hyper::Uri>()
         .expect("valid chrome endpoint");
 
@@ -213,7 +282,7 @@ async fn version_handler_bytes_base(endpoint_path: Option<&str>) -> Option<Bytes
 
     let address = format!("{}:{}", host, port);
 
-    let resp = if let Some(stream) = connect_with_retries(&address).await {
+    let resp = if let Some(stream) = connect_with_retries(server, &address).await {
         let io = TokioIo::new(stream);
 
         if let Ok((mut client, conn)) = hyper::client::conn::http1::handshake(io).await {
@@ -225,7 +294,11 @@ async fn version_handler_bytes_base(endpoint_path: Option<&str>) -> Option<Bytes
 
             match client.send_request(req).await {
                 Ok(mut resp) => {
-                    IS_HEALTHY.store(true, Ordering::Relaxed);
+                    // the health probes own the health of the instances when enabled.
+                    if server.config.health_interval == 0 {
+                        server.instances.mark_running(port.into());
+                        server.healthy.store(true, Ordering::Relaxed);
+                    }
 
                     let mut bytes_mut = vec![];
 
@@ -237,15 +310,22 @@ async fn version_handler_bytes_base(endpoint_path: Option<&str>) -> Option<Bytes
                         }
                     }
 
-                    if !HOST_NAME.is_empty() {
-                        let body = modify::modify_json_output(bytes_mut.into());
-                        Some(body)
-                    } else {
-                        Some(bytes_mut.into())
+                    if let Ok(version) = serde_json::from_slice::<serde_json::Value>(&bytes_mut) {
+                        if let Some(browser) = version.get("Browser").and_then(|b| b.as_str()) {
+                            server.instances.set_browser(port.into(), browser);
+                        }
                     }
+
+                    // the browser id handed out routes back to the instance.
+                    routing::learn(server, port.into(), &bytes_mut);
+
+                    Some(bytes_mut.into())
                 }
                 _ => {
-                    IS_HEALTHY.store(false, Ordering::Relaxed);
+                    if server.config.health_interval == 0 {
+                        server.instances.mark_healthy(port.into(), false);
+                        server.healthy.store(false, Ordering::Relaxed);
+                    }
                     None
                 }
             }
@@ -259,15 +339,42 @@ async fn version_handler_bytes_base(endpoint_path: Option<&str>) -> Option<Bytes
     resp
 }
 
-/// Get json endpoint for chrome instance proxying.
-#[once(option = true, sync_writes = true, time = 10)]
-async fn version_handler_bytes(endpoint_path: Option<&str>) -> Option<Bytes> {
-    version_handler_bytes_base(endpoint_path).await
+/// Get json endpoint for chrome instance proxying. The response of each instance is cached for ten seconds so the browser id
+/// handed out routes to the instance selected.
+async fn version_handler_bytes(
+    server: &Server,
+    endpoint_path: Option<&str>,
+    port: u32,
+) -> Option<Bytes> {
+    if let Some(cached) = server.version_cache.get(&port) {
+        let (at, body) = cached.value();
+
+        if at.elapsed() < server::TEN_SECONDS {
+            server.metrics.version_cache_hits.inc();
+            return Some(body.clone());
+        }
+    }
+
+    server.metrics.version_cache_misses.inc();
+
+    let body = version_handler_bytes_base(server, endpoint_path, port).await;
+
+    if let Some(body) = &body {
+        // the responses of the instances gone are dropped.
+        server.version_cache.retain(|port, _| {
+            *port == server.config.chrome_port || server.instances.get_by_port(*port).is_some()
+        });
+        server
+            .version_cache
+            .insert(port, (std::time::Instant::now(), body.clone()));
+    }
+
+    body
 }
 
 /// Health check handler
-async fn health_check_handler() -> Result<Response<Full<Bytes>>, Infallible> {
-    if IS_HEALTHY.load(Ordering::Relaxed) {
+async fn health_check_handler(server: &Server) -> Result<Response<Full<Bytes>>, Infallible> {
+    if server.is_healthy() {
         Ok(Response::new(Full::new(Bytes::from("healthy"))))
     } else {
         let mut response = Response::new(Full::new(Bytes::from("unhealthy")));
@@ -277,35 +384,226 @@ async fn health_check_handler() -> Result<Response<Full<Bytes>>, Infallible> {
     }
 }
 
-/// Fork handler.
-async fn fork_handler(port: Option<u32>) -> Result<Response<Full<Bytes>>, Infallible> {
-    let pid = fork(port);
-    let pid = format!("Forked process with pid: {}", pid);
+/// Liveness handler. Unhealthy once every instance failed the probes past the liveness threshold or the crash loop limit emptied the pool.
+async fn live_handler(server: &Server) -> Result<Response<Full<Bytes>>, Infallible> {
+    Ok(probe_response(server.is_live(), "live", "not live"))
+}
+
+/// Readiness handler. Healthy while an instance passes the probes.
+async fn ready_handler(server: &Server) -> Result<Response<Full<Bytes>>, Infallible> {
+    Ok(probe_response(server.is_ready(), "ready", "not ready"))
+}
+
+/// The probe response with 503 when unhealthy.
+fn probe_response(healthy: bool, ok: &'static str, err: &'static str) -> Response<Full<Bytes>> {
+    if healthy {
+        Response::new(Full::new(Bytes::from(ok)))
+    } else {
+        let mut response = Response::new(Full::new(Bytes::from(err)));
+        *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+        response
+    }
+}
+
+/// Unauthorized response asking for a bearer token.
+pub(crate) fn unauthorized() -> Response<Full<Bytes>> {
+    let mut response = json_error(StatusCode::UNAUTHORIZED, "Unauthorized");
+
+    response.headers_mut().insert(
+        hyper::header::WWW_AUTHENTICATE,
+        hyper::header::HeaderValue::from_static("Bearer"),
+    );
+
+    response
+}
+
+/// Json response with the status.
+fn json_response<T: serde::Serialize>(status: StatusCode, body: &T) -> Response<Full<Bytes>> {
+    let body = serde_json::to_vec(body).unwrap_or_default();
+    let mut resp = Response::new(Full::new(Bytes::from(body)));
+
+    *resp.status_mut() = status;
+    resp.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("application/json"),
+    );
+
+    resp
+}
+
+/// Json error response with the status.
+fn json_error(status: StatusCode, message: &str) -> Response<Full<Bytes>> {
+    json_response(status, &serde_json::json!({ "error": message }))
+}
+
+/// The browser version of the instance. The version is requested once from the instance and kept in the pool without changing the health of the instance.
+async fn instance_browser(server: &Server, instance: &pool::Instance) -> Option<String> {
+    if instance.browser.is_some() {
+        return instance.browser.clone();
+    }
+
+    let reply = timeout(
+        Duration::from_secs(2),
+        discovery::fetch(server, instance.port, &Method::GET, "/json/version"),
+    )
+    .await
+    .ok()
+    .flatten()?;
+
+    let version = serde_json::from_slice::<serde_json::Value>(&reply.body).ok()?;
+    let browser = version.get("Browser")?.as_str()?;
+
+    server.instances.set_browser(instance.port, browser);
+
+    Some(browser.to_string())
+}
+
+/// Metrics handler.
+async fn metrics_handler(server: &Server) -> Result<Response<Full<Bytes>>, Infallible> {
+    let mut resp = Response::new(Full::new(Bytes::from(
+        server
+            .metrics
+            .render(&server.instances, &current_usage(server).await),
+    )));
+
+    resp.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        hyper::header::HeaderValue::from_static("text/plain; version=0.0.4"),
+    );
+
+    Ok(resp)
+}
+
+/// The resource usage of the instances. Sampled on request without the usage interval.
+async fn current_usage(server: &Server) -> Vec<usage::InstanceUsage> {
+    if server.config.usage_interval == 0 {
+        server.sample_usage().await
+    } else {
+        server.usage()
+    }
+}
+
+/// Usage handler.
+async fn usage_handler(server: &Server) -> Result<Response<Full<Bytes>>, Infallible> {
+    Ok(json_response(StatusCode::OK, &current_usage(server).await))
+}
 
-    Ok(Response::new(Full::new(Bytes::from(pid))))
+/// Instances handler.
+async fn instances_handler(server: &Server) -> Result<Response<Full<Bytes>>, Infallible> {
+    let instances = server.instances.instances();
+    let browsers = futures_util::future::join_all(
+        instances
+            .iter()
+            .map(|instance| instance_browser(server, instance)),
+    )
+    .await;
+
+    let instances: Vec<_> = instances
+        .iter()
+        .zip(browsers)
+        .map(|(instance, browser)| instance.info(browser))
+        .collect();
+
+    Ok(json_response(StatusCode::OK, &instances))
+}
+
+/// Instance handler.
+async fn instance_handler(server: &Server, pid: &str) -> Result<Response<Full<Bytes>>, Infallible> {
+    let pid = match pid.parse::<u32>() {
+        Ok(pid) => pid,
+        _ => return Ok(json_error(StatusCode::BAD_REQUEST, "Invalid pid argument")),
+    };
+
+    match server.instances.get(pid) {
+        Some(instance) => {
+            let browser = instance_browser(server, &instance).await;
+            Ok(json_response(StatusCode::OK, &instance.info(browser)))
+        }
+        _ => Ok(json_error(StatusCode::NOT_FOUND, "Instance not found")),
+    }
+}
+
+/// Read the json body of the request. An empty body is the default value.
+async fn read_json<T: serde::de::DeserializeOwned + Default>(body: Incoming) -> Result<T, String> {
+    use http_body_util::BodyExt;
+
+    let body = http_body_util::Limited::new(body, MAX_BODY_SIZE)
+        .collect()
+        .await
+        .map_err(|e| e.to_string())?
+        .to_bytes();
+
+    if body.iter().all(u8::is_ascii_whitespace) {
+        Ok(T::default())
+    } else {
+        serde_json::from_slice(&body).map_err(|e| e.to_string())
+    }
+}
+
+/// Fork handler. The body can set the launch options of the instance.
+async fn fork_handler(
+    server: &Server,
+    port: Option<u32>,
+    body: Incoming,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let options = match read_json::<launch::LaunchOptions>(body).await {
+        Ok(options) => options,
+        Err(e) => {
+            return Ok(json_error(
+                StatusCode::BAD_REQUEST,
+                &format!("Invalid launch options: {}", e),
+            ))
+        }
+    };
+
+    match server.try_fork_with(port, options) {
+        Ok(instance) => Ok(json_response(StatusCode::OK, &instance.info(None))),
+        Err(e) => {
+            tracing::error!("Failed to fork chrome: {}", e);
+
+            let status = match e {
+                ForkError::PortInUse(_) | ForkError::ProfileInUse(_) => StatusCode::CONFLICT,
+                ForkError::NoPortAvailable => StatusCode::SERVICE_UNAVAILABLE,
+                ForkError::Spawn(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                ForkError::Invalid(_) => StatusCode::BAD_REQUEST,
+            };
+
+            Ok(json_error(status, &e.to_string()))
+        }
+    }
 }
 
 /// Json version handler.
 async fn json_version_handler(
+    server: &Server,
     endpoint_path: Option<&str>,
+    headers: &hyper::HeaderMap,
+    peer: std::net::IpAddr,
 ) -> Result<Response<Full<Bytes>>, Infallible> {
+    let start = std::time::Instant::now();
     let mut attempts = 0;
     let mut body: Option<Bytes> = None;
     let mut checked_empty = false;
 
     // check if the instances are alive.
-    while attempts < 10 && body.is_none() && !CHROME_INSTANCES.is_empty() {
-        body = if CACHEABLE.load(Ordering::Relaxed) {
-            version_handler_bytes(endpoint_path).await
+    while attempts < 10 && body.is_none() && !server.instances.is_empty() {
+        let port = server
+            .instances
+            .next_port()
+            .unwrap_or(server.config.chrome_port);
+
+        body = if server.cacheable.load(Ordering::Relaxed) {
+            version_handler_bytes(server, endpoint_path, port).await
         } else {
-            version_handler_bytes_base(endpoint_path).await
+            server.metrics.version_cache_misses.inc();
+            version_handler_bytes_base(server, endpoint_path, port).await
         };
 
         if body.is_none() {
             // check the first instance.
             if !checked_empty {
                 checked_empty = true;
-                if CHROME_INSTANCES.is_empty() {
+                if server.instances.is_empty() {
                     break;
                 }
             }
@@ -318,9 +616,12 @@ async fn json_version_handler(
     }
 
     let empty = body.is_none();
-    let body = body.unwrap_or_else(|| EMPTY_RESPONSE);
+    let body = match (body, server.advertised(headers, peer)) {
+        (Some(body), Some(advertised)) => modify::modify_json_output(body, &advertised),
+        (body, _) => body.unwrap_or(EMPTY_RESPONSE),
+    };
 
-    if *DEBUG_JSON {
+    if server.config.debug_json {
         tracing::info!("{:?}", body);
     }
 
@@ -335,21 +636,69 @@ async fn json_version_handler(
         *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
     }
 
+    server.metrics.version_latency.observe(start.elapsed());
+
     Ok(resp)
 }
 
-/// Shutdown all the chrome instances launched.
+/// Shutdown all the chrome instances launched by the default server.
 pub async fn shutdown_instances() {
-    for pid in CHROME_INSTANCES.iter() {
-        shutdown(&pid);
+    default_server().shutdown_instances().await
+}
+
+/// Replace the instance of the default server with a new one on a fresh port. The old instance stops receiving new connections and is shutdown once the active connections close or the drain timeout passes.
+/// Returns false if the instance is unknown or already draining.
+pub fn drain_and_replace(pid: u32) -> bool {
+    default_server().drain_and_replace(pid)
+}
+
+/// The outcome of shutting down an instance.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ShutdownReport {
+    /// The process id.
+    pub pid: u32,
+    /// Did the process exit?
+    pub exited: bool,
+    /// Was the process killed after the grace period?
+    pub killed: bool,
+    /// The exit code if the process exited normally.
+    pub code: Option<i32>,
+    /// The signal that terminated the process.
+    pub signal: Option<i32>,
+}
+
+/// Shutdown the instance of the default server by process id. The process is asked to terminate and killed if still running after the grace period.
+/// Returns None if the process id is not an instance of the pool.
+pub async fn shutdown_instance(pid: u32) -> Option<ShutdownReport> {
+    default_server().shutdown_instance(pid).await
+}
+
+/// Shutdown instance handler.
+async fn shutdown_instance_handler(
+    server: &Server,
+    pid: &str,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let pid = match pid.parse::<u32>() {
+        Ok(pid) => pid,
+        _ => return Ok(json_error(StatusCode::BAD_REQUEST, "Invalid pid argument")),
+    };
+
+    match server.shutdown_instance(pid).await {
+        Some(report) => {
+            let status = if report.exited {
+                StatusCode::OK
+            } else {
+                StatusCode::INTERNAL_SERVER_ERROR
+            };
+            Ok(json_response(status, &report))
+        }
+        _ => Ok(json_error(StatusCode::NOT_FOUND, "Instance not found")),
     }
-    CHROME_INSTANCES.clear();
-    CACHEABLE.store(false, std::sync::atomic::Ordering::Relaxed);
 }
 
 /// Shutdown handler.
-async fn shutdown_handler() -> Result<Response<Full<Bytes>>, Infallible> {
-    shutdown_instances().await;
+async fn shutdown_handler(server: &Server) -> Result<Response<Full<Bytes>>, Infallible> {
+    server.shutdown_instances().await;
 
     Ok(Response::new(Full::new(Bytes::from(
         "Shutdown successful.",
@@ -357,29 +706,91 @@ async fn shutdown_handler() -> Result<Response<Full<Bytes>>, Infallible> {
 }
 
 /// Request handler.
-async fn request_handler(req: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
-    match (req.method(), req.uri().path()) {
-        (&Method::GET, "/health") => health_check_handler().await,
-        (&Method::GET, "/") => health_check_handler().await,
-        (&Method::POST, "/fork") => fork_handler(None).await,
+async fn request_handler(
+    server: Server,
+    peer: std::net::IpAddr,
+    req: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let server = &server;
+
+    let (parts, body) = req.into_parts();
+
+    // the health probes stay open for the orchestrators.
+    let probe = matches!(
+        parts.uri.path(),
+        "/" | "/health" | "/health/live" | "/health/ready"
+    );
+    let scope = auth::Scope::of(&parts.method, parts.uri.path());
+
+    let grant = if probe {
+        Some(auth::Grant::Open)
+    } else {
+        auth::authorize(
+            &server.config,
+            scope,
+            &parts.method,
+            &parts.uri,
+            &parts.headers,
+        )
+    };
+
+    let grant = match grant {
+        Some(grant) => grant,
+        _ => {
+            server.metrics.auth_rejected.inc();
+            return Ok(unauthorized());
+        }
+    };
+
+    match (&parts.method, parts.uri.path()) {
+        (&Method::GET, "/health") => health_check_handler(server).await,
+        (&Method::GET, "/") => health_check_handler(server).await,
+        (&Method::GET, "/health/live") => live_handler(server).await,
+        (&Method::GET, "/health/ready") => ready_handler(server).await,
+        (&Method::POST, "/fork") => fork_handler(server, None, body).await,
         (&Method::POST, path) if path.starts_with("/fork/") => {
             if let Some(port) = path.split('/').nth(2) {
                 if let Ok(port) = port.parse::<u32>() {
-                    fork_handler(Some(port)).await
+                    fork_handler(server, Some(port), body).await
                 } else {
-                    let message = Response::new(Full::new(Bytes::from("Invalid port argument")));
-
-                    Ok(message)
+                    Ok(json_error(StatusCode::BAD_REQUEST, "Invalid port argument"))
                 }
             } else {
-                let message = Response::new(Full::new(Bytes::from("Invalid path")));
-
-                Ok(message)
+                Ok(json_error(StatusCode::BAD_REQUEST, "Invalid path"))
             }
         }
+        (&Method::GET, "/metrics") => metrics_handler(server).await,
+        (&Method::GET, "/usage") => usage_handler(server).await,
+        (&Method::GET, "/instances") => instances_handler(server).await,
+        (&Method::GET, path) if path.starts_with("/instances/") => {
+            instance_handler(server, path.trim_start_matches("/instances/")).await
+        }
         // we only care about the main /json/version for 9223 for the proxy forwarder.
-        (&Method::GET, "/json/version") => json_version_handler(None).await,
-        (&Method::POST, "/shutdown") => shutdown_handler().await,
+        (&Method::GET, "/json/version") => {
+            json_version_handler(server, None, &parts.headers, peer).await
+        }
+        (&Method::GET, "/json" | "/json/list") => {
+            discovery::list_handler(server, server.advertised(&parts.headers, peer)).await
+        }
+        (&Method::GET | &Method::PUT, "/json/new") => {
+            discovery::new_handler(
+                server,
+                &parts.method,
+                &grant.forwarded_path(&parts.uri),
+                server.advertised(&parts.headers, peer),
+            )
+            .await
+        }
+        (&Method::GET, "/json/protocol") => discovery::protocol_handler(server).await,
+        (&Method::GET | &Method::PUT, path)
+            if path.starts_with("/json/activate/") || path.starts_with("/json/close/") =>
+        {
+            discovery::target_handler(server, &parts.method, path).await
+        }
+        (&Method::POST, "/shutdown") => shutdown_handler(server).await,
+        (&Method::POST, path) if path.starts_with("/shutdown/") => {
+            shutdown_instance_handler(server, path.trim_start_matches("/shutdown/")).await
+        }
         _ => {
             let mut resp = Response::new(Full::new(Bytes::from("Not Found")));
 
@@ -390,68 +801,250 @@ async fn request_handler(req: Request<Incoming>) -> Result<Response<Full<Bytes>>
     }
 }
 
-/// Launch chrome, start the server, and proxy for management.
+/// Launch chrome, start the server, and proxy for management with the default server. The config loads from the config file and env variables unless set with `conf::set_config`.
 pub async fn run_main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let auto_start = std::env::args().nth(3).unwrap_or_else(|| {
-        if std::env::var("CHROME_INIT").unwrap_or("true".into()) == "true" {
-            "init".into()
-        } else {
-            "ignore".into()
+    // surface config errors instead of panicking on first use.
+    conf::try_config()?;
+    default_server().run().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::testing::FakeChrome;
+    use http_body_util::BodyExt;
+
+    #[tokio::test]
+    async fn version_requests_write_the_health_without_probes() {
+        let chrome = FakeChrome::start("a").await;
+        let server = Server::new(Config {
+            chrome_port: chrome.port,
+            health_interval: 0,
+            ..Default::default()
+        });
+
+        server.healthy.store(false, Ordering::Relaxed);
+        assert!(version_handler_bytes_base(&server, None, chrome.port)
+            .await
+            .is_some());
+        assert!(server.is_healthy());
+    }
+
+    #[tokio::test]
+    async fn version_responses_are_cached_per_instance() {
+        let (a, b) = (FakeChrome::start("a").await, FakeChrome::start("b").await);
+        let server = Server::new(Config {
+            load_balancer: balancer::Strategy::RoundRobin,
+            ..Default::default()
+        });
+
+        for (pid, chrome) in [&a, &b].into_iter().enumerate() {
+            server.instances.insert(
+                pid as u32 + 1,
+                chrome.port,
+                Default::default(),
+                Default::default(),
+                None,
+            );
+            server.instances.mark_running(chrome.port);
+        }
+
+        let mut urls = vec![];
+
+        for _ in 0..4 {
+            let response =
+                json_version_handler(&server, None, &Default::default(), [127, 0, 0, 1].into())
+                    .await
+                    .unwrap();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            let version: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            urls.push(
+                version["webSocketDebuggerUrl"]
+                    .as_str()
+                    .unwrap()
+                    .to_string(),
+            );
         }
-    });
 
-    if auto_start == "init" {
-        fork(Some(*DEFAULT_PORT));
+        assert_eq!(urls[0], urls[2]);
+        assert_eq!(urls[1], urls[3]);
+        assert_ne!(urls[0], urls[1]);
+        assert_eq!(a.requests(), ["GET /json/version"]);
+        assert_eq!(b.requests(), ["GET /json/version"]);
+        assert_eq!(server.metrics.version_cache_hits.get(), 2);
+
+        for chrome in [&a, &b] {
+            let path = format!("/devtools/browser/{}", chrome.browser_id());
+            let lease = routing::acquire(&server, &path).await.unwrap();
+            assert_eq!(lease.port, chrome.port);
+        }
     }
 
-    let addr = SocketAddr::new(
-        std::net::IpAddr::V4(std::net::Ipv4Addr::new(0, 0, 0, 0)),
-        *DEFAULT_PORT_SERVER,
-    );
+    /// The status and json body of the response.
+    async fn json_body(response: Response<Full<Bytes>>) -> (StatusCode, serde_json::Value) {
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn instances_list_the_pool() {
+        let chrome = FakeChrome::start("a").await;
+        let server = Server::new(Config::default());
+
+        server
+            .instances
+            .insert(7, chrome.port, Default::default(), Default::default(), None);
+        server.instances.mark_running(chrome.port);
+        let _lease = server.instances.acquire().unwrap();
+
+        let (status, body) = json_body(instances_handler(&server).await.unwrap()).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let instance = &body.as_array().unwrap()[0];
+        assert_eq!(instance["pid"], 7);
+        assert_eq!(instance["port"], chrome.port);
+        assert_eq!(instance["state"], "running");
+        assert_eq!(instance["restarts"], 0);
+        assert_eq!(instance["active_connections"], 1);
+        assert_eq!(instance["browser"], "a");
+        assert!(instance["started_at"].as_u64().unwrap() > 0);
+        assert!(instance["uptime"].is_u64());
+
+        let (status, body) = json_body(instance_handler(&server, "7").await.unwrap()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(&body, instance);
+    }
+
+    #[tokio::test]
+    async fn unknown_instances_are_not_found() {
+        let server = Server::new(Config::default());
+
+        let (status, body) = json_body(instance_handler(&server, "7").await.unwrap()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"], "Instance not found");
+
+        let (status, _) = json_body(instance_handler(&server, "abc").await.unwrap()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, body) = json_body(instances_handler(&server).await.unwrap()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, serde_json::json!([]));
+    }
+
+    /// The server launching the script as chrome.
+    #[cfg(unix)]
+    fn scripted(body: &str, config: Config) -> Server {
+        Server::new(Config {
+            chrome_path: Some(crate::testing::script(body)),
+            health_interval: 0,
+            ..config
+        })
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn shutdown_instance_terminates_the_process() {
+        let server = scripted("exec sleep 30", Config::default());
+        let instance = server.try_fork(None).unwrap();
 
-    let listener = TcpListener::bind(addr).await.expect("connection");
-
-    let make_svc = async move {
-        let builder_options = std::sync::Arc::new(
-            http1::Builder::new()
-                .preserve_header_case(true)
-                .title_case_headers(true)
-                .header_read_timeout(None)
-                .half_close(true)
-                .auto_date_header(false)
-                .to_owned(),
+        let (status, report) = json_body(
+            shutdown_instance_handler(&server, &instance.pid.to_string())
+                .await
+                .unwrap(),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(report["pid"], instance.pid);
+        assert_eq!(report["exited"], true);
+        assert_eq!(report["killed"], false);
+        assert_eq!(report["signal"], libc::SIGTERM);
+        assert!(server.instances.is_empty());
+        assert_eq!(server.metrics.crashes.get(), 0);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn shutdown_instance_kills_after_the_grace_period() {
+        let server = scripted(
+            "trap '' TERM\nwhile :; do sleep 1; done",
+            Config {
+                shutdown_grace: 1,
+                ..Default::default()
+            },
         );
+        let instance = server.try_fork(None).unwrap();
+        // the shell ignores the signal once the trap ran.
+        tokio::time::sleep(Duration::from_millis(200)).await;
 
-        loop {
-            if let Ok((tcp, _)) = listener.accept().await {
-                let builder_options = builder_options.clone();
-
-                tokio::task::spawn(async move {
-                    let io = TokioIo::new(tcp);
-                    if let Err(err) = builder_options
-                        .serve_connection(io, service_fn(request_handler))
-                        .await
-                    {
-                        eprintln!("Error serving connection: {:?}", err);
-                    }
-                });
-            }
-        }
-    };
+        let start = std::time::Instant::now();
+        let report = server.shutdown_instance(instance.pid).await.unwrap();
 
-    println!(
-        "Chrome server running on {}:{}",
-        if CHROME_ADDRESS.is_empty() {
-            "localhost"
-        } else {
-            &CHROME_ADDRESS
-        },
-        DEFAULT_PORT_SERVER.to_string()
-    );
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert!(report.exited);
+        assert!(report.killed);
+        assert_eq!(report.signal, Some(libc::SIGKILL));
+    }
+
+    #[tokio::test]
+    async fn shutdown_instance_rejects_unknown_pids() {
+        let server = Server::new(Config::default());
+
+        let (status, _) = json_body(shutdown_instance_handler(&server, "7").await.unwrap()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, _) = json_body(shutdown_instance_handler(&server, "abc").await.unwrap()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
 
-    tokio::select! {
-        _ = make_svc => Ok(()),
-        _ = crate::proxy::proxy::run_proxy() =>  Ok(()),
-        _ = signal::ctrl_c() => Ok(()),
+    /// A sleep leading its own process group with a sleep child.
+    #[cfg(unix)]
+    fn process_group() -> std::process::Child {
+        use std::os::unix::process::CommandExt;
+
+        std::process::Command::new("sh")
+            .args(["-c", "sleep 30 & wait"])
+            .process_group(0)
+            .spawn()
+            .unwrap()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn signals_reach_the_process_group() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let mut child = process_group();
+        terminate(&child.id()).unwrap();
+        assert_eq!(child.wait().unwrap().signal(), Some(libc::SIGTERM));
+
+        let mut child = process_group();
+        shutdown(&child.id()).unwrap();
+        assert_eq!(child.wait().unwrap().signal(), Some(libc::SIGKILL));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn signals_reject_invalid_pids() {
+        assert_eq!(
+            terminate(&0).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            shutdown(&u32::MAX).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+
+        assert_eq!(
+            terminate(&pid).unwrap_err().raw_os_error(),
+            Some(libc::ESRCH)
+        );
     }
 }
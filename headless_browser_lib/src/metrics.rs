@@ -0,0 +1,525 @@
+use crate::pool::{InstanceState, Pool};
+use crate::usage::InstanceUsage;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// The metric name prefix.
+const PREFIX: &str = "headless_browser";
+
+/// The latency buckets in seconds.
+const LATENCY_BUCKETS: [f64; 10] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0];
+
+/// A monotonic counter.
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    /// Increment the counter.
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    /// Add to the counter.
+    pub fn add(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// The counter value.
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A histogram of durations with fixed buckets.
+#[derive(Debug)]
+pub struct Histogram {
+    /// The bucket upper bounds in seconds.
+    buckets: &'static [f64],
+    /// The observations per bucket. Not cumulative.
+    counts: Vec<AtomicU64>,
+    /// The sum of the observations in microseconds.
+    sum: AtomicU64,
+    /// The amount of observations.
+    count: AtomicU64,
+}
+
+impl Histogram {
+    /// A new histogram with the bucket upper bounds in seconds.
+    pub fn new(buckets: &'static [f64]) -> Self {
+        Self {
+            buckets,
+            counts: buckets.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    /// Record the duration.
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+
+        if let Some(index) = self.buckets.iter().position(|le| seconds <= *le) {
+            self.counts[index].fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.sum.fetch_add(
+            duration.as_micros().try_into().unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Write the histogram samples.
+    fn render(&self, out: &mut String, name: &str) {
+        let mut cumulative = 0;
+
+        for (le, count) in self.buckets.iter().zip(&self.counts) {
+            cumulative += count.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, cumulative);
+        }
+
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+/// The server metrics.
+#[derive(Debug)]
+pub struct Metrics {
+    /// The instances forked.
+    pub forks: Counter,
+    /// The crashed instances respawned.
+    pub restarts: Counter,
+    /// The instances that exited without being shutdown.
+    pub crashes: Counter,
+    /// The proxied connections accepted.
+    pub connections: Counter,
+    /// The bytes proxied from the clients to chrome.
+    pub bytes_to_chrome: Counter,
+    /// The bytes proxied from chrome to the clients.
+    pub bytes_to_client: Counter,
+    /// The instances recycled for memory.
+    pub recycles_memory: Counter,
+    /// The instances recycled for sessions.
+    pub recycles_sessions: Counter,
+    /// The instances recycled for uptime.
+    pub recycles_uptime: Counter,
+    /// The CDP sessions proxied.
+    pub cdp_sessions: Counter,
+    /// The CDP messages proxied from the clients to chrome.
+    pub cdp_messages_to_chrome: Counter,
+    /// The CDP messages proxied from chrome to the clients.
+    pub cdp_messages_to_client: Counter,
+    /// The CDP commands rejected by the policy.
+    pub cdp_denied: Counter,
+    /// The requests rejected without valid credentials.
+    pub auth_rejected: Counter,
+    /// The browser and target connections routed to the owning instance.
+    pub routes_owner: Counter,
+    /// The browser and target connections balanced without a known owner.
+    pub routes_balanced: Counter,
+    /// The connection attempts to chrome retried.
+    pub connect_retries: Counter,
+    /// The connections to chrome that failed after the retries.
+    pub connect_failures: Counter,
+    /// The /json/version responses served from the cache.
+    pub version_cache_hits: Counter,
+    /// The /json/version responses requested from chrome.
+    pub version_cache_misses: Counter,
+    /// The /json/version latency.
+    pub version_latency: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            forks: Counter::default(),
+            restarts: Counter::default(),
+            crashes: Counter::default(),
+            connections: Counter::default(),
+            bytes_to_chrome: Counter::default(),
+            bytes_to_client: Counter::default(),
+            recycles_memory: Counter::default(),
+            recycles_sessions: Counter::default(),
+            recycles_uptime: Counter::default(),
+            cdp_sessions: Counter::default(),
+            cdp_messages_to_chrome: Counter::default(),
+            cdp_messages_to_client: Counter::default(),
+            cdp_denied: Counter::default(),
+            auth_rejected: Counter::default(),
+            routes_owner: Counter::default(),
+            routes_balanced: Counter::default(),
+            connect_retries: Counter::default(),
+            connect_failures: Counter::default(),
+            version_cache_hits: Counter::default(),
+            version_cache_misses: Counter::default(),
+            version_latency: Histogram::new(&LATENCY_BUCKETS),
+        }
+    }
+}
+
+impl Metrics {
+    /// Render the metrics in the prometheus text format.
+    pub fn render(&self, instances: &Pool, usage: &[InstanceUsage]) -> String {
+        let mut out = String::new();
+        let snapshot = instances.instances();
+
+        header(
+            &mut out,
+            "instances",
+            "gauge",
+            "The chrome instances by state.",
+        );
+        for (state, label) in [
+            (InstanceState::Starting, "starting"),
+            (InstanceState::Running, "running"),
+            (InstanceState::Draining, "draining"),
+        ] {
+            let count = snapshot.iter().filter(|i| i.state == state).count();
+            let _ = writeln!(out, "{}_instances{{state=\"{}\"}} {}", PREFIX, label, count);
+        }
+
+        counter(
+            &mut out,
+            "forks_total",
+            "The instances forked.",
+            &self.forks,
+        );
+        counter(
+            &mut out,
+            "restarts_total",
+            "The crashed instances respawned.",
+            &self.restarts,
+        );
+        counter(
+            &mut out,
+            "crashes_total",
+            "The instances that exited without being shutdown.",
+            &self.crashes,
+        );
+
+        header(
+            &mut out,
+            "recycles_total",
+            "counter",
+            "The instances recycled by reason.",
+        );
+        for (reason, count) in [
+            ("memory", &self.recycles_memory),
+            ("sessions", &self.recycles_sessions),
+            ("uptime", &self.recycles_uptime),
+        ] {
+            let _ = writeln!(
+                out,
+                "{}_recycles_total{{reason=\"{}\"}} {}",
+                PREFIX,
+                reason,
+                count.get()
+            );
+        }
+
+        let active: usize = snapshot.iter().map(|i| i.active_connections()).sum();
+
+        header(
+            &mut out,
+            "proxy_connections_active",
+            "gauge",
+            "The active proxied connections.",
+        );
+        let _ = writeln!(out, "{}_proxy_connections_active {}", PREFIX, active);
+
+        counter(
+            &mut out,
+            "proxy_connections_total",
+            "The proxied connections accepted.",
+            &self.connections,
+        );
+
+        header(
+            &mut out,
+            "proxy_bytes_total",
+            "counter",
+            "The bytes proxied by direction.",
+        );
+        for (direction, bytes) in [
+            ("to_chrome", &self.bytes_to_chrome),
+            ("to_client", &self.bytes_to_client),
+        ] {
+            let _ = writeln!(
+                out,
+                "{}_proxy_bytes_total{{direction=\"{}\"}} {}",
+                PREFIX,
+                direction,
+                bytes.get()
+            );
+        }
+
+        counter(
+            &mut out,
+            "cdp_sessions_total",
+            "The CDP sessions proxied.",
+            &self.cdp_sessions,
+        );
+
+        header(
+            &mut out,
+            "cdp_messages_total",
+            "counter",
+            "The CDP messages proxied by direction.",
+        );
+        for (direction, messages) in [
+            ("to_chrome", &self.cdp_messages_to_chrome),
+            ("to_client", &self.cdp_messages_to_client),
+        ] {
+            let _ = writeln!(
+                out,
+                "{}_cdp_messages_total{{direction=\"{}\"}} {}",
+                PREFIX,
+                direction,
+                messages.get()
+            );
+        }
+
+        counter(
+            &mut out,
+            "cdp_denied_total",
+            "The CDP commands rejected by the policy.",
+            &self.cdp_denied,
+        );
+
+        counter(
+            &mut out,
+            "auth_rejected_total",
+            "The requests rejected without valid credentials.",
+            &self.auth_rejected,
+        );
+
+        header(
+            &mut out,
+            "target_routes_total",
+            "counter",
+            "The browser and target connections by route.",
+        );
+        for (route, count) in [
+            ("owner", &self.routes_owner),
+            ("balanced", &self.routes_balanced),
+        ] {
+            let _ = writeln!(
+                out,
+                "{}_target_routes_total{{route=\"{}\"}} {}",
+                PREFIX,
+                route,
+                count.get()
+            );
+        }
+
+        counter(
+            &mut out,
+            "connect_retries_total",
+            "The connection attempts to chrome retried.",
+            &self.connect_retries,
+        );
+        counter(
+            &mut out,
+            "connect_failures_total",
+            "The connections to chrome that failed after the retries.",
+            &self.connect_failures,
+        );
+
+        header(
+            &mut out,
+            "json_version_cache_total",
+            "counter",
+            "The /json/version responses by cache result.",
+        );
+        for (result, count) in [
+            ("hit", &self.version_cache_hits),
+            ("miss", &self.version_cache_misses),
+        ] {
+            let _ = writeln!(
+                out,
+                "{}_json_version_cache_total{{result=\"{}\"}} {}",
+                PREFIX,
+                result,
+                count.get()
+            );
+        }
+
+        header(
+            &mut out,
+            "json_version_duration_seconds",
+            "histogram",
+            "The /json/version latency.",
+        );
+        self.version_latency.render(
+            &mut out,
+            &format!("{}_json_version_duration_seconds", PREFIX),
+        );
+
+        header(
+            &mut out,
+            "instance_cpu_percent",
+            "gauge",
+            "The cpu usage of the instance process tree in percent of a core.",
+        );
+        for u in usage {
+            let _ = writeln!(
+                out,
+                "{}_instance_cpu_percent{{pid=\"{}\",port=\"{}\"}} {}",
+                PREFIX, u.pid, u.port, u.cpu_percent
+            );
+        }
+
+        header(
+            &mut out,
+            "instance_memory_bytes",
+            "gauge",
+            "The resident memory of the instance process tree.",
+        );
+        for u in usage {
+            let _ = writeln!(
+                out,
+                "{}_instance_memory_bytes{{pid=\"{}\",port=\"{}\"}} {}",
+                PREFIX, u.pid, u.port, u.memory_bytes
+            );
+        }
+
+        header(
+            &mut out,
+            "instance_open_fds",
+            "gauge",
+            "The open file descriptors of the instance process tree.",
+        );
+        for u in usage {
+            if let Some(fds) = u.open_fds {
+                let _ = writeln!(
+                    out,
+                    "{}_instance_open_fds{{pid=\"{}\",port=\"{}\"}} {}",
+                    PREFIX, u.pid, u.port, fds
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "instance_processes",
+            "gauge",
+            "The processes in the instance process tree.",
+        );
+        for u in usage {
+            let _ = writeln!(
+                out,
+                "{}_instance_processes{{pid=\"{}\",port=\"{}\"}} {}",
+                PREFIX, u.pid, u.port, u.processes
+            );
+        }
+
+        out
+    }
+}
+
+/// Write the help and type lines of the metric.
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {}_{} {}", PREFIX, name, help);
+    let _ = writeln!(out, "# TYPE {}_{} {}", PREFIX, name, kind);
+}
+
+/// Write the counter.
+fn counter(out: &mut String, name: &str, help: &str, counter: &Counter) {
+    header(out, name, "counter", help);
+    let _ = writeln!(out, "{}_{} {}", PREFIX, name, counter.get());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balancer::Strategy;
+    use crate::pool::Restarts;
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let histogram = Histogram::new(&LATENCY_BUCKETS);
+        histogram.observe(Duration::from_micros(500));
+        histogram.observe(Duration::from_millis(20));
+        histogram.observe(Duration::from_secs(10));
+
+        let mut out = String::new();
+        histogram.render(&mut out, "latency");
+
+        assert_eq!(
+            out,
+            "latency_bucket{le=\"0.001\"} 1\n\
+             latency_bucket{le=\"0.005\"} 1\n\
+             latency_bucket{le=\"0.01\"} 1\n\
+             latency_bucket{le=\"0.025\"} 2\n\
+             latency_bucket{le=\"0.05\"} 2\n\
+             latency_bucket{le=\"0.1\"} 2\n\
+             latency_bucket{le=\"0.25\"} 2\n\
+             latency_bucket{le=\"0.5\"} 2\n\
+             latency_bucket{le=\"1\"} 2\n\
+             latency_bucket{le=\"5\"} 2\n\
+             latency_bucket{le=\"+Inf\"} 3\n\
+             latency_sum 10.0205\n\
+             latency_count 3\n"
+        );
+    }
+
+    #[test]
+    fn render_labels_the_samples() {
+        let metrics = Metrics::default();
+        let pool = Pool::new(9300..=9310, &[], Strategy::default());
+        pool.insert(1, 9300, Restarts::default(), Default::default(), None);
+        pool.insert(2, 9301, Restarts::default(), Default::default(), None);
+        pool.mark_running(9301);
+        pool.insert(3, 9302, Restarts::default(), Default::default(), None);
+        pool.begin_drain(3);
+
+        metrics.forks.add(3);
+        metrics.recycles_sessions.inc();
+        metrics.bytes_to_client.add(42);
+        metrics.version_cache_hits.inc();
+        metrics.version_latency.observe(Duration::from_millis(2));
+
+        let usage = [InstanceUsage {
+            pid: 2,
+            port: 9301,
+            cpu_percent: 12.5,
+            memory_bytes: 1024,
+            open_fds: None,
+            processes: 4,
+        }];
+        let out = metrics.render(&pool, &usage);
+        let lines: Vec<&str> = out.lines().collect();
+
+        for line in [
+            "# HELP headless_browser_forks_total The instances forked.",
+            "# TYPE headless_browser_forks_total counter",
+            "headless_browser_forks_total 3",
+            "headless_browser_instances{state=\"starting\"} 1",
+            "headless_browser_instances{state=\"running\"} 1",
+            "headless_browser_instances{state=\"draining\"} 1",
+            "headless_browser_recycles_total{reason=\"memory\"} 0",
+            "headless_browser_recycles_total{reason=\"sessions\"} 1",
+            "headless_browser_proxy_bytes_total{direction=\"to_client\"} 42",
+            "headless_browser_json_version_cache_total{result=\"hit\"} 1",
+            "# TYPE headless_browser_json_version_duration_seconds histogram",
+            "headless_browser_json_version_duration_seconds_bucket{le=\"0.001\"} 0",
+            "headless_browser_json_version_duration_seconds_bucket{le=\"0.005\"} 1",
+            "headless_browser_json_version_duration_seconds_sum 0.002",
+            "headless_browser_json_version_duration_seconds_count 1",
+            "headless_browser_instance_cpu_percent{pid=\"2\",port=\"9301\"} 12.5",
+            "headless_browser_instance_memory_bytes{pid=\"2\",port=\"9301\"} 1024",
+            "headless_browser_instance_processes{pid=\"2\",port=\"9301\"} 4",
+        ] {
+            assert!(lines.contains(&line), "missing {}", line);
+        }
+
+        // the platforms without fd counts report no sample.
+        assert!(!out.contains("headless_browser_instance_open_fds{"));
+    }
+}
@@ -1,44 +1,218 @@
 use hyper::body::Bytes;
 
+/// The external address advertised in the chrome json urls.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Advertised {
+    /// The host. None keeps the host of chrome.
+    pub host: Option<String>,
+    /// The port.
+    pub port: u16,
+    /// Use `wss://`.
+    pub secure: bool,
+}
+
+impl Advertised {
+    /// The websocket scheme.
+    fn scheme(&self) -> &'static str {
+        if self.secure {
+            "wss"
+        } else {
+            "ws"
+        }
+    }
+
+    /// The host and port replacing the authority of chrome.
+    fn authority(&self, authority: &str) -> String {
+        let host = match &self.host {
+            Some(host) if host.contains(':') && !host.starts_with('[') => format!("[{}]", host),
+            Some(host) => host.clone(),
+            _ => match authority.rsplit_once(':') {
+                Some((host, _)) if !host.is_empty() => host.to_string(),
+                _ => authority.to_string(),
+            },
+        };
+
+        format!("{}:{}", host, self.port)
+    }
+}
+
 /// modify the json output for the bytes hosting. The headless instance cannot accept external request so we use the proxy.
-pub(crate) fn modify_json_output(body_bytes: Bytes) -> Bytes {
-    let buffer = body_bytes.as_ref();
-    let target_host = b"127.0.0.1";
-    let replacement_host = crate::HOST_NAME.as_bytes();
-
-    let (target_port, replacement_port) = *crate::TARGET_REPLACEMENT;
-
-    // Estimate a suitable capacity
-    let mut modified_buffer =
-        Vec::with_capacity(buffer.len() + (replacement_host.len() - target_host.len()));
-
-    let mut start = 0;
-
-    // Replace occurrences of the target host
-    while let Some(pos) = buffer[start..]
-        .windows(target_host.len())
-        .position(|window| window == target_host)
-    {
-        modified_buffer.extend_from_slice(&buffer[start..start + pos]);
-        modified_buffer.extend_from_slice(replacement_host);
-        start += pos + target_host.len();
-    }
-    modified_buffer.extend_from_slice(&buffer[start..]);
-
-    // Now handle the port replacement
-    let mut final_buffer =
-        Vec::with_capacity(modified_buffer.len() + (replacement_port.len() - target_port.len()));
-    start = 0;
-
-    while let Some(pos) = modified_buffer[start..]
-        .windows(target_port.len())
-        .position(|window| window == target_port)
-    {
-        final_buffer.extend_from_slice(&modified_buffer[start..start + pos]);
-        final_buffer.extend_from_slice(replacement_port);
-        start += pos + target_port.len();
-    }
-    final_buffer.extend_from_slice(&modified_buffer[start..]);
-
-    final_buffer.into()
+/// Only the `webSocketDebuggerUrl` and `devtoolsFrontendUrl` fields are rewritten. The body is returned as is when it is not json.
+/// The rewritten body keeps the key order of chrome and is serialized without the whitespace.
+pub(crate) fn modify_json_output(body_bytes: Bytes, advertised: &Advertised) -> Bytes {
+    let mut json = match serde_json::from_slice::<serde_json::Value>(&body_bytes) {
+        Ok(json) => json,
+        _ => return body_bytes,
+    };
+
+    rewrite(&mut json, advertised);
+
+    match serde_json::to_vec(&json) {
+        Ok(body) => body.into(),
+        _ => body_bytes,
+    }
+}
+
+/// Rewrite the urls of the target or the list of targets.
+fn rewrite(json: &mut serde_json::Value, advertised: &Advertised) {
+    match json {
+        serde_json::Value::Array(targets) => {
+            for target in targets {
+                rewrite(target, advertised);
+            }
+        }
+        serde_json::Value::Object(target) => {
+            if let Some(serde_json::Value::String(url)) = target.get_mut("webSocketDebuggerUrl") {
+                *url = rewrite_socket_url(url, advertised);
+            }
+            if let Some(serde_json::Value::String(url)) = target.get_mut("devtoolsFrontendUrl") {
+                *url = rewrite_frontend_url(url, advertised);
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Rewrite the websocket url ex: `ws://127.0.0.1:9223/devtools/browser/{id}`.
+fn rewrite_socket_url(url: &str, advertised: &Advertised) -> String {
+    let rest = match url.split_once("://") {
+        Some((_, rest)) => rest,
+        _ => return url.to_string(),
+    };
+
+    let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+
+    format!(
+        "{}://{}{}",
+        advertised.scheme(),
+        advertised.authority(authority),
+        path
+    )
+}
+
+/// Rewrite the `ws` or `wss` param of the devtools frontend url ex: `/devtools/inspector.html?ws=127.0.0.1:9223/devtools/page/{id}`.
+fn rewrite_frontend_url(url: &str, advertised: &Advertised) -> String {
+    let (base, query) = match url.split_once('?') {
+        Some(parts) => parts,
+        _ => return url.to_string(),
+    };
+
+    let params: Vec<String> = query
+        .split('&')
+        .map(|param| {
+            let value = param
+                .strip_prefix("ws=")
+                .or_else(|| param.strip_prefix("wss="));
+
+            match value {
+                Some(value) => {
+                    let (authority, path) = value.split_at(value.find('/').unwrap_or(value.len()));
+
+                    format!(
+                        "{}={}{}",
+                        advertised.scheme(),
+                        advertised.authority(authority),
+                        path
+                    )
+                }
+                _ => param.to_string(),
+            }
+        })
+        .collect();
+
+    format!("{}?{}", base, params.join("&"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The advertised address.
+    fn advertised(host: Option<&str>, port: u16, secure: bool) -> Advertised {
+        Advertised {
+            host: host.map(String::from),
+            port,
+            secure,
+        }
+    }
+
+    /// Rewrite the json body.
+    fn modify(body: &str, advertised: &Advertised) -> String {
+        let body = modify_json_output(Bytes::copy_from_slice(body.as_bytes()), advertised);
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn socket_url() {
+        let body = r#"{
+   "Browser": "HeadlessChrome/131.0.6778.139",
+   "User-Agent": "Mozilla/5.0 (127.0.0.1:9223)",
+   "webSocketDebuggerUrl": "ws://127.0.0.1:9223/devtools/browser/abc"
+}"#;
+
+        assert_eq!(
+            modify(body, &advertised(Some("example.com"), 443, true)),
+            r#"{"Browser":"HeadlessChrome/131.0.6778.139","User-Agent":"Mozilla/5.0 (127.0.0.1:9223)","webSocketDebuggerUrl":"wss://example.com:443/devtools/browser/abc"}"#
+        );
+    }
+
+    #[test]
+    fn socket_url_keeps_the_chrome_host() {
+        let body = r#"{"webSocketDebuggerUrl":"ws://127.0.0.1:9223/devtools/browser/abc"}"#;
+
+        assert_eq!(
+            modify(body, &advertised(None, 9222, false)),
+            r#"{"webSocketDebuggerUrl":"ws://127.0.0.1:9222/devtools/browser/abc"}"#
+        );
+    }
+
+    #[test]
+    fn ipv6_host() {
+        let body = r#"{"webSocketDebuggerUrl":"ws://127.0.0.1:9223/devtools/browser/abc"}"#;
+
+        assert_eq!(
+            modify(body, &advertised(Some("::1"), 9222, false)),
+            r#"{"webSocketDebuggerUrl":"ws://[::1]:9222/devtools/browser/abc"}"#
+        );
+    }
+
+    #[test]
+    fn frontend_url_params() {
+        let body = r#"[{
+   "id": "A",
+   "devtoolsFrontendUrl": "/devtools/inspector.html?ws=127.0.0.1:9223/devtools/page/A&panel=console",
+   "webSocketDebuggerUrl": "ws://127.0.0.1:9223/devtools/page/A"
+}, {
+   "id": "B",
+   "devtoolsFrontendUrl": "https://chrome-devtools-frontend.appspot.com/serve_rev/@abc/inspector.html?wss=127.0.0.1:9223/devtools/page/B",
+   "url": "https://example.com/?ws=127.0.0.1:9223"
+}]"#;
+
+        assert_eq!(
+            modify(body, &advertised(Some("example.com"), 9222, true)),
+            r#"[{"id":"A","devtoolsFrontendUrl":"/devtools/inspector.html?wss=example.com:9222/devtools/page/A&panel=console","webSocketDebuggerUrl":"wss://example.com:9222/devtools/page/A"},{"id":"B","devtoolsFrontendUrl":"https://chrome-devtools-frontend.appspot.com/serve_rev/@abc/inspector.html?wss=example.com:9222/devtools/page/B","url":"https://example.com/?ws=127.0.0.1:9223"}]"#
+        );
+    }
+
+    #[test]
+    fn frontend_url_without_query() {
+        let body = r#"{"devtoolsFrontendUrl":"/devtools/inspector.html"}"#;
+
+        assert_eq!(
+            modify(body, &advertised(Some("example.com"), 9222, false)),
+            body
+        );
+    }
+
+    #[test]
+    fn non_json_passthrough() {
+        let advertised = advertised(Some("example.com"), 9222, false);
+
+        assert_eq!(modify("Not Found", &advertised), "Not Found");
+        assert_eq!(
+            modify("ws://127.0.0.1:9223/devtools/browser/abc", &advertised),
+            "ws://127.0.0.1:9223/devtools/browser/abc"
+        );
+        assert_eq!(modify("", &advertised), "");
+    }
 }
@@ -0,0 +1,283 @@
+use crate::config::Config;
+use serde::Deserialize;
+use std::path::{Component, Path};
+
+/// The CDP error code of the rejected commands.
+const POLICY_ERROR: i64 = -32000;
+
+/// The method wrapping a command for a target in `params.message`.
+const SEND_MESSAGE_METHOD: &str = "Target.sendMessageToTarget";
+
+/// The methods that take a download path.
+const DOWNLOAD_METHODS: [&str; 2] = ["Browser.setDownloadBehavior", "Page.setDownloadBehavior"];
+
+/// The fields of a CDP command read by the proxy.
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct Command {
+    /// The command id.
+    pub id: Option<serde_json::Value>,
+    /// The CDP method ex: `Page.navigate`.
+    pub method: Option<String>,
+    /// The target session of the command.
+    #[serde(rename = "sessionId")]
+    pub session_id: Option<String>,
+    /// The download params.
+    pub params: Option<Params>,
+}
+
+/// The command params checked by the policy. The values are untyped since other methods use the same names.
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct Params {
+    /// The download behavior ex: `allow`.
+    pub behavior: Option<serde_json::Value>,
+    /// The download directory.
+    #[serde(rename = "downloadPath")]
+    pub download_path: Option<serde_json::Value>,
+    /// The command sent to a target with `Target.sendMessageToTarget`.
+    pub message: Option<serde_json::Value>,
+}
+
+/// Is a CDP policy configured?
+pub(crate) fn enabled(config: &Config) -> bool {
+    !config.cdp_allow.is_empty() || !config.cdp_deny.is_empty() || config.cdp_download_dir.is_some()
+}
+
+/// Check the command against the CDP policy of the config. Returns the reason the command is rejected.
+pub(crate) fn check(config: &Config, command: &Command) -> Result<(), String> {
+    let method = command.method.as_deref().unwrap_or_default();
+
+    if config.cdp_deny.iter().any(|p| matches(p, method))
+        || !config.cdp_allow.is_empty() && !config.cdp_allow.iter().any(|p| matches(p, method))
+    {
+        return Err(format!("{} is not allowed", method));
+    }
+
+    if let Some(dir) = &config.cdp_download_dir {
+        let params = command.params.as_ref();
+        let behavior = params.and_then(|p| p.behavior.as_ref()?.as_str());
+        let download_path = params.and_then(|p| p.download_path.as_ref()?.as_str());
+
+        let downloads =
+            DOWNLOAD_METHODS.contains(&method) && !matches!(behavior, Some("deny" | "default"));
+
+        if downloads
+            && !download_path.is_some_and(|path| is_within(Path::new(dir), Path::new(path)))
+        {
+            return Err(format!("{} must download to {}", method, dir));
+        }
+    }
+
+    // the wrapped command reaches the target so it passes the same policy.
+    if method == SEND_MESSAGE_METHOD {
+        let inner = command
+            .params
+            .as_ref()
+            .and_then(|p| p.message.as_ref()?.as_str())
+            .and_then(|message| serde_json::from_str::<Command>(message).ok())
+            .ok_or_else(|| format!("{} has an invalid message", method))?;
+
+        check(config, &inner)?;
+    }
+
+    Ok(())
+}
+
+/// The CDP error response of the rejected command.
+pub(crate) fn error_response(command: &Command, reason: &str) -> String {
+    let mut response = serde_json::json!({
+        "id": command.id,
+        "error": { "code": POLICY_ERROR, "message": reason },
+    });
+
+    if let Some(session_id) = &command.session_id {
+        response["sessionId"] = session_id.as_str().into();
+    }
+
+    response.to_string()
+}
+
+/// Validate the method pattern.
+pub(crate) fn validate_pattern(pattern: &str) -> Result<(), String> {
+    let method = pattern.strip_suffix(".*").unwrap_or(pattern);
+
+    if pattern != "*" && (method.is_empty() || method.contains(['*', ' '])) {
+        return Err(format!(
+            "CDP method pattern {} must be a method, Domain.*, or *",
+            pattern
+        ));
+    }
+
+    Ok(())
+}
+
+/// Does the pattern match the method? `Domain.*` matches the methods of the domain and `*` matches every method.
+fn matches(pattern: &str, method: &str) -> bool {
+    match pattern.strip_suffix(".*") {
+        Some(domain) => method
+            .split_once('.')
+            .is_some_and(|(method_domain, _)| method_domain == domain),
+        _ => pattern == "*" || pattern == method,
+    }
+}
+
+/// Is the path inside the directory without leaving it through `..`?
+pub(crate) fn is_within(dir: &Path, path: &Path) -> bool {
+    path.is_absolute()
+        && !path.components().any(|c| c == Component::ParentDir)
+        && path.starts_with(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The command parsed from the json.
+    fn command(json: serde_json::Value) -> Command {
+        serde_json::from_value(json).unwrap()
+    }
+
+    /// The command with the method.
+    fn method(method: &str) -> Command {
+        command(serde_json::json!({ "id": 1, "method": method }))
+    }
+
+    /// The config with the allow and deny patterns.
+    fn config(allow: &[&str], deny: &[&str]) -> Config {
+        Config {
+            cdp_allow: allow.iter().map(|p| p.to_string()).collect(),
+            cdp_deny: deny.iter().map(|p| p.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn patterns() {
+        assert!(matches("Page.navigate", "Page.navigate"));
+        assert!(!matches("Page.navigate", "Page.reload"));
+        assert!(matches("Page.*", "Page.navigate"));
+        assert!(!matches("Page.*", "PageX.navigate"));
+        assert!(!matches("Page.*", "Page"));
+        assert!(matches("*", "Browser.close"));
+        assert!(matches("*", ""));
+    }
+
+    #[test]
+    fn pattern_validation() {
+        assert!(validate_pattern("Browser.close").is_ok());
+        assert!(validate_pattern("SystemInfo.*").is_ok());
+        assert!(validate_pattern("*").is_ok());
+        assert!(validate_pattern(".*").is_err());
+        assert!(validate_pattern("Page*").is_err());
+        assert!(validate_pattern("*.navigate").is_err());
+        assert!(validate_pattern("Page.navigate ").is_err());
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let config = config(&["Browser.*"], &["Browser.close"]);
+
+        assert!(check(&config, &method("Browser.getVersion")).is_ok());
+        assert!(check(&config, &method("Browser.close")).is_err());
+    }
+
+    #[test]
+    fn allow_list_miss() {
+        let config = config(&["Page.*", "Runtime.evaluate"], &[]);
+
+        assert!(check(&config, &method("Page.navigate")).is_ok());
+        assert!(check(&config, &method("Runtime.evaluate")).is_ok());
+        assert!(check(&config, &method("Runtime.callFunctionOn")).is_err());
+        assert!(check(&config, &command(serde_json::json!({ "id": 1 }))).is_err());
+    }
+
+    #[test]
+    fn empty_policy_allows() {
+        assert!(check(&Config::default(), &method("Browser.close")).is_ok());
+    }
+
+    #[test]
+    fn wrapped_commands() {
+        let config = config(&[], &["Browser.close"]);
+        let wrapped = |message: serde_json::Value| {
+            command(serde_json::json!({
+                "id": 1,
+                "method": "Target.sendMessageToTarget",
+                "params": { "message": message, "sessionId": "A" },
+            }))
+        };
+
+        let denied = serde_json::json!({ "id": 2, "method": "Browser.close" }).to_string();
+        let allowed = serde_json::json!({ "id": 2, "method": "Page.reload" }).to_string();
+        let nested = serde_json::json!({
+            "id": 2,
+            "method": "Target.sendMessageToTarget",
+            "params": { "message": denied },
+        })
+        .to_string();
+
+        assert!(check(&config, &wrapped(denied.into())).is_err());
+        assert!(check(&config, &wrapped(nested.into())).is_err());
+        assert!(check(&config, &wrapped(allowed.into())).is_ok());
+        assert!(check(&config, &wrapped("not json".into())).is_err());
+        assert!(check(&config, &wrapped(serde_json::Value::Null)).is_err());
+    }
+
+    #[test]
+    fn download_paths() {
+        let config = Config {
+            cdp_download_dir: Some("/srv/downloads".into()),
+            ..Default::default()
+        };
+        let download = |behavior: &str, path: &str| {
+            command(serde_json::json!({
+                "id": 1,
+                "method": "Browser.setDownloadBehavior",
+                "params": { "behavior": behavior, "downloadPath": path },
+            }))
+        };
+
+        assert!(check(&config, &download("allow", "/srv/downloads/job-1")).is_ok());
+        assert!(check(&config, &download("allow", "/srv/downloads")).is_ok());
+        assert!(check(&config, &download("allow", "/tmp")).is_err());
+        assert!(check(&config, &download("allow", "/srv/downloads/../etc")).is_err());
+        assert!(check(&config, &download("allow", "/srv/downloads-x")).is_err());
+        assert!(check(&config, &download("allow", "downloads")).is_err());
+        assert!(check(&config, &download("deny", "/tmp")).is_ok());
+        assert!(check(
+            &config,
+            &command(serde_json::json!({
+                "id": 1,
+                "method": "Page.setDownloadBehavior",
+                "params": { "behavior": "allow" },
+            }))
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn within() {
+        let dir = Path::new("/srv/downloads");
+
+        assert!(is_within(dir, Path::new("/srv/downloads/a/b")));
+        assert!(!is_within(dir, Path::new("/srv/downloads/a/../../etc")));
+        assert!(!is_within(dir, Path::new("/srv/downloads/..")));
+        assert!(!is_within(dir, Path::new("/srv/down")));
+        assert!(!is_within(dir, Path::new("a/b")));
+    }
+
+    #[test]
+    fn error_response_keeps_the_id_and_session() {
+        let command = command(serde_json::json!({
+            "id": 7,
+            "method": "Browser.close",
+            "sessionId": "S",
+        }));
+        let response: serde_json::Value =
+            serde_json::from_str(&error_response(&command, "Browser.close is not allowed"))
+                .unwrap();
+
+        assert_eq!(response["id"], 7);
+        assert_eq!(response["sessionId"], "S");
+        assert_eq!(response["error"]["code"], POLICY_ERROR);
+    }
+}
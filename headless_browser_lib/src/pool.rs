@@ -0,0 +1,659 @@
+use crate::balancer::Strategy;
+use crate::launch::LaunchOptions;
+use dashmap::{DashMap, DashSet};
+use std::collections::VecDeque;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+
+/// The amount of exits kept in the history.
+const EXIT_HISTORY: usize = 64;
+
+/// The lifecycle state of a chrome instance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InstanceState {
+    /// The process spawned and the debugging port did not accept a connection yet.
+    Starting,
+    /// The debugging port accepted a connection.
+    Running,
+    /// The instance is replaced and waits for the active connections to close. No new connections are routed.
+    Draining,
+}
+
+/// A chrome instance tracked by the pool.
+#[derive(Debug, Clone)]
+pub struct Instance {
+    /// The process id.
+    pub pid: u32,
+    /// The remote debugging port.
+    pub port: u32,
+    /// The lifecycle state.
+    pub state: InstanceState,
+    /// When the instance was launched.
+    pub started: Instant,
+    /// The wall clock time the instance was launched.
+    pub started_at: SystemTime,
+    /// The browser version reported by the instance.
+    pub browser: Option<String>,
+    /// The restart history carried over from the crashed instances replaced.
+    pub restarts: Restarts,
+    /// Is the instance accepting connections? Unhealthy instances are skipped by the balancer.
+    pub healthy: bool,
+    /// Did a health probe of the instance succeed?
+    pub probed: bool,
+    /// The consecutive failed health probes.
+    pub probe_failures: u32,
+    /// The launch options. Respawns launch with the same options.
+    pub options: LaunchOptions,
+    /// The profile directory.
+    pub user_data_dir: Option<PathBuf>,
+    /// The active proxied connections.
+    active: Arc<AtomicUsize>,
+    /// The proxied connections routed to the instance.
+    sessions: Arc<AtomicU64>,
+}
+
+impl Instance {
+    /// The amount of proxied connections routed to the instance.
+    pub fn sessions(&self) -> u64 {
+        self.sessions.load(Ordering::Relaxed)
+    }
+
+    /// The amount of active proxied connections.
+    pub fn active_connections(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// The management API view of the instance.
+    pub fn info(&self, browser: Option<String>) -> InstanceInfo {
+        InstanceInfo {
+            pid: self.pid,
+            port: self.port,
+            state: self.state,
+            started_at: self
+                .started_at
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            uptime: self.started.elapsed().as_secs(),
+            restarts: self.restarts.total,
+            active_connections: self.active_connections(),
+            sessions: self.sessions(),
+            probe_failures: self.probe_failures,
+            browser: browser.or_else(|| self.browser.clone()),
+            profile: self.options.profile.clone(),
+            user_data_dir: self
+                .user_data_dir
+                .as_ref()
+                .map(|dir| dir.display().to_string()),
+        }
+    }
+}
+
+/// The management API view of an instance.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct InstanceInfo {
+    /// The process id.
+    pub pid: u32,
+    /// The remote debugging port.
+    pub port: u32,
+    /// The lifecycle state.
+    pub state: InstanceState,
+    /// The unix time in seconds the instance was launched.
+    pub started_at: u64,
+    /// The seconds the instance is up.
+    pub uptime: u64,
+    /// The amount of times the instance was respawned.
+    pub restarts: u32,
+    /// The active proxied connections.
+    pub active_connections: usize,
+    /// The proxied connections routed to the instance.
+    pub sessions: u64,
+    /// The consecutive failed health probes.
+    pub probe_failures: u32,
+    /// The browser version reported by the instance.
+    pub browser: Option<String>,
+    /// The named profile.
+    pub profile: Option<String>,
+    /// The profile directory.
+    pub user_data_dir: Option<String>,
+}
+
+/// The restart history of an instance.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Restarts {
+    /// The total amount of times the instance was respawned.
+    pub total: u32,
+    /// The respawns since the last instance that stayed up past the crash loop window.
+    pub consecutive: u32,
+}
+
+/// The exit of a chrome process.
+#[derive(Debug, Clone)]
+pub struct Exit {
+    /// The process id.
+    pub pid: u32,
+    /// The remote debugging port.
+    pub port: u32,
+    /// The exit code if the process exited normally.
+    pub code: Option<i32>,
+    /// The signal that terminated the process.
+    pub signal: Option<i32>,
+    /// Did the process exit without being shutdown?
+    pub unexpected: bool,
+    /// When the exit was reaped.
+    pub at: SystemTime,
+}
+
+/// A proxied connection routed to an instance. The connection is released on drop.
+#[derive(Debug)]
+pub struct Lease {
+    /// The process id.
+    pub pid: u32,
+    /// The remote debugging port.
+    pub port: u32,
+    /// The active connections of the instance.
+    active: Arc<AtomicUsize>,
+    /// The proxied connections routed to the instance.
+    sessions: Arc<AtomicU64>,
+}
+
+impl Lease {
+    /// The active connections of the instance including the lease.
+    pub fn active_connections(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Count the connection as a session of the instance once chrome accepted it.
+    pub fn accept(&self) {
+        self.sessions.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The pool of chrome instances launched with the debugging ports allocated.
+#[derive(Debug)]
+pub struct Pool {
+    /// The instances keyed by process id.
+    instances: DashMap<u32, Instance>,
+    /// The ports in use or reserved.
+    ports: DashSet<u32>,
+    /// The range to allocate debugging ports from.
+    port_range: RangeInclusive<u32>,
+    /// The balancing strategy.
+    strategy: Strategy,
+    /// The balancer cursor.
+    cursor: AtomicUsize,
+    /// The recent process exits.
+    exits: Mutex<VecDeque<Exit>>,
+    /// Incremented when the pool is cleared to cancel pending respawns.
+    generation: AtomicU64,
+    /// The instance owning the browser or target id.
+    targets: DashMap<String, TargetOwner>,
+}
+
+/// The instance owning a browser or target id.
+#[derive(Debug, Clone, Copy)]
+struct TargetOwner {
+    /// The process id.
+    pid: u32,
+    /// Is the id a page target? The browser ids live as long as the instance.
+    page: bool,
+}
+
+impl Pool {
+    /// A new pool allocating ports from the range. The reserved ports are never handed out.
+    pub fn new(port_range: RangeInclusive<u32>, reserved: &[u32], strategy: Strategy) -> Self {
+        let ports = DashSet::new();
+
+        for port in reserved {
+            ports.insert(*port);
+        }
+
+        Self {
+            instances: DashMap::new(),
+            ports,
+            port_range,
+            strategy,
+            cursor: AtomicUsize::new(0),
+            exits: Mutex::new(VecDeque::with_capacity(EXIT_HISTORY)),
+            generation: AtomicU64::new(0),
+            targets: DashMap::new(),
+        }
+    }
+
+    /// The port range used for allocation.
+    pub fn port_range(&self) -> &RangeInclusive<u32> {
+        &self.port_range
+    }
+
+    /// Are there no instances?
+    pub fn is_empty(&self) -> bool {
+        self.instances.is_empty()
+    }
+
+    /// The amount of instances.
+    pub fn len(&self) -> usize {
+        self.instances.len()
+    }
+
+    /// Reserve a debugging port. A requested port is used as is when free, else the next free port in the range.
+    pub fn allocate_port(&self, port: Option<u32>) -> Option<u32> {
+        match port {
+            Some(port) => {
+                if self.ports.insert(port) {
+                    Some(port)
+                } else {
+                    None
+                }
+            }
+            _ => {
+                for port in self.port_range.clone() {
+                    if self.ports.insert(port) {
+                        if port_available(port) {
+                            return Some(port);
+                        }
+                        self.ports.remove(&port);
+                    }
+                }
+                None
+            }
+        }
+    }
+
+    /// Release a reserved port.
+    pub fn release_port(&self, port: u32) {
+        self.ports.remove(&port);
+    }
+
+    /// Track a launched instance on the reserved port.
+    pub fn insert(
+        &self,
+        pid: u32,
+        port: u32,
+        restarts: Restarts,
+        options: LaunchOptions,
+        user_data_dir: Option<PathBuf>,
+    ) {
+        self.instances.insert(
+            pid,
+            Instance {
+                pid,
+                port,
+                state: InstanceState::Starting,
+                started: Instant::now(),
+                started_at: SystemTime::now(),
+                browser: None,
+                restarts,
+                healthy: true,
+                probed: false,
+                probe_failures: 0,
+                options,
+                user_data_dir,
+                active: Default::default(),
+                sessions: Default::default(),
+            },
+        );
+    }
+
+    /// Stop tracking the instance and release the port.
+    pub fn remove(&self, pid: u32) -> Option<Instance> {
+        let (_, instance) = self.instances.remove(&pid)?;
+        self.release_port(instance.port);
+        self.targets.retain(|_, owner| owner.pid != pid);
+        Some(instance)
+    }
+
+    /// Get the instance by process id.
+    pub fn get(&self, pid: u32) -> Option<Instance> {
+        self.instances.get(&pid).map(|i| i.clone())
+    }
+
+    /// Get the instance listening on the port.
+    pub fn get_by_port(&self, port: u32) -> Option<Instance> {
+        self.instances
+            .iter()
+            .find(|i| i.port == port)
+            .map(|i| i.clone())
+    }
+
+    /// Is the profile directory used by an instance?
+    pub fn profile_in_use(&self, dir: &std::path::Path) -> bool {
+        self.instances
+            .iter()
+            .any(|i| i.user_data_dir.as_deref() == Some(dir))
+    }
+
+    /// Does the pool contain the process id?
+    pub fn contains(&self, pid: u32) -> bool {
+        self.instances.contains_key(&pid)
+    }
+
+    /// All of the process ids tracked.
+    pub fn pids(&self) -> Vec<u32> {
+        self.instances.iter().map(|i| i.pid).collect()
+    }
+
+    /// A snapshot of the instances ordered by port.
+    pub fn instances(&self) -> Vec<Instance> {
+        let mut instances: Vec<Instance> = self.instances.iter().map(|i| i.clone()).collect();
+        instances.sort_by_key(|i| i.port);
+        instances
+    }
+
+    /// Mark the instance on the port as running and healthy.
+    pub fn mark_running(&self, port: u32) {
+        for mut instance in self.instances.iter_mut() {
+            if instance.port == port && instance.state != InstanceState::Draining {
+                instance.state = InstanceState::Running;
+                instance.healthy = true;
+            }
+        }
+    }
+
+    /// Start draining the instance. Returns false if the instance is unknown or already draining.
+    pub fn begin_drain(&self, pid: u32) -> bool {
+        match self.instances.get_mut(&pid) {
+            Some(mut instance) if instance.state != InstanceState::Draining => {
+                instance.state = InstanceState::Draining;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Set the browser version reported by the instance on the port.
+    pub fn set_browser(&self, port: u32, browser: &str) {
+        for mut instance in self.instances.iter_mut() {
+            if instance.port == port && instance.browser.as_deref() != Some(browser) {
+                instance.browser = Some(browser.to_string());
+            }
+        }
+    }
+
+    /// Mark the instance on the port as healthy or unhealthy.
+    pub fn mark_healthy(&self, port: u32, healthy: bool) {
+        for mut instance in self.instances.iter_mut() {
+            if instance.port == port {
+                instance.healthy = healthy;
+            }
+        }
+    }
+
+    /// Record the health probe of the instance. A success marks the instance running and healthy and the instance is unhealthy once the failures reach the threshold.
+    /// Returns the consecutive failures.
+    pub fn record_probe(&self, pid: u32, browser: Option<&str>, threshold: u32) -> u32 {
+        let mut instance = match self.instances.get_mut(&pid) {
+            Some(instance) => instance,
+            _ => return 0,
+        };
+
+        match browser {
+            Some(browser) => {
+                if instance.state == InstanceState::Starting {
+                    instance.state = InstanceState::Running;
+                }
+                instance.healthy = true;
+                instance.probed = true;
+                instance.probe_failures = 0;
+                instance.browser = Some(browser.to_string());
+            }
+            _ => {
+                instance.probe_failures += 1;
+
+                if instance.probe_failures >= threshold {
+                    instance.healthy = false;
+                }
+            }
+        }
+
+        instance.probe_failures
+    }
+
+    /// Select the instance to route to with the balancing strategy. Draining instances are never selected and unhealthy instances are skipped unless none are healthy.
+    pub fn select(&self) -> Option<Instance> {
+        let instances: Vec<Instance> = self
+            .instances()
+            .into_iter()
+            .filter(|i| i.state != InstanceState::Draining)
+            .collect();
+        let healthy: Vec<Instance> = instances.iter().filter(|i| i.healthy).cloned().collect();
+        let candidates = if healthy.is_empty() {
+            instances
+        } else {
+            healthy
+        };
+
+        self.strategy
+            .select(&candidates, &self.cursor)
+            .map(|index| candidates[index].clone())
+    }
+
+    /// The next port to route to.
+    pub fn next_port(&self) -> Option<u32> {
+        self.select().map(|i| i.port)
+    }
+
+    /// Select an instance and track the connection until the lease drops.
+    pub fn acquire(&self) -> Option<Lease> {
+        self.select().map(Self::lease)
+    }
+
+    /// Track the connection on the instance until the lease drops. Draining instances are leased since the targets live there.
+    pub fn acquire_instance(&self, pid: u32) -> Option<Lease> {
+        self.get(pid).map(Self::lease)
+    }
+
+    /// Lease the instance.
+    fn lease(instance: Instance) -> Lease {
+        instance.active.fetch_add(1, Ordering::Relaxed);
+
+        Lease {
+            pid: instance.pid,
+            port: instance.port,
+            active: instance.active,
+            sessions: instance.sessions,
+        }
+    }
+
+    /// Remember the instance on the port owns the browser or page target id.
+    pub fn record_target(&self, id: &str, port: u32, page: bool) {
+        if let Some(instance) = self.get_by_port(port) {
+            self.targets.insert(
+                id.to_string(),
+                TargetOwner {
+                    pid: instance.pid,
+                    page,
+                },
+            );
+        }
+    }
+
+    /// Forget the owner of the closed target id.
+    pub fn forget_target(&self, id: &str) {
+        self.targets.remove(id);
+    }
+
+    /// Forget the page targets of the instance on the port that are not listed anymore.
+    pub fn retain_targets(&self, port: u32, listed: &[String]) {
+        if let Some(instance) = self.get_by_port(port) {
+            self.targets.retain(|id, owner| {
+                owner.pid != instance.pid || !owner.page || listed.contains(id)
+            });
+        }
+    }
+
+    /// The instance owning the browser or target id.
+    pub fn target_owner(&self, id: &str) -> Option<Instance> {
+        let pid = self.targets.get(id)?.pid;
+        self.get(pid)
+    }
+
+    /// Record a reaped process exit.
+    pub fn record_exit(&self, exit: Exit) {
+        if let Ok(mut exits) = self.exits.lock() {
+            if exits.len() >= EXIT_HISTORY {
+                exits.pop_front();
+            }
+            exits.push_back(exit);
+        }
+    }
+
+    /// The recent process exits, oldest first.
+    pub fn exits(&self) -> Vec<Exit> {
+        self.exits
+            .lock()
+            .map(|exits| exits.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// The current generation. Changes when the pool is cleared.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Relaxed)
+    }
+
+    /// Stop tracking all instances and release the ports.
+    pub fn clear(&self) {
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        for pid in self.pids() {
+            self.remove(pid);
+        }
+    }
+}
+
+/// Is the port free to bind on the loopback?
+fn port_available(port: u32) -> bool {
+    match u16::try_from(port) {
+        Ok(port) => std::net::TcpListener::bind(("127.0.0.1", port)).is_ok(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The pool with one instance.
+    fn pool() -> Pool {
+        let pool = Pool::new(9300..=9310, &[], Strategy::default());
+        pool.insert(1, 9300, Restarts::default(), Default::default(), None);
+        pool
+    }
+
+    #[test]
+    fn sessions_count_once_accepted() {
+        let pool = pool();
+
+        let rejected = pool.acquire().unwrap();
+        assert_eq!(rejected.active_connections(), 1);
+        drop(rejected);
+
+        let accepted = pool.acquire().unwrap();
+        accepted.accept();
+
+        let instance = pool.get(1).unwrap();
+        assert_eq!(instance.sessions(), 1);
+        assert_eq!(instance.active_connections(), 1);
+
+        drop(accepted);
+        assert_eq!(pool.get(1).unwrap().active_connections(), 0);
+    }
+
+    #[test]
+    fn targets_forgotten_when_closed_or_unlisted() {
+        let pool = pool();
+        pool.record_target("browser", 9300, false);
+        pool.record_target("open", 9300, true);
+        pool.record_target("closed", 9300, true);
+        pool.record_target("gone", 9300, true);
+
+        pool.forget_target("closed");
+        pool.retain_targets(9300, &["open".to_string()]);
+
+        assert!(pool.target_owner("browser").is_some());
+        assert!(pool.target_owner("open").is_some());
+        assert!(pool.target_owner("closed").is_none());
+        assert!(pool.target_owner("gone").is_none());
+
+        pool.remove(1);
+        assert!(pool.target_owner("browser").is_none());
+    }
+
+    /// A port of the loopback free when returned.
+    fn free_port() -> u32 {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().port().into()
+    }
+
+    #[test]
+    fn ports_allocate_until_the_range_is_exhausted() {
+        let port = free_port();
+        let pool = Pool::new(port..=port, &[], Strategy::default());
+
+        assert_eq!(pool.allocate_port(None), Some(port));
+        assert_eq!(pool.allocate_port(None), None);
+        assert_eq!(pool.allocate_port(Some(port)), None);
+
+        pool.release_port(port);
+        assert_eq!(pool.allocate_port(Some(port)), Some(port));
+    }
+
+    #[test]
+    fn reserved_ports_are_never_allocated() {
+        let port = free_port();
+        let pool = Pool::new(port..=port, &[port], Strategy::default());
+
+        assert_eq!(pool.allocate_port(None), None);
+        assert_eq!(pool.allocate_port(Some(port)), None);
+    }
+
+    #[test]
+    fn ports_bound_by_other_processes_are_skipped() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port: u32 = listener.local_addr().unwrap().port().into();
+        let pool = Pool::new(port..=port, &[], Strategy::default());
+
+        assert_eq!(pool.allocate_port(None), None);
+
+        drop(listener);
+        assert_eq!(pool.allocate_port(None), Some(port));
+    }
+
+    #[test]
+    fn draining_instances_are_never_selected() {
+        let pool = Pool::new(9300..=9310, &[], Strategy::RandomTwoChoices);
+        pool.insert(1, 9300, Restarts::default(), Default::default(), None);
+        pool.insert(2, 9301, Restarts::default(), Default::default(), None);
+        pool.begin_drain(1);
+
+        for _ in 0..50 {
+            assert_eq!(pool.select().unwrap().pid, 2);
+        }
+
+        pool.begin_drain(2);
+        assert!(pool.select().is_none());
+    }
+
+    #[test]
+    fn unhealthy_instances_are_skipped_unless_none_are_healthy() {
+        let pool = Pool::new(9300..=9310, &[], Strategy::RoundRobin);
+        pool.insert(1, 9300, Restarts::default(), Default::default(), None);
+        pool.insert(2, 9301, Restarts::default(), Default::default(), None);
+        pool.mark_healthy(9300, false);
+
+        for _ in 0..4 {
+            assert_eq!(pool.select().unwrap().pid, 2);
+        }
+
+        pool.mark_healthy(9301, false);
+        assert!(pool.select().is_some());
+    }
+}
@@ -1,72 +1,268 @@
 pub(crate) mod proxy {
-    use crate::conf::{BUFFER_SIZE, ENTRY, TARGET, TEN_SECONDS};
-    use crate::{connect_with_retries, fork, shutdown_instances, CACHEABLE, LAST_CACHE};
-    use std::{io::ErrorKind, time::Instant};
+    use crate::auth::{self, Grant, Scope};
+    use crate::config::ProxyMode;
+    use crate::connect_with_retries;
+    use crate::pool::Lease;
+    use crate::routing;
+    use crate::server::TEN_SECONDS;
+    use crate::Server;
+    use hyper::header::{HeaderName, HeaderValue};
+    use hyper::{HeaderMap, Method, Uri};
+    use std::{
+        io::ErrorKind,
+        time::{Duration, Instant},
+    };
     use tokio::{
-        io::{AsyncReadExt, AsyncWriteExt},
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
         net::{TcpListener, TcpStream},
     };
 
+    /// The time a client has to send the request head. Authenticated connections are dropped after it and the others are balanced.
+    const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// The response to the requests without valid credentials.
+    const UNAUTHORIZED: &[u8] = b"HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Bearer\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
     /// Run the proxy forwarder for chrome. This allows connecting to chrome outside of the network.
-    pub async fn run_proxy() -> std::io::Result<()> {
-        let listener = TcpListener::bind(*ENTRY).await?;
-        println!("Proxy Listening on {}", *ENTRY);
+    pub async fn run_proxy(server: Server) -> std::io::Result<()> {
+        let entry = format!("0.0.0.0:{}", server.entry_port());
+        let listener = TcpListener::bind(&entry).await?;
+        println!("Proxy Listening on {}", entry);
         let base_time = Instant::now();
 
         loop {
-            let (mut client_stream, client_addr) = listener.accept().await?;
+            let (client_stream, client_addr) = listener.accept().await?;
             tracing::info!("Accepted connection from {}", client_addr);
+            server.metrics.connections.inc();
+
+            let server = server.clone();
 
             tokio::spawn(async move {
-                let mut should_retry = false;
-
-                if let Err(err) = handle_connection(&mut client_stream).await {
-                    if err.kind() == ErrorKind::NotConnected || err.kind() == ErrorKind::Other {
-                        should_retry = true;
-                        tracing::error!("Error handling connection: {}. Restarting Chrome.", err);
-                        // send a signal instead or channel to prevent race
-                        // todo: we need to swap to a new port and use a LB to track the drain to reset the ports used.
-                        shutdown_instances().await;
-                        fork(Some(*crate::DEFAULT_PORT));
-                        CACHEABLE.store(false, std::sync::atomic::Ordering::Relaxed);
-                        LAST_CACHE.store(
-                            base_time.elapsed().as_secs().try_into().unwrap_or_default(),
-                            std::sync::atomic::Ordering::Relaxed,
-                        );
-                    } else {
-                        // ignore connection resets by peer
-                        if err.kind() != ErrorKind::ConnectionReset {
-                            tracing::error!("Error handling connection: {}", err);
+                match &server.tls {
+                    Some(tls) => match tls.accept(client_stream).await {
+                        Ok(stream) => serve_connection(server.clone(), stream, base_time).await,
+                        Err(e) => {
+                            tracing::debug!("TLS handshake from {} failed: {}", client_addr, e)
                         }
-                    }
-                } else if !CACHEABLE.load(std::sync::atomic::Ordering::Relaxed) {
-                    let elasped = LAST_CACHE.load(std::sync::atomic::Ordering::Relaxed);
+                    },
+                    _ => serve_connection(server.clone(), client_stream, base_time).await,
+                }
+            });
+        }
+    }
+
+    /// Serve the proxy connection with the proxy mode.
+    async fn serve_connection<S>(server: Server, client_stream: S, base_time: Instant)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        match server.config.proxy_mode {
+            ProxyMode::Cdp => crate::cdp::serve_connection(server, client_stream).await,
+            ProxyMode::Raw => forward_connection(server, client_stream, base_time).await,
+        }
+    }
+
+    /// Forward the raw bytes of the connection to an instance. The instance is replaced when chrome cannot be reached.
+    async fn forward_connection<S>(server: Server, mut client_stream: S, base_time: Instant)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut should_retry = false;
+        // the headers are read to authenticate the request, else the request line routes the connection with several instances.
+        let authenticate = !server.config.proxy_keys.is_empty();
+        let mut head = vec![];
 
-                    if elasped > 0 {
-                        let elapsed_since_base = base_time.elapsed();
-                        let total_elapsed =
-                            tokio::time::Duration::from_secs(elasped) + elapsed_since_base;
+        if authenticate || server.instances.len() > 1 {
+            let end: &[u8] = if authenticate { b"\r\n\r\n" } else { b"\r\n" };
+
+            match tokio::time::timeout(
+                HEAD_TIMEOUT,
+                read_head(
+                    &mut client_stream,
+                    &mut head,
+                    server.config.buffer_size,
+                    end,
+                ),
+            )
+            .await
+            {
+                Ok(Ok(_)) if !head.is_empty() => (),
+                Err(_) if !authenticate => (),
+                _ => return,
+            }
+        }
 
-                        if total_elapsed >= *TEN_SECONDS {
-                            CACHEABLE.store(true, std::sync::atomic::Ordering::Relaxed);
+        if authenticate {
+            head = match authorize_head(&server, &head) {
+                Some(head) => head,
+                _ => {
+                    server.metrics.auth_rejected.inc();
+                    let _ = client_stream.write_all(UNAUTHORIZED).await;
+                    return;
+                }
+            };
+        }
+
+        let path = std::str::from_utf8(&head)
+            .ok()
+            .and_then(|head| head.split_whitespace().nth(1))
+            .unwrap_or_default();
+        let lease = routing::acquire(&server, path).await;
+        let pid = lease.as_ref().map(|lease| lease.pid);
+
+        if let Err(err) = handle_connection(&server, &mut client_stream, &head, lease).await {
+            if err.kind() == ErrorKind::NotConnected || err.kind() == ErrorKind::Other {
+                should_retry = true;
+                tracing::error!("Error handling connection: {}. Replacing Chrome.", err);
+                // the failed instance drains on its own port while new connections route to the replacement.
+                match pid {
+                    Some(pid) => {
+                        server.drain_and_replace(pid);
+                    }
+                    _ => {
+                        if server.instances.is_empty() {
+                            server.fork(None);
                         }
                     }
                 }
+                server
+                    .cacheable
+                    .store(false, std::sync::atomic::Ordering::Relaxed);
+                server.last_cache.store(
+                    base_time.elapsed().as_secs(),
+                    std::sync::atomic::Ordering::Relaxed,
+                );
+            } else {
+                // ignore connection resets by peer
+                if err.kind() != ErrorKind::ConnectionReset {
+                    tracing::error!("Error handling connection: {}", err);
+                }
+            }
+        } else if !server.cacheable.load(std::sync::atomic::Ordering::Relaxed) {
+            let elasped = server.last_cache.load(std::sync::atomic::Ordering::Relaxed);
+
+            if elasped > 0 {
+                let elapsed_since_base = base_time.elapsed();
+                let total_elapsed = tokio::time::Duration::from_secs(elasped) + elapsed_since_base;
 
-                if should_retry {
-                    tokio::task::yield_now().await;
-                    let _ = handle_connection(&mut client_stream).await;
+                if total_elapsed >= TEN_SECONDS {
+                    server
+                        .cacheable
+                        .store(true, std::sync::atomic::Ordering::Relaxed);
                 }
-            });
+            }
+        }
+
+        if should_retry {
+            tokio::task::yield_now().await;
+            let lease = routing::acquire(&server, path).await;
+            let _ = handle_connection(&server, &mut client_stream, &head, lease).await;
         }
     }
 
-    /// Handle the proxy connection.
-    async fn handle_connection(client_stream: &mut TcpStream) -> std::io::Result<()> {
-        let server_stream: Option<TcpStream> = connect_with_retries(*TARGET).await;
+    /// Read the start of the request until the end is found or the limit is reached.
+    async fn read_head<S>(
+        client_stream: &mut S,
+        head: &mut Vec<u8>,
+        limit: usize,
+        end: &[u8],
+    ) -> std::io::Result<()>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let mut buf = vec![0u8; limit];
+
+        while head.len() < limit && !head.windows(end.len()).any(|w| w == end) {
+            let size = client_stream.read(&mut buf).await?;
+            if size == 0 {
+                break;
+            }
+            head.extend_from_slice(&buf[..size]);
+        }
+
+        Ok(())
+    }
+
+    /// Authorize the request head with the proxy keys. Returns the head to forward without the credentials.
+    fn authorize_head(server: &Server, head: &[u8]) -> Option<Vec<u8>> {
+        let end = head.windows(4).position(|w| w == b"\r\n\r\n")?;
+        let text = std::str::from_utf8(&head[..end]).ok()?;
+        let mut lines = text.split("\r\n");
+        let mut request_line = lines.next()?.split(' ');
+        let (method, target, version) = (
+            request_line.next()?,
+            request_line.next()?,
+            request_line.next()?,
+        );
+        let method = Method::from_bytes(method.as_bytes()).ok()?;
+        let uri = target.parse::<Uri>().ok()?;
+        let fields: Vec<(&str, &str)> = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim(), value.trim()))
+            .collect();
+
+        let mut headers = HeaderMap::new();
+
+        for (name, value) in &fields {
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(value),
+            ) {
+                headers.append(name, value);
+            }
+        }
+
+        let grant = auth::authorize(&server.config, Scope::Proxy, &method, &uri, &headers)?;
+        let target = match grant {
+            Grant::Signed => grant.forwarded_path(&uri),
+            _ => target.to_string(),
+        };
+
+        // the credentials are not forwarded to chrome.
+        let mut forwarded = format!("{} {} {}\r\n", method, target, version);
+
+        for (name, value) in fields {
+            if grant != Grant::Bearer || !name.eq_ignore_ascii_case("authorization") {
+                forwarded.push_str(&format!("{}: {}\r\n", name, value));
+            }
+        }
+        forwarded.push_str("\r\n");
+
+        let mut forwarded = forwarded.into_bytes();
+        forwarded.extend_from_slice(&head[end + 4..]);
+
+        Some(forwarded)
+    }
+
+    /// Handle the proxy connection. The head read from the client is sent first. The lease tracks the active connection on the instance until dropped.
+    async fn handle_connection<S>(
+        server: &Server,
+        client_stream: &mut S,
+        head: &[u8],
+        lease: Option<Lease>,
+    ) -> std::io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let port = lease
+            .as_ref()
+            .map_or(server.config.chrome_port, |lease| lease.port);
+        let target = format!("127.0.0.1:{}", port);
+        let server_stream: Option<TcpStream> = connect_with_retries(server, &target).await;
 
         if let Some(mut server_stream) = server_stream {
-            let buffer_size = *BUFFER_SIZE;
+            server.instances.mark_running(port);
+
+            if let Some(lease) = &lease {
+                lease.accept();
+            }
+
+            if server_stream.write_all(head).await.is_err() {
+                return Ok(());
+            }
+            server.metrics.bytes_to_chrome.add(head.len() as u64);
+
+            let buffer_size = server.config.buffer_size;
             let mut buf1 = vec![0u8; buffer_size];
             let mut buf2 = vec![0u8; buffer_size];
 
@@ -83,6 +279,7 @@ pub(crate) mod proxy {
                         if let Err(_) = client_stream.write_all(&buf1[..size]).await  {
                             break;
                         }
+                        server.metrics.bytes_to_client.add(size as u64);
                     },
                     b = client_stream.read(&mut buf2) => {
                         let size = match b {
@@ -95,6 +292,7 @@ pub(crate) mod proxy {
                         if let Err(_) = server_stream.write_all(&buf2[..size]).await {
                             break;
                         }
+                        server.metrics.bytes_to_chrome.add(size as u64);
                     },
                     else => {
                         break;
@@ -102,12 +300,128 @@ pub(crate) mod proxy {
                 }
             }
 
+            drop(lease);
+
             Ok(())
         } else {
+            server.instances.mark_healthy(port, false);
+
             Err(std::io::Error::new(
                 std::io::ErrorKind::Other,
                 "Failed to connect after several attempts",
             ))
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::config::Config;
+        use crate::testing::FakeChrome;
+
+        #[tokio::test]
+        async fn single_instance_connects_before_the_request() {
+            let chrome = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let server = Server::new(Config {
+                chrome_port: chrome.local_addr().unwrap().port().into(),
+                ..Default::default()
+            });
+            let (mut client, stream) = tokio::io::duplex(1024);
+
+            tokio::spawn(forward_connection(server, stream, Instant::now()));
+
+            let (mut chrome, _) = tokio::time::timeout(Duration::from_secs(1), chrome.accept())
+                .await
+                .unwrap()
+                .unwrap();
+            chrome.write_all(b"hello").await.unwrap();
+
+            let mut greeting = [0u8; 5];
+            client.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(&greeting, b"hello");
+        }
+
+        /// Send the raw request through the proxy of the fake chrome and read the response.
+        async fn send(chrome: &FakeChrome, proxy_keys: &[&str], request: &str) -> String {
+            let server = Server::new(Config {
+                chrome_port: chrome.port,
+                proxy_keys: proxy_keys.iter().map(|key| key.to_string()).collect(),
+                ..Default::default()
+            });
+            let (mut client, stream) = tokio::io::duplex(64 * 1024);
+
+            tokio::spawn(forward_connection(server, stream, Instant::now()));
+
+            client.write_all(request.as_bytes()).await.unwrap();
+
+            let mut response = vec![];
+            client.read_to_end(&mut response).await.unwrap();
+
+            String::from_utf8_lossy(&response).into_owned()
+        }
+
+        #[tokio::test]
+        async fn bearer_token_is_not_forwarded() {
+            let chrome = FakeChrome::start("a").await;
+            let response = send(
+                &chrome,
+                &["proxy"],
+                "GET /json/version HTTP/1.1\r\nHost: localhost\r\nauthorization: bearer proxy\r\nConnection: close\r\n\r\n",
+            )
+            .await;
+
+            assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+            assert_eq!(chrome.requests(), ["GET /json/version"]);
+        }
+
+        #[tokio::test]
+        async fn signed_url_is_forwarded_without_the_signature() {
+            let chrome = FakeChrome::start("a").await;
+            let path = auth::sign_url("proxy", "GET", "/json/version?a=1", u64::MAX / 2);
+            let response = send(
+                &chrome,
+                &["proxy"],
+                &format!(
+                    "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                    path
+                ),
+            )
+            .await;
+
+            assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+            assert_eq!(chrome.requests(), ["GET /json/version?a=1"]);
+        }
+
+        #[tokio::test]
+        async fn unauthorized_requests_are_rejected() {
+            let chrome = FakeChrome::start("a").await;
+
+            for request in [
+                "GET /json/version HTTP/1.1\r\nHost: localhost\r\n\r\n",
+                "GET /json/version HTTP/1.1\r\nAuthorization: Bearer other\r\n\r\n",
+                "GET /json/version?expires=1&signature=00 HTTP/1.1\r\n\r\n",
+                "not http\r\n\r\n",
+            ] {
+                let response = send(&chrome, &["proxy"], request).await;
+
+                assert!(response.starts_with("HTTP/1.1 401"), "{}", response);
+            }
+
+            assert!(chrome.requests().is_empty());
+        }
+
+        #[tokio::test]
+        async fn open_proxy_forwards_the_request_as_sent() {
+            let chrome = FakeChrome::start("a").await;
+            let response = send(
+                &chrome,
+                &[],
+                "GET /json/version?a=1&&signature=2 HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            )
+            .await;
+
+            assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+            assert_eq!(chrome.requests(), ["GET /json/version?a=1&&signature=2"]);
+        }
+    }
 }
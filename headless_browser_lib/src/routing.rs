@@ -0,0 +1,369 @@
+use crate::discovery;
+use crate::pool::Lease;
+use crate::Server;
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use hyper::Method;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// The time an unknown id is balanced without asking the instances again.
+const MISS_TTL: Duration = Duration::from_secs(5);
+
+/// The time the instances have to answer the lookup of an unknown id.
+const LOCATE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The unknown ids looked up at once. Other unknown ids are balanced without a lookup.
+const MAX_LOOKUPS: usize = 32;
+
+/// The unknown ids remembered before the expired ones are dropped.
+const MAX_MISSES: usize = 1024;
+
+/// The lookups of the ids without a known owner. Concurrent connections to an id share one lookup, the lookups at once
+/// are capped, and the unknown ids are remembered so clients cannot multiply the requests to the instances.
+#[derive(Debug, Default)]
+pub(crate) struct Locator {
+    /// When the unknown ids were looked up.
+    misses: DashMap<String, Instant>,
+    /// The lookups running by id. The sender drops once the lookup finishes.
+    inflight: DashMap<String, watch::Sender<()>>,
+}
+
+impl Locator {
+    /// Was the id looked up recently without an owner?
+    fn missed(&self, id: &str) -> bool {
+        self.misses
+            .get(id)
+            .is_some_and(|at| at.elapsed() < MISS_TTL)
+    }
+
+    /// Remember the id has no owner.
+    fn record_miss(&self, id: &str) {
+        if self.misses.len() >= MAX_MISSES {
+            self.misses.retain(|_, at| at.elapsed() < MISS_TTL);
+
+            if self.misses.len() >= MAX_MISSES {
+                self.misses.clear();
+            }
+        }
+
+        self.misses.insert(id.to_string(), Instant::now());
+    }
+}
+
+/// The lookup of an id. The lookup is removed when dropped so the waiting connections resume even if the client leaves.
+struct Lookup<'a> {
+    /// The locator running the lookup.
+    locator: &'a Locator,
+    /// The id looked up.
+    id: &'a str,
+}
+
+impl Drop for Lookup<'_> {
+    fn drop(&mut self) {
+        self.locator.inflight.remove(self.id);
+    }
+}
+
+/// The browser or target id of the devtools websocket path ex: `/devtools/page/{id}`.
+pub(crate) fn target_id(path: &str) -> Option<&str> {
+    let path = path.split('?').next().unwrap_or_default();
+    let path = path.strip_suffix('/').unwrap_or(path);
+    let id = path
+        .strip_prefix("/devtools/browser/")
+        .or_else(|| path.strip_prefix("/devtools/page/"))?;
+
+    (!id.is_empty() && !id.contains('/')).then_some(id)
+}
+
+/// The browser or target ids of the websocket urls in the chrome json body. The flag is set for the page targets.
+fn socket_ids(json: &serde_json::Value) -> Vec<(String, bool)> {
+    let targets = match json {
+        serde_json::Value::Array(targets) => targets.iter().collect(),
+        target => vec![target],
+    };
+
+    targets
+        .into_iter()
+        .filter_map(|target| {
+            let path = target
+                .get("webSocketDebuggerUrl")
+                .and_then(|url| url.as_str())
+                .and_then(|url| url.split_once("://"))
+                .and_then(|(_, rest)| rest.find('/').map(|index| &rest[index..]))?;
+
+            target_id(path).map(|id| (id.to_string(), path.starts_with("/devtools/page/")))
+        })
+        .collect()
+}
+
+/// The browser or target ids of the chrome json body.
+fn body_ids(body: &[u8]) -> Vec<(String, bool)> {
+    match serde_json::from_slice::<serde_json::Value>(body) {
+        Ok(json) => socket_ids(&json),
+        _ => vec![],
+    }
+}
+
+/// Remember the owner of the targets in the chrome json body of the instance on the port.
+pub(crate) fn learn(server: &Server, port: u32, body: &[u8]) {
+    for (id, page) in body_ids(body) {
+        server.locator.misses.remove(&id);
+        server.instances.record_target(&id, port, page);
+    }
+}
+
+/// Remember the owner of the targets in the `/json/list` body of the instance on the port and forget the closed targets.
+pub(crate) fn reconcile(server: &Server, port: u32, body: &[u8]) {
+    let json = match serde_json::from_slice::<serde_json::Value>(body) {
+        Ok(json @ serde_json::Value::Array(_)) => json,
+        _ => return,
+    };
+
+    let ids = socket_ids(&json);
+
+    for (id, page) in &ids {
+        server.locator.misses.remove(id);
+        server.instances.record_target(id, port, *page);
+    }
+
+    let listed: Vec<String> = ids.into_iter().map(|(id, _)| id).collect();
+
+    server.instances.retain_targets(port, &listed);
+}
+
+/// Forget the owner of the target closed with `/json/close/{id}`.
+pub(crate) fn forget(server: &Server, path: &str) {
+    if let Some(id) = path.strip_prefix("/json/close/") {
+        server.instances.forget_target(id);
+    }
+}
+
+/// Ask the instances at once for the browser and targets until the owner of the id is known or the locate timeout passes.
+/// The ids looked up recently without an owner are skipped and connections to an id being looked up wait on that lookup.
+async fn locate(server: &Server, id: &str) {
+    let locator = &server.locator;
+
+    if locator.missed(id) {
+        return;
+    }
+
+    if locator.inflight.len() >= MAX_LOOKUPS && !locator.inflight.contains_key(id) {
+        return;
+    }
+
+    let lookup = match locator.inflight.entry(id.to_string()) {
+        Entry::Occupied(entry) => {
+            let mut done = entry.get().subscribe();
+            drop(entry);
+            let _ = tokio::time::timeout(LOCATE_TIMEOUT, done.changed()).await;
+            return;
+        }
+        Entry::Vacant(entry) => {
+            entry.insert(watch::channel(()).0);
+            Lookup { locator, id }
+        }
+    };
+
+    // a lookup finished before this one started.
+    if server.instances.target_owner(id).is_some() || locator.missed(id) {
+        return;
+    }
+
+    let mut fetches: FuturesUnordered<_> = server
+        .instances
+        .instances()
+        .into_iter()
+        .map(|instance| async move {
+            let (version, list) = futures_util::future::join(
+                discovery::fetch(server, instance.port, &Method::GET, "/json/version"),
+                discovery::fetch(server, instance.port, &Method::GET, "/json/list"),
+            )
+            .await;
+
+            if let Some(reply) = version.filter(|reply| reply.status.is_success()) {
+                learn(server, instance.port, &reply.body);
+            }
+            if let Some(reply) = list.filter(|reply| reply.status.is_success()) {
+                reconcile(server, instance.port, &reply.body);
+            }
+        })
+        .collect();
+
+    let search = async {
+        while fetches.next().await.is_some() {
+            if server.instances.target_owner(id).is_some() {
+                break;
+            }
+        }
+    };
+
+    let _ = tokio::time::timeout(LOCATE_TIMEOUT, search).await;
+
+    if server.instances.target_owner(id).is_none() {
+        locator.record_miss(id);
+    }
+
+    drop(lookup);
+}
+
+/// Lease the instance owning the browser or target of the path. Other paths and unknown ids are balanced.
+pub(crate) async fn acquire(server: &Server, path: &str) -> Option<Lease> {
+    // a single instance owns every target.
+    if let Some(id) = target_id(path).filter(|_| server.instances.len() > 1) {
+        if server.instances.target_owner(id).is_none() {
+            locate(server, id).await;
+        }
+
+        if let Some(lease) = server
+            .instances
+            .target_owner(id)
+            .and_then(|owner| server.instances.acquire_instance(owner.pid))
+        {
+            server.metrics.routes_owner.inc();
+            return Some(lease);
+        }
+
+        server.metrics.routes_balanced.inc();
+        tracing::debug!("No instance owns {}. Balancing the connection.", id);
+    }
+
+    server.instances.acquire()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::testing::{hung_port, FakeChrome};
+
+    /// The server balancing the instances on the ports.
+    fn server(ports: &[u32]) -> Server {
+        let server = Server::new(Config::default());
+
+        for (pid, port) in ports.iter().enumerate() {
+            server.instances.insert(
+                pid as u32 + 1,
+                *port,
+                Default::default(),
+                Default::default(),
+                None,
+            );
+            server.instances.mark_running(*port);
+        }
+
+        server
+    }
+
+    /// The requests of the lookups received by the chrome.
+    fn lookups(chrome: &FakeChrome) -> usize {
+        chrome
+            .requests()
+            .iter()
+            .filter(|request| *request == "GET /json/version" || *request == "GET /json/list")
+            .count()
+    }
+
+    #[test]
+    fn target_id_of_browser_and_page_paths() {
+        assert_eq!(target_id("/devtools/browser/abc-123"), Some("abc-123"));
+        assert_eq!(target_id("/devtools/page/ABC123"), Some("ABC123"));
+    }
+
+    #[test]
+    fn target_id_ignores_query_and_trailing_slash() {
+        assert_eq!(target_id("/devtools/page/abc?token=1"), Some("abc"));
+        assert_eq!(target_id("/devtools/browser/abc/"), Some("abc"));
+        assert_eq!(target_id("/devtools/page/abc/?token=1"), Some("abc"));
+    }
+
+    #[test]
+    fn target_id_rejects_other_paths() {
+        assert_eq!(target_id("/devtools/page/"), None);
+        assert_eq!(target_id("/devtools/page"), None);
+        assert_eq!(target_id("/devtools/page/abc/def"), None);
+        assert_eq!(target_id("/devtools/worker/abc"), None);
+        assert_eq!(target_id("/json/version"), None);
+        assert_eq!(target_id("/"), None);
+    }
+
+    #[test]
+    fn socket_ids_flag_page_targets() {
+        let body = br#"[
+            {"webSocketDebuggerUrl": "ws://127.0.0.1:9222/devtools/page/page-1"},
+            {"webSocketDebuggerUrl": "ws://127.0.0.1:9222/devtools/browser/browser-1"},
+            {"url": "about:blank"}
+        ]"#;
+
+        assert_eq!(
+            body_ids(body),
+            vec![
+                ("page-1".to_string(), true),
+                ("browser-1".to_string(), false)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn concurrent_connections_share_the_lookup() {
+        let (a, b) = (FakeChrome::start("a").await, FakeChrome::start("b").await);
+        let server = server(&[a.port, b.port]);
+        let path = format!("/devtools/page/{}", b.open());
+
+        let (first, second) = tokio::join!(acquire(&server, &path), acquire(&server, &path));
+
+        assert_eq!(first.unwrap().port, b.port);
+        assert_eq!(second.unwrap().port, b.port);
+        assert!(lookups(&a) <= 2);
+        assert_eq!(lookups(&b), 2);
+        assert!(server.locator.inflight.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unknown_ids_are_not_looked_up_again() {
+        let chrome = FakeChrome::start("a").await;
+        let server = server(&[chrome.port, hung_port().await]);
+
+        let start = Instant::now();
+        assert!(acquire(&server, "/devtools/page/missing").await.is_some());
+        assert!(start.elapsed() < LOCATE_TIMEOUT + Duration::from_secs(1));
+        assert_eq!(lookups(&chrome), 2);
+
+        assert!(acquire(&server, "/devtools/page/missing").await.is_some());
+        assert_eq!(lookups(&chrome), 2);
+        assert_eq!(server.metrics.routes_balanced.get(), 2);
+    }
+
+    #[tokio::test]
+    async fn a_hung_instance_does_not_delay_the_owner() {
+        let chrome = FakeChrome::start("a").await;
+        let server = server(&[hung_port().await, chrome.port]);
+        let path = format!("/devtools/page/{}", chrome.open());
+
+        let start = Instant::now();
+        let lease = acquire(&server, &path).await.unwrap();
+
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(lease.port, chrome.port);
+        assert_eq!(server.metrics.routes_owner.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn browser_ids_route_to_the_issuing_instance() {
+        let (a, b) = (FakeChrome::start("a").await, FakeChrome::start("b").await);
+        let server = server(&[a.port, b.port]);
+        let version = discovery::fetch(&server, b.port, &Method::GET, "/json/version")
+            .await
+            .unwrap();
+
+        learn(&server, b.port, &version.body);
+
+        let path = format!("/devtools/browser/{}", b.browser_id());
+        let lease = acquire(&server, &path).await.unwrap();
+
+        assert_eq!(lease.port, b.port);
+        assert_eq!(lookups(&a), 0);
+        assert_eq!(server.metrics.routes_owner.get(), 1);
+    }
+}
@@ -0,0 +1,1309 @@
+use crate::config::{Config, ConfigError};
+use crate::forwarded;
+use crate::launch::LaunchOptions;
+use crate::metrics::Metrics;
+use crate::modify::Advertised;
+use crate::pool::{Instance, InstanceState, Pool, Restarts};
+use crate::routing::Locator;
+use crate::tls::Tls;
+use crate::usage::{InstanceUsage, Sampler};
+use crate::{health, request_handler, shutdown, supervisor, terminate, ForkError, ShutdownReport};
+use dashmap::DashMap;
+use hyper::body::Bytes;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::HeaderMap;
+use hyper_util::rt::TokioIo;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+
+/// The time between the recycle checks when the usage is not sampled.
+const RECYCLE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The time the /json/version response is cached.
+pub(crate) const TEN_SECONDS: Duration = Duration::from_secs(10);
+
+/// A chrome server. The server owns the instances launched and the management and proxy listeners.
+/// Clones share the same state.
+#[derive(Debug, Clone)]
+pub struct Server {
+    /// The shared state.
+    state: Arc<ServerState>,
+}
+
+/// The state of a server.
+#[derive(Debug)]
+pub struct ServerState {
+    /// The server config.
+    pub(crate) config: Config,
+    /// The chrome launch path.
+    pub(crate) chrome_path: String,
+    /// Is a lightpanda instance?
+    pub(crate) light_panda: bool,
+    /// The chrome instances launched.
+    pub(crate) instances: Pool,
+    /// Is the instance healthy? Written by the health probes or by the /json/version requests without the probes.
+    pub(crate) healthy: AtomicBool,
+    /// Did the crash loop limit stop respawning an instance? Cleared once an instance spawns.
+    pub(crate) crash_looped: AtomicBool,
+    /// Can the /json/version response be cached?
+    pub(crate) cacheable: AtomicBool,
+    /// The last cache date period.
+    pub(crate) last_cache: AtomicU64,
+    /// The cached /json/version responses by instance port.
+    pub(crate) version_cache: DashMap<u32, (Instant, Bytes)>,
+    /// The server metrics.
+    pub(crate) metrics: Metrics,
+    /// The TLS termination of the listeners.
+    pub(crate) tls: Option<Tls>,
+    /// The lookups of the browser and target ids without a known owner.
+    pub(crate) locator: Locator,
+    /// The resource usage sampler.
+    sampler: std::sync::Mutex<Sampler>,
+    /// The last resource usage sample.
+    usage: std::sync::RwLock<Vec<InstanceUsage>>,
+    /// Signals the server to stop.
+    stop: watch::Sender<bool>,
+}
+
+impl Deref for Server {
+    type Target = ServerState;
+
+    fn deref(&self) -> &Self::Target {
+        &self.state
+    }
+}
+
+impl ServerState {
+    /// The server config.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// The chrome launch path.
+    pub fn chrome_path(&self) -> &str {
+        &self.chrome_path
+    }
+
+    /// The chrome instances launched.
+    pub fn instances(&self) -> &Pool {
+        &self.instances
+    }
+
+    /// The server metrics.
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// The last resource usage sample of the instances.
+    pub fn usage(&self) -> Vec<InstanceUsage> {
+        self.usage
+            .read()
+            .map(|usage| usage.clone())
+            .unwrap_or_default()
+    }
+
+    /// Is the instance healthy?
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Can an instance take connections? Without the health probes an instance is ready once the debugging port accepted a connection.
+    pub fn is_ready(&self) -> bool {
+        let probes = self.config.health_interval > 0;
+
+        self.instances.instances().iter().any(|i| {
+            i.state != InstanceState::Draining
+                && if probes {
+                    i.probed && i.probe_failures < self.config.ready_failure_threshold
+                } else {
+                    i.state == InstanceState::Running && i.healthy
+                }
+        })
+    }
+
+    /// Is the server live? False once every instance failed the probes past the liveness threshold. Without instances the
+    /// server is live unless the crash loop limit emptied the pool, so instances shutdown on purpose do not restart the server.
+    pub fn is_live(&self) -> bool {
+        let instances = self.instances.instances();
+
+        if instances.is_empty() {
+            return !self.crash_looped.load(Ordering::Relaxed);
+        }
+
+        instances
+            .iter()
+            .any(|i| i.probe_failures < self.config.live_failure_threshold)
+    }
+
+    /// The proxy entry port.
+    pub(crate) fn entry_port(&self) -> u32 {
+        self.config.proxy_port().into()
+    }
+
+    /// The external address of the /json/version urls for the request from the peer. None keeps the urls of chrome.
+    pub(crate) fn advertised(&self, headers: &HeaderMap, peer: IpAddr) -> Option<Advertised> {
+        let config = &self.config;
+
+        if config.hostname.is_none()
+            && config.advertised_port.is_none()
+            && config.advertised_scheme.is_none()
+            && self.tls.is_none()
+            && !config.advertise_request_host
+        {
+            return None;
+        }
+
+        // the instance port serves plain websockets so the urls point at the proxy.
+        let mut advertised = Advertised {
+            host: config.hostname.clone(),
+            port: config.advertised_port.unwrap_or(config.proxy_port()),
+            secure: match config.advertised_scheme.as_deref() {
+                Some(scheme) => scheme == "wss",
+                _ => self.tls.is_some(),
+            },
+        };
+
+        if config.advertise_request_host {
+            forwarded::apply(&mut advertised, config, headers, peer);
+        }
+
+        Some(advertised)
+    }
+}
+
+/// Stops a running server.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    /// The server to stop.
+    server: Server,
+}
+
+impl ShutdownHandle {
+    /// Stop the server. The instances are shutdown before `run` returns.
+    pub fn shutdown(&self) {
+        self.server.stop.send_replace(true);
+    }
+}
+
+/// Build a server from a config.
+#[derive(Debug, Clone, Default)]
+pub struct ServerBuilder {
+    /// The server config.
+    config: Config,
+}
+
+impl ServerBuilder {
+    /// A new builder with the default config.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use the config.
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// The chrome executable.
+    pub fn chrome_path(mut self, chrome_path: impl Into<String>) -> Self {
+        self.config.chrome_path = Some(chrome_path.into());
+        self
+    }
+
+    /// The chrome remote debugging port.
+    pub fn chrome_port(mut self, port: u32) -> Self {
+        self.config.chrome_port = port;
+        self
+    }
+
+    /// The management server port.
+    pub fn server_port(mut self, port: u16) -> Self {
+        self.config.server_port = port;
+        self
+    }
+
+    /// The proxy entry port.
+    pub fn proxy_port(mut self, port: u16) -> Self {
+        self.config.proxy_port = Some(port);
+        self
+    }
+
+    /// Launch chrome on startup.
+    pub fn init(mut self, init: bool) -> Self {
+        self.config.init = init;
+        self
+    }
+
+    /// Validate the config and build the server.
+    pub fn build(self) -> Result<Server, ConfigError> {
+        self.config.validate()?;
+        Ok(Server::new(self.config))
+    }
+}
+
+impl Server {
+    /// A builder for the server.
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::new()
+    }
+
+    /// A new server with the config. The config is not validated.
+    pub fn new(config: Config) -> Self {
+        let chrome_path = crate::conf::chrome_path(&config);
+        let instances = Pool::new(
+            config.port_range(),
+            &[config.proxy_port().into()],
+            config.load_balancer,
+        );
+
+        Self {
+            state: Arc::new(ServerState {
+                light_panda: crate::conf::is_light_panda(&chrome_path),
+                chrome_path,
+                instances,
+                healthy: AtomicBool::new(true),
+                crash_looped: AtomicBool::new(false),
+                cacheable: AtomicBool::new(true),
+                last_cache: AtomicU64::new(0),
+                version_cache: DashMap::new(),
+                metrics: Metrics::default(),
+                tls: Tls::new(&config),
+                locator: Locator::default(),
+                sampler: Default::default(),
+                usage: Default::default(),
+                stop: watch::channel(false).0,
+                config,
+            }),
+        }
+    }
+
+    /// A handle to stop the server.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            server: self.clone(),
+        }
+    }
+
+    /// Launch chrome, start the server, and proxy for management. Runs until ctrl-c or the shutdown handle is used and
+    /// shuts down the instances before returning.
+    pub async fn run(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if self.config.init {
+            if let Err(e) = self.try_fork(Some(self.config.chrome_port)) {
+                tracing::error!("Failed to fork chrome: {}", e);
+            }
+        }
+
+        let addr = SocketAddr::new(
+            std::net::IpAddr::V4(std::net::Ipv4Addr::new(0, 0, 0, 0)),
+            self.config.server_port,
+        );
+
+        if let Some(tls) = &self.tls {
+            tls.load()?;
+        }
+
+        let listener = TcpListener::bind(addr).await?;
+
+        let server = self.clone();
+
+        let make_svc = async move {
+            let builder_options = Arc::new(
+                http1::Builder::new()
+                    .preserve_header_case(true)
+                    .title_case_headers(true)
+                    .header_read_timeout(None)
+                    .half_close(true)
+                    .auto_date_header(false)
+                    .to_owned(),
+            );
+
+            loop {
+                if let Ok((tcp, peer)) = listener.accept().await {
+                    let builder_options = builder_options.clone();
+                    let server = server.clone();
+
+                    tokio::task::spawn(async move {
+                        let service =
+                            service_fn(|req| request_handler(server.clone(), peer.ip(), req));
+
+                        let served = match &server.tls {
+                            Some(tls) => match tls.accept(tcp).await {
+                                Ok(stream) => {
+                                    builder_options
+                                        .serve_connection(TokioIo::new(stream), service)
+                                        .await
+                                }
+                                Err(e) => {
+                                    tracing::debug!("TLS handshake failed: {}", e);
+                                    Ok(())
+                                }
+                            },
+                            _ => {
+                                builder_options
+                                    .serve_connection(TokioIo::new(tcp), service)
+                                    .await
+                            }
+                        };
+
+                        if let Err(err) = served {
+                            eprintln!("Error serving connection: {:?}", err);
+                        }
+                    });
+                }
+            }
+        };
+
+        println!(
+            "Chrome server running on {}:{}",
+            if self.config.chrome_address.is_empty() {
+                "localhost"
+            } else {
+                &self.config.chrome_address
+            },
+            self.config.server_port
+        );
+
+        let mut stop = self.stop.subscribe();
+
+        let result = tokio::select! {
+            _ = make_svc => Ok(()),
+            result = crate::proxy::proxy::run_proxy(self.clone()) => result.map_err(Into::into),
+            _ = tokio::signal::ctrl_c() => Ok(()),
+            _ = stop.wait_for(|stop| *stop) => Ok(()),
+            _ = self.sample_usage_interval() => Ok(()),
+            _ = self.probe_interval() => Ok(()),
+        };
+
+        // the instances lead their own process groups and do not receive the terminal signals.
+        self.shutdown_instances().await;
+
+        result
+    }
+
+    /// Sample the resource usage of the instances.
+    pub async fn sample_usage(&self) -> Vec<InstanceUsage> {
+        let server = self.clone();
+
+        let usage = tokio::task::spawn_blocking(move || {
+            let instances = server.instances.instances();
+
+            match server.sampler.lock() {
+                Ok(mut sampler) => sampler.sample(&instances),
+                _ => vec![],
+            }
+        })
+        .await
+        .unwrap_or_default();
+
+        if let Ok(mut last) = self.usage.write() {
+            last.clone_from(&usage);
+        }
+
+        usage
+    }
+
+    /// Sample the resource usage and recycle the instances on the configured interval.
+    async fn sample_usage_interval(&self) {
+        let recycle = self.config.recycle_sessions > 0 || self.config.recycle_uptime > 0;
+
+        let period = match self.config.usage_interval {
+            0 if recycle => RECYCLE_INTERVAL,
+            0 => return std::future::pending().await,
+            interval => Duration::from_secs(interval),
+        };
+
+        let mut interval = tokio::time::interval(period);
+
+        loop {
+            interval.tick().await;
+
+            let usage = if self.config.usage_interval > 0 {
+                self.sample_usage().await
+            } else {
+                vec![]
+            };
+
+            self.recycle(&usage);
+        }
+    }
+
+    /// Probe the health of the instances on the configured interval.
+    async fn probe_interval(&self) {
+        if self.config.health_interval == 0 {
+            return std::future::pending().await;
+        }
+
+        let mut interval = tokio::time::interval(Duration::from_secs(self.config.health_interval));
+
+        loop {
+            interval.tick().await;
+            self.probe_instances().await;
+        }
+    }
+
+    /// Probe the instances concurrently and update the health. Draining instances are skipped.
+    pub async fn probe_instances(&self) {
+        let timeout = Duration::from_millis(self.config.health_timeout);
+
+        let probes: Vec<_> = self
+            .instances
+            .instances()
+            .into_iter()
+            .filter(|i| i.state != InstanceState::Draining)
+            .map(|instance| {
+                let server = self.clone();
+
+                tokio::spawn(async move {
+                    let threshold = server.config.ready_failure_threshold;
+
+                    match health::probe(instance.port, timeout).await {
+                        Ok(browser) => {
+                            server
+                                .instances
+                                .record_probe(instance.pid, Some(&browser), threshold);
+                        }
+                        Err(e) => {
+                            let failures =
+                                server.instances.record_probe(instance.pid, None, threshold);
+
+                            tracing::warn!(
+                                "Health probe of chrome PID: {} failed {} times: {}",
+                                instance.pid,
+                                failures,
+                                e
+                            );
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for probe in probes {
+            let _ = probe.await;
+        }
+
+        self.healthy.store(self.is_ready(), Ordering::Relaxed);
+    }
+
+    /// Fork a chrome process. The debugging port is allocated from the pool when not provided. The process is supervised on
+    /// the tokio runtime of the caller so the fork fails outside of a runtime.
+    pub fn try_fork(&self, port: Option<u32>) -> Result<Instance, ForkError> {
+        self.try_fork_with(port, LaunchOptions::default())
+    }
+
+    /// Fork a chrome process with the launch options. The debugging port is allocated from the pool when not provided. The
+    /// fork fails outside of a tokio runtime.
+    pub fn try_fork_with(
+        &self,
+        port: Option<u32>,
+        options: LaunchOptions,
+    ) -> Result<Instance, ForkError> {
+        options.validate(&self.config).map_err(ForkError::Invalid)?;
+
+        // chrome locks the profile so one instance can use a profile at a time.
+        if let Some(dir) = options.profile_dir(&self.config) {
+            if self.instances.profile_in_use(&dir) {
+                return Err(ForkError::ProfileInUse(dir));
+            }
+        }
+
+        let port = match self.instances.allocate_port(port) {
+            Some(port) => port,
+            _ => {
+                return Err(match port {
+                    Some(port) => ForkError::PortInUse(port),
+                    _ => ForkError::NoPortAvailable,
+                })
+            }
+        };
+
+        let pid = self
+            .spawn_instance(port, Default::default(), options)
+            .map_err(ForkError::Spawn)?;
+
+        self.metrics.forks.inc();
+
+        self.instances
+            .get(pid)
+            .ok_or_else(|| ForkError::Spawn(std::io::Error::other("chrome exited on startup")))
+    }
+
+    /// Fork a chrome process. The debugging port is allocated from the pool when not provided. Returns the pid or 0 on failure,
+    /// including outside of a tokio runtime.
+    pub fn fork(&self, port: Option<u32>) -> String {
+        match self.try_fork(port) {
+            Ok(instance) => instance.pid.to_string(),
+            Err(e) => {
+                tracing::error!("Failed to fork chrome: {}", e);
+                "0".into()
+            }
+        }
+    }
+
+    /// Spawn chrome on the reserved port and supervise the process.
+    pub(crate) fn spawn_instance(
+        &self,
+        port: u32,
+        restarts: Restarts,
+        options: LaunchOptions,
+    ) -> std::io::Result<u32> {
+        // the process and the supervisor need the runtime.
+        if tokio::runtime::Handle::try_current().is_err() {
+            self.instances.release_port(port);
+            return Err(std::io::Error::other(
+                "forking chrome requires a tokio runtime",
+            ));
+        }
+
+        let mut command = tokio::process::Command::new(&self.chrome_path);
+
+        // lead a new process group so the renderer and gpu processes are signaled with chrome.
+        #[cfg(unix)]
+        command.process_group(0);
+
+        let mut profile = Profile::default();
+
+        if self.light_panda {
+            command
+                .args(["--port", &port.to_string()])
+                .args(["--host", "0.0.0.0"]);
+        } else {
+            let mut args =
+                options.apply(&self.config, crate::conf::chrome_args(&self.config, port));
+
+            profile = match Profile::new(&self.config, port, &options, &args) {
+                Ok(profile) => profile,
+                Err(e) => {
+                    self.instances.release_port(port);
+                    return Err(e);
+                }
+            };
+
+            if let Some(dir) = &profile.dir {
+                args.retain(|arg| crate::conf::flag_name(arg) != "--user-data-dir");
+                args.push(format!("--user-data-dir={}", dir.display()));
+            }
+
+            command.args(args).envs(options.envs());
+        }
+
+        let child = command.spawn().and_then(|child| match child.id() {
+            Some(id) => Ok((child, id)),
+            _ => Err(std::io::Error::other("chrome exited on startup")),
+        });
+
+        match child {
+            Ok((child, id)) => {
+                tracing::info!("Chrome PID: {}", id);
+                self.crash_looped.store(false, Ordering::Relaxed);
+                self.instances
+                    .insert(id, port, restarts, options, profile.dir.clone());
+                supervisor::supervise(self.clone(), child, id, profile.temp_dir());
+                Ok(id)
+            }
+            Err(e) => {
+                tracing::error!("{} command didn't start {:?}", self.chrome_path, e);
+                self.instances.release_port(port);
+                if let Some(dir) = profile.temp_dir() {
+                    let _ = std::fs::remove_dir_all(dir);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Shutdown all the chrome instances launched. Waits briefly for the processes to be reaped and the temporary profiles removed.
+    pub async fn shutdown_instances(&self) {
+        let since = SystemTime::now();
+        let mut pids = vec![];
+
+        // remove before the kill so the supervisor does not respawn the instance.
+        for pid in self.instances.pids() {
+            if self.instances.remove(pid).is_some() {
+                if let Err(e) = shutdown(&pid) {
+                    tracing::warn!("Failed to shutdown chrome PID: {} {}", pid, e);
+                }
+                pids.push(pid);
+            }
+        }
+        self.instances.clear();
+        self.cacheable.store(false, Ordering::Relaxed);
+
+        let start = Instant::now();
+
+        for pid in pids {
+            let wait = Duration::from_secs(2).saturating_sub(start.elapsed());
+            supervisor::wait_for_exit(&self.instances, pid, since, wait).await;
+        }
+    }
+
+    /// Replace the instance with a new one on a fresh port launched with the same options. The old instance stops receiving new connections and is shutdown once the active connections close or the drain timeout passes.
+    /// Instances with a profile directory are replaced after the drain since the profile is locked.
+    /// Returns false if the instance is unknown or already draining.
+    pub fn drain_and_replace(&self, pid: u32) -> bool {
+        let instance = match self.instances.get(pid) {
+            Some(instance) if self.instances.begin_drain(pid) => instance,
+            _ => return false,
+        };
+
+        let options = instance.options;
+        let server = self.clone();
+
+        if options.profile_dir(&self.config).is_some() {
+            tracing::info!("Draining chrome PID: {} before the replacement", pid);
+
+            tokio::spawn(async move {
+                server.drain(pid).await;
+                server.replace(pid, options);
+            });
+        } else {
+            self.replace(pid, options);
+            tokio::spawn(async move { server.drain(pid).await });
+        }
+
+        true
+    }
+
+    /// Fork the replacement of the instance.
+    fn replace(&self, pid: u32, options: LaunchOptions) {
+        match self.try_fork_with(None, options) {
+            Ok(replacement) => {
+                tracing::info!("Chrome PID: {} replaced by {}", pid, replacement.pid)
+            }
+            Err(e) => tracing::error!("Failed to replace chrome PID: {} {}", pid, e),
+        }
+    }
+
+    /// Drain and replace the instances past the recycle limits of the config.
+    pub fn recycle(&self, usage: &[InstanceUsage]) {
+        let config = &self.config;
+
+        for instance in self.instances.instances() {
+            if instance.state == InstanceState::Draining {
+                continue;
+            }
+
+            let memory = usage
+                .iter()
+                .find(|u| u.pid == instance.pid)
+                .map_or(0, |u| u.memory_bytes);
+
+            let reason = if config.recycle_memory_mb > 0
+                && memory > config.recycle_memory_mb.saturating_mul(1024 * 1024)
+            {
+                self.metrics.recycles_memory.inc();
+                "memory"
+            } else if config.recycle_sessions > 0 && instance.sessions() >= config.recycle_sessions
+            {
+                self.metrics.recycles_sessions.inc();
+                "sessions"
+            } else if config.recycle_uptime > 0
+                && instance.started.elapsed() >= Duration::from_secs(config.recycle_uptime)
+            {
+                self.metrics.recycles_uptime.inc();
+                "uptime"
+            } else {
+                continue;
+            };
+
+            tracing::info!("Recycling chrome PID: {} for {}", instance.pid, reason);
+
+            self.drain_and_replace(instance.pid);
+        }
+    }
+
+    /// Wait for the instance connections to close and shutdown the instance.
+    async fn drain(&self, pid: u32) {
+        let start = Instant::now();
+        let drain_timeout = Duration::from_secs(self.config.drain_timeout);
+
+        while let Some(instance) = self.instances.get(pid) {
+            if instance.active_connections() == 0 {
+                break;
+            }
+            if start.elapsed() >= drain_timeout {
+                tracing::warn!(
+                    "Drain timeout for chrome PID: {} with {} active connections",
+                    pid,
+                    instance.active_connections()
+                );
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(250)).await;
+        }
+
+        self.shutdown_instance(pid).await;
+    }
+
+    /// Shutdown the instance by process id. The process is asked to terminate and killed if still running after the grace period.
+    /// Returns None if the process id is not an instance of the pool.
+    pub async fn shutdown_instance(&self, pid: u32) -> Option<ShutdownReport> {
+        let since = SystemTime::now();
+
+        // remove before the kill so the supervisor does not respawn the instance.
+        self.instances.remove(pid)?;
+        self.cacheable.store(false, Ordering::Relaxed);
+
+        if let Err(e) = terminate(&pid) {
+            tracing::warn!("Failed to terminate chrome PID: {} {}", pid, e);
+        }
+
+        let mut killed = false;
+        let grace = Duration::from_secs(self.config.shutdown_grace);
+        let mut exit = supervisor::wait_for_exit(&self.instances, pid, since, grace).await;
+
+        if exit.is_none() {
+            tracing::warn!(
+                "Chrome PID: {} did not terminate. Killing the process.",
+                pid
+            );
+            killed = true;
+            if let Err(e) = shutdown(&pid) {
+                tracing::warn!("Failed to kill chrome PID: {} {}", pid, e);
+            }
+            exit = supervisor::wait_for_exit(&self.instances, pid, since, Duration::from_secs(2))
+                .await;
+        }
+
+        Some(ShutdownReport {
+            pid,
+            exited: exit.is_some(),
+            killed,
+            code: exit.as_ref().and_then(|e| e.code),
+            signal: exit.as_ref().and_then(|e| e.signal),
+        })
+    }
+}
+
+/// The directory under the temp dir holding the temporary profiles.
+const TEMP_PROFILES: &str = "headless_browser";
+
+/// The profile directory of an instance.
+#[derive(Debug, Default)]
+struct Profile {
+    /// The profile directory. None when the directory is left to chrome.
+    dir: Option<PathBuf>,
+    /// Is the directory removed when the instance exits?
+    temporary: bool,
+}
+
+impl Profile {
+    /// The profile of the instance. A `--user-data-dir` of the chrome flags is shared by every instance so the instance
+    /// gets a subdirectory by port, else the named profile or a new temporary directory is created.
+    fn new(
+        config: &Config,
+        port: u32,
+        options: &LaunchOptions,
+        args: &[String],
+    ) -> std::io::Result<Self> {
+        let shared = args
+            .iter()
+            .find(|arg| crate::conf::flag_name(arg) == "--user-data-dir")
+            .and_then(|arg| arg.split_once('='))
+            .map(|(_, dir)| PathBuf::from(dir));
+
+        let (dir, temporary) = match (shared, &options.profile) {
+            (Some(shared), _) => (shared.join(port.to_string()), false),
+            (_, Some(profile)) => (config.profiles_dir().join(profile), false),
+            _ => (
+                std::env::temp_dir().join(TEMP_PROFILES).join(format!(
+                    "{}-{:016x}",
+                    port,
+                    rand::random::<u64>()
+                )),
+                true,
+            ),
+        };
+
+        std::fs::create_dir_all(&dir)?;
+
+        Ok(Self {
+            dir: Some(dir),
+            temporary,
+        })
+    }
+
+    /// The directory to remove when the instance exits.
+    fn temp_dir(&self) -> Option<PathBuf> {
+        self.dir.clone().filter(|_| self.temporary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FakeChrome;
+
+    #[test]
+    fn empty_pool_is_live_unless_crash_looped() {
+        let server = Server::new(Config::default());
+        assert!(server.is_live());
+        assert!(!server.is_ready());
+
+        server.crash_looped.store(true, Ordering::Relaxed);
+        assert!(!server.is_live());
+    }
+
+    #[test]
+    fn live_until_every_instance_fails_the_threshold() {
+        let server = Server::new(Config::default());
+        let threshold = server.config.live_failure_threshold;
+
+        server
+            .instances
+            .insert(1, 9300, Restarts::default(), Default::default(), None);
+        server
+            .instances
+            .insert(2, 9301, Restarts::default(), Default::default(), None);
+
+        for _ in 0..threshold {
+            server.instances.record_probe(1, None, threshold);
+        }
+        assert!(server.is_live());
+
+        for _ in 0..threshold {
+            server.instances.record_probe(2, None, threshold);
+        }
+        assert!(!server.is_live());
+    }
+
+    #[test]
+    fn ready_without_probes_once_running_and_healthy() {
+        let server = Server::new(Config {
+            health_interval: 0,
+            ..Default::default()
+        });
+
+        server
+            .instances
+            .insert(1, 9300, Restarts::default(), Default::default(), None);
+        assert!(!server.is_ready());
+
+        server.instances.mark_running(9300);
+        assert!(server.is_ready());
+
+        server.instances.mark_healthy(9300, false);
+        assert!(!server.is_ready());
+    }
+
+    #[tokio::test]
+    async fn probes_mark_the_instances_ready() {
+        let chrome = FakeChrome::start("a").await;
+        let server = Server::new(Config::default());
+
+        server.instances.insert(
+            1,
+            chrome.port,
+            Restarts::default(),
+            Default::default(),
+            None,
+        );
+        assert!(!server.is_ready());
+
+        server.probe_instances().await;
+
+        let instance = server.instances.get(1).unwrap();
+        assert_eq!(instance.state, InstanceState::Running);
+        assert_eq!(instance.browser.as_deref(), Some("a"));
+        assert!(server.is_ready());
+        assert!(server.is_healthy());
+    }
+
+    #[tokio::test]
+    async fn failed_probes_cross_the_thresholds() {
+        let server = Server::new(Config {
+            ready_failure_threshold: 2,
+            live_failure_threshold: 3,
+            health_timeout: 500,
+            ..Default::default()
+        });
+
+        server.instances.insert(
+            1,
+            crate::testing::closed_port(),
+            Restarts::default(),
+            Default::default(),
+            None,
+        );
+        server.instances.record_probe(1, Some("a"), 2);
+
+        server.probe_instances().await;
+        assert!(server.is_ready());
+        assert!(server.is_healthy());
+
+        server.probe_instances().await;
+        assert!(!server.is_ready());
+        assert!(!server.is_healthy());
+        assert!(server.is_live());
+
+        server.probe_instances().await;
+        assert!(!server.is_live());
+        assert_eq!(server.instances.get(1).unwrap().probe_failures, 3);
+    }
+
+    #[tokio::test]
+    async fn draining_instances_are_not_probed() {
+        let server = Server::new(Config::default());
+
+        server.instances.insert(
+            1,
+            crate::testing::closed_port(),
+            Restarts::default(),
+            Default::default(),
+            None,
+        );
+        server.instances.begin_drain(1);
+
+        server.probe_instances().await;
+        assert_eq!(server.instances.get(1).unwrap().probe_failures, 0);
+    }
+
+    /// The server launching a sleep as chrome.
+    #[cfg(unix)]
+    fn sleeping(config: Config) -> Server {
+        Server::new(Config {
+            chrome_path: Some(crate::testing::script("exec sleep 30")),
+            health_interval: 0,
+            ..config
+        })
+    }
+
+    /// Wait until the condition holds or five seconds pass.
+    #[cfg(unix)]
+    async fn until(condition: impl Fn() -> bool) -> bool {
+        let start = Instant::now();
+
+        while !condition() {
+            if start.elapsed() >= Duration::from_secs(5) {
+                return false;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        true
+    }
+
+    /// The instances not draining.
+    #[cfg(unix)]
+    fn serving(server: &Server) -> Vec<Instance> {
+        server
+            .instances
+            .instances()
+            .into_iter()
+            .filter(|i| i.state != InstanceState::Draining)
+            .collect()
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn recycle_past_the_memory_limit() {
+        let server = sleeping(Config {
+            recycle_memory_mb: 64,
+            usage_interval: 5,
+            ..Default::default()
+        });
+        let instance = server.try_fork(None).unwrap();
+        let usage = |memory_bytes| InstanceUsage {
+            pid: instance.pid,
+            memory_bytes,
+            ..Default::default()
+        };
+
+        server.recycle(&[usage(64 * 1024 * 1024)]);
+        assert_eq!(server.metrics.recycles_memory.get(), 0);
+
+        server.recycle(&[usage(65 * 1024 * 1024)]);
+        assert_eq!(server.metrics.recycles_memory.get(), 1);
+
+        let replacement = serving(&server).remove(0);
+        assert_ne!(replacement.pid, instance.pid);
+        assert!(until(|| server.instances.get(instance.pid).is_none()).await);
+
+        server.shutdown_instances().await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn recycle_after_the_sessions() {
+        let server = sleeping(Config {
+            recycle_sessions: 2,
+            ..Default::default()
+        });
+        let instance = server.try_fork(None).unwrap();
+
+        server.instances.acquire().unwrap().accept();
+        server.recycle(&[]);
+        assert_eq!(server.metrics.recycles_sessions.get(), 0);
+
+        server.instances.acquire().unwrap().accept();
+        server.recycle(&[]);
+        assert_eq!(server.metrics.recycles_sessions.get(), 1);
+        assert_eq!(
+            server.instances.get(instance.pid).unwrap().state,
+            InstanceState::Draining
+        );
+        assert_eq!(serving(&server)[0].sessions(), 0);
+
+        server.shutdown_instances().await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn recycle_after_the_uptime() {
+        let server = sleeping(Config {
+            recycle_uptime: 1,
+            ..Default::default()
+        });
+        let instance = server.try_fork(None).unwrap();
+
+        server.recycle(&[]);
+        assert_eq!(server.metrics.recycles_uptime.get(), 0);
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        server.recycle(&[]);
+        assert_eq!(server.metrics.recycles_uptime.get(), 1);
+        assert_ne!(serving(&server)[0].pid, instance.pid);
+
+        // the draining instance is not recycled again.
+        server.recycle(&[]);
+        assert_eq!(server.metrics.recycles_uptime.get(), 1);
+
+        server.shutdown_instances().await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn profile_instances_are_replaced_after_the_drain() {
+        let profiles = std::env::temp_dir().join(format!(
+            "headless_browser_profiles_{:016x}",
+            rand::random::<u64>()
+        ));
+        let server = sleeping(Config {
+            recycle_sessions: 1,
+            profiles_dir: Some(profiles.display().to_string()),
+            ..Default::default()
+        });
+        let options = LaunchOptions {
+            profile: Some("kept".into()),
+            ..Default::default()
+        };
+        let instance = server.try_fork_with(None, options).unwrap();
+        let lease = server.instances.acquire().unwrap();
+        lease.accept();
+
+        server.recycle(&[]);
+
+        // the profile is locked until the drained instance exits so the pool has no instance to route to.
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(serving(&server).is_empty());
+        assert!(server.instances.acquire().is_none());
+
+        drop(lease);
+
+        assert!(until(|| serving(&server).len() == 1).await);
+        let replacement = serving(&server).remove(0);
+        assert_ne!(replacement.pid, instance.pid);
+        assert_eq!(replacement.user_data_dir, Some(profiles.join("kept")));
+        assert!(server.instances.get(instance.pid).is_none());
+
+        server.shutdown_instances().await;
+        let _ = std::fs::remove_dir_all(profiles);
+    }
+
+    #[test]
+    fn temporary_profiles_are_created_per_instance() {
+        let options = LaunchOptions::default();
+        let first = Profile::new(&Config::default(), 9300, &options, &[]).unwrap();
+        let second = Profile::new(&Config::default(), 9300, &options, &[]).unwrap();
+        let dir = first.temp_dir().unwrap();
+
+        assert!(dir.is_dir());
+        assert!(dir.starts_with(std::env::temp_dir().join(TEMP_PROFILES)));
+        assert!(dir
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with("9300-"));
+        assert_ne!(first.dir, second.dir);
+
+        for profile in [first, second] {
+            std::fs::remove_dir_all(profile.temp_dir().unwrap()).unwrap();
+        }
+    }
+
+    #[test]
+    fn named_and_shared_profiles_are_kept() {
+        let base = std::env::temp_dir().join(format!(
+            "headless_browser_profiles_{:016x}",
+            rand::random::<u64>()
+        ));
+        let config = Config {
+            profiles_dir: Some(base.join("named").display().to_string()),
+            ..Default::default()
+        };
+        let options = LaunchOptions {
+            profile: Some("kept".into()),
+            ..Default::default()
+        };
+
+        let named = Profile::new(&config, 9300, &options, &[]).unwrap();
+        assert_eq!(named.dir, Some(base.join("named/kept")));
+        assert!(named.temp_dir().is_none());
+
+        let args = [format!("--user-data-dir={}", base.join("shared").display())];
+        let first = Profile::new(&config, 9300, &options, &args).unwrap();
+        let second = Profile::new(&config, 9301, &options, &args).unwrap();
+
+        assert_eq!(first.dir, Some(base.join("shared/9300")));
+        assert_eq!(second.dir, Some(base.join("shared/9301")));
+        assert!(base.join("shared/9301").is_dir());
+        assert!(first.temp_dir().is_none());
+
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn named_profiles_outlive_the_instance() {
+        let profiles = std::env::temp_dir().join(format!(
+            "headless_browser_profiles_{:016x}",
+            rand::random::<u64>()
+        ));
+        let server = Server::new(Config {
+            chrome_path: Some(crate::testing::script("exit 3")),
+            crash_loop_limit: 0,
+            profiles_dir: Some(profiles.display().to_string()),
+            ..Default::default()
+        });
+        let options = LaunchOptions {
+            profile: Some("kept".into()),
+            ..Default::default()
+        };
+        let since = SystemTime::now();
+        let instance = server.try_fork_with(None, options).unwrap();
+
+        supervisor::wait_for_exit(
+            &server.instances,
+            instance.pid,
+            since,
+            Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+
+        assert!(profiles.join("kept").is_dir());
+        std::fs::remove_dir_all(profiles).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn drained_instances_stop_taking_connections() {
+        let server = sleeping(Config {
+            drain_timeout: 1,
+            ..Default::default()
+        });
+        let instance = server.try_fork(None).unwrap();
+        let lease = server.instances.acquire().unwrap();
+
+        assert!(server.drain_and_replace(instance.pid));
+        assert!(!server.drain_and_replace(instance.pid));
+
+        let replacement = serving(&server).remove(0);
+        assert_ne!(replacement.pid, instance.pid);
+
+        for _ in 0..4 {
+            assert_eq!(server.instances.acquire().unwrap().pid, replacement.pid);
+        }
+
+        // the connection held keeps the instance until the drain timeout.
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(server.instances.get(instance.pid).is_some());
+        assert!(until(|| server.instances.get(instance.pid).is_none()).await);
+        assert_eq!(lease.active_connections(), 1);
+
+        server.shutdown_instances().await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn idle_instances_drain_right_away() {
+        let server = sleeping(Config::default());
+        let instance = server.try_fork(None).unwrap();
+
+        assert!(server.drain_and_replace(instance.pid));
+        assert!(until(|| server.instances.get(instance.pid).is_none()).await);
+        assert_eq!(server.instances.len(), 1);
+
+        server.shutdown_instances().await;
+    }
+
+    #[test]
+    fn builder_sets_the_config() {
+        let server = Server::builder()
+            .chrome_path("/usr/bin/chromium")
+            .chrome_port(9333)
+            .server_port(7000)
+            .proxy_port(7001)
+            .init(false)
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            server.config.chrome_path.as_deref(),
+            Some("/usr/bin/chromium")
+        );
+        assert_eq!(server.config.chrome_port, 9333);
+        assert_eq!(server.config.server_port, 7000);
+        assert_eq!(server.config.proxy_port(), 7001);
+        assert!(!server.config.init);
+    }
+
+    #[test]
+    fn builder_rejects_an_invalid_config() {
+        assert!(Server::builder().chrome_port(0).build().is_err());
+        assert!(Server::builder().server_port(0).build().is_err());
+        assert!(Server::builder()
+            .chrome_port(9400)
+            .proxy_port(9400)
+            .build()
+            .is_err());
+        assert!(Server::builder()
+            .config(Config {
+                server_port: 7002,
+                ..Default::default()
+            })
+            .proxy_port(7002)
+            .build()
+            .is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn shutdown_handle_stops_the_run() {
+        let server_port = crate::testing::closed_port() as u16;
+        let proxy_port = crate::testing::closed_port() as u16;
+        let server = Server::builder()
+            .init(false)
+            .server_port(server_port)
+            .proxy_port(proxy_port)
+            .build()
+            .unwrap();
+        let handle = server.shutdown_handle();
+        let running = tokio::spawn(async move { server.run().await.is_ok() });
+
+        let listening = until(|| std::net::TcpStream::connect(("127.0.0.1", server_port)).is_ok());
+        assert!(listening.await);
+
+        handle.shutdown();
+
+        let stopped = tokio::time::timeout(Duration::from_secs(5), running).await;
+        assert!(stopped.unwrap().unwrap());
+    }
+}
@@ -0,0 +1,353 @@
+use crate::pool::{Exit, Instance, InstanceState, Pool, Restarts};
+use crate::Server;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use tokio::process::Child;
+
+/// The max delay between respawns.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Supervise the chrome process until it exits. The exit is reaped and recorded, the temporary profile is removed, and an instance
+/// that exited without being shutdown is respawned with backoff until the crash loop limit.
+pub(crate) fn supervise(server: Server, mut child: Child, pid: u32, temp_dir: Option<PathBuf>) {
+    tokio::spawn(async move {
+        let status = child.wait().await;
+
+        // renderer and gpu processes outliving chrome are left in the process group.
+        crate::kill_process_group(pid);
+
+        // the temporary profile is removed before the exit is recorded so waiting on the exit includes the cleanup.
+        if let Some(dir) = temp_dir {
+            let removed = tokio::task::spawn_blocking(move || std::fs::remove_dir_all(dir)).await;
+
+            if let Ok(Err(e)) = removed {
+                tracing::warn!("Failed to remove the profile of chrome PID: {} {}", pid, e);
+            }
+        }
+
+        // instances shutdown on purpose are removed from the pool before the kill.
+        let instance = server.instances.remove(pid);
+
+        let (code, signal) = match &status {
+            Ok(status) => (status.code(), exit_signal(status)),
+            Err(e) => {
+                tracing::error!("Failed to wait on chrome PID: {} {:?}", pid, e);
+                (None, None)
+            }
+        };
+
+        let exit = Exit {
+            pid,
+            port: instance.as_ref().map_or(0, |i| i.port),
+            code,
+            signal,
+            unexpected: instance.is_some(),
+            at: SystemTime::now(),
+        };
+
+        if exit.unexpected {
+            server.metrics.crashes.inc();
+            tracing::error!(
+                "Chrome PID: {} exited unexpectedly code: {:?} signal: {:?}",
+                pid,
+                code,
+                signal
+            );
+        } else {
+            tracing::info!("Chrome PID: {} exited", pid);
+        }
+
+        server.instances.record_exit(exit);
+
+        if let Some(instance) = instance {
+            // draining instances are already replaced.
+            if instance.state != InstanceState::Draining {
+                respawn(&server, instance).await;
+            }
+        }
+    });
+}
+
+/// Respawn the crashed instance after the backoff on the same port if possible.
+async fn respawn(server: &Server, instance: Instance) {
+    let config = server.config();
+
+    let consecutive = if instance.started.elapsed() >= Duration::from_secs(config.crash_loop_window)
+    {
+        0
+    } else {
+        instance.restarts.consecutive
+    };
+
+    if consecutive >= config.crash_loop_limit {
+        tracing::error!(
+            "Chrome crash loop detected after {} restarts. Not respawning PID: {}",
+            consecutive,
+            instance.pid
+        );
+        server
+            .crash_looped
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        return;
+    }
+
+    let generation = server.instances.generation();
+
+    // hold the port during the backoff.
+    let port = match server
+        .instances
+        .allocate_port(Some(instance.port))
+        .or_else(|| server.instances.allocate_port(None))
+    {
+        Some(port) => port,
+        _ => {
+            tracing::error!("No remote debugging port available to respawn chrome.");
+            return;
+        }
+    };
+
+    tokio::time::sleep(backoff(
+        Duration::from_millis(config.restart_backoff),
+        consecutive,
+    ))
+    .await;
+
+    // the instances were shutdown during the backoff.
+    if generation != server.instances.generation() {
+        server.instances.release_port(port);
+        return;
+    }
+
+    let restarts = Restarts {
+        total: instance.restarts.total + 1,
+        consecutive: consecutive + 1,
+    };
+
+    match server.spawn_instance(port, restarts, instance.options.clone()) {
+        Ok(pid) => {
+            server.metrics.restarts.inc();
+            tracing::info!("Respawned chrome PID: {} as {}", instance.pid, pid)
+        }
+        Err(e) => tracing::error!("Failed to respawn chrome PID: {} {:?}", instance.pid, e),
+    }
+}
+
+/// The delay before the respawn after the consecutive restarts. The delay doubles with every restart up to the max.
+fn backoff(restart_backoff: Duration, consecutive: u32) -> Duration {
+    restart_backoff
+        .saturating_mul(2u32.saturating_pow(consecutive))
+        .min(MAX_BACKOFF)
+}
+
+/// Wait for the supervisor to reap the process exit since the time provided.
+pub(crate) async fn wait_for_exit(
+    instances: &Pool,
+    pid: u32,
+    since: SystemTime,
+    wait: Duration,
+) -> Option<Exit> {
+    let start = std::time::Instant::now();
+
+    loop {
+        let exit = instances
+            .exits()
+            .into_iter()
+            .rev()
+            .find(|e| e.pid == pid && e.at >= since);
+
+        if exit.is_some() || start.elapsed() >= wait {
+            return exit;
+        }
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+/// The signal that terminated the process.
+#[cfg(unix)]
+fn exit_signal(status: &std::process::ExitStatus) -> Option<i32> {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal()
+}
+
+/// The signal that terminated the process.
+#[cfg(not(unix))]
+fn exit_signal(_status: &std::process::ExitStatus) -> Option<i32> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::ForkError;
+    use std::sync::atomic::Ordering;
+
+    /// The server launching the script as chrome.
+    #[cfg(unix)]
+    fn server(body: &str, config: Config) -> Server {
+        Server::new(Config {
+            chrome_path: Some(crate::testing::script(body)),
+            health_interval: 0,
+            ..config
+        })
+    }
+
+    /// Wait until the condition holds or the time passes.
+    #[cfg(unix)]
+    async fn until(wait: Duration, condition: impl Fn() -> bool) -> bool {
+        let start = std::time::Instant::now();
+
+        while !condition() {
+            if start.elapsed() >= wait {
+                return false;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        true
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_max() {
+        let base = Duration::from_millis(500);
+
+        assert_eq!(backoff(base, 0), base);
+        assert_eq!(backoff(base, 1), Duration::from_secs(1));
+        assert_eq!(backoff(base, 3), Duration::from_secs(4));
+        assert_eq!(backoff(base, 6), MAX_BACKOFF);
+        assert_eq!(backoff(base, 64), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn fork_fails_outside_of_a_runtime() {
+        let server = Server::new(Config {
+            chrome_path: Some("/bin/true".into()),
+            ..Default::default()
+        });
+
+        assert!(matches!(server.try_fork(None), Err(ForkError::Spawn(_))));
+        assert_eq!(server.fork(None), "0");
+        assert!(server.instances.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn supervise_records_the_exit_and_removes_the_profile() {
+        let server = server(
+            "exit 3",
+            Config {
+                crash_loop_limit: 0,
+                ..Default::default()
+            },
+        );
+        let since = SystemTime::now();
+        let instance = server.try_fork(None).unwrap();
+        let profile = instance.user_data_dir.clone().unwrap();
+        assert!(profile.exists());
+
+        let exit = wait_for_exit(
+            &server.instances,
+            instance.pid,
+            since,
+            Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(exit.code, Some(3));
+        assert_eq!(exit.port, instance.port);
+        assert!(exit.unexpected);
+        assert!(!profile.exists());
+        assert!(server.instances.is_empty());
+        assert_eq!(server.metrics.crashes.get(), 1);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn respawns_until_the_crash_loop_limit() {
+        let server = server(
+            "exit 3",
+            Config {
+                crash_loop_limit: 2,
+                restart_backoff: 10,
+                ..Default::default()
+            },
+        );
+        server.try_fork(None).unwrap();
+
+        assert!(
+            until(Duration::from_secs(5), || server
+                .crash_looped
+                .load(Ordering::Relaxed))
+            .await
+        );
+        assert_eq!(server.metrics.restarts.get(), 2);
+        assert_eq!(server.instances.exits().len(), 3);
+        assert!(server.instances.is_empty());
+        assert!(!server.is_live());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn respawned_instances_count_the_restarts() {
+        let server = server(
+            "exec sleep 30",
+            Config {
+                restart_backoff: 10,
+                ..Default::default()
+            },
+        );
+        let instance = server.try_fork(None).unwrap();
+
+        crate::kill_process_group(instance.pid);
+
+        assert!(
+            until(Duration::from_secs(5), || server
+                .instances
+                .instances()
+                .iter()
+                .any(|i| i.pid != instance.pid))
+            .await
+        );
+
+        let respawned = server.instances.instances().remove(0);
+        assert_eq!(respawned.port, instance.port);
+        assert_eq!(respawned.restarts.total, 1);
+        assert_eq!(respawned.restarts.consecutive, 1);
+
+        server.shutdown_instances().await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn shutdown_during_the_backoff_cancels_the_respawn() {
+        let server = server(
+            "exit 3",
+            Config {
+                restart_backoff: 300,
+                ..Default::default()
+            },
+        );
+        let since = SystemTime::now();
+        let instance = server.try_fork(None).unwrap();
+
+        wait_for_exit(
+            &server.instances,
+            instance.pid,
+            since,
+            Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+        server.shutdown_instances().await;
+        tokio::time::sleep(Duration::from_millis(600)).await;
+
+        assert!(server.instances.is_empty());
+        assert_eq!(server.metrics.restarts.get(), 0);
+        assert_eq!(
+            server.instances.allocate_port(Some(instance.port)),
+            Some(instance.port)
+        );
+    }
+}
@@ -0,0 +1,257 @@
+use futures_util::{SinkExt, StreamExt};
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, UPGRADE};
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::{Role, WebSocketConfig};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+/// A fake chrome answering the DevTools HTTP endpoints. The websockets answer `Browser.getVersion` with the browser name
+/// and echo the other messages.
+#[derive(Debug, Clone)]
+pub(crate) struct FakeChrome {
+    /// The remote debugging port.
+    pub port: u32,
+    /// The browser name reported by `/json/version`.
+    pub name: String,
+    /// The requests received ex: `GET /json/version`.
+    requests: Arc<Mutex<Vec<String>>>,
+    /// The page target ids.
+    targets: Arc<Mutex<Vec<String>>>,
+}
+
+impl FakeChrome {
+    /// Listen on a free port of the loopback.
+    pub async fn start(name: &str) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let chrome = Self {
+            port: listener.local_addr().unwrap().port().into(),
+            name: name.to_string(),
+            requests: Default::default(),
+            targets: Default::default(),
+        };
+        let server = chrome.clone();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let chrome = server.clone();
+
+                tokio::spawn(async move {
+                    let service = service_fn(move |req| {
+                        let chrome = chrome.clone();
+                        async move { Ok::<_, Infallible>(chrome.handle(req)) }
+                    });
+
+                    let _ = hyper::server::conn::http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .with_upgrades()
+                        .await;
+                });
+            }
+        });
+
+        chrome
+    }
+
+    /// The browser id of the `/json/version` websocket url.
+    pub fn browser_id(&self) -> String {
+        format!("browser-{}", self.name)
+    }
+
+    /// The requests received.
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Open a page target and return the id.
+    pub fn open(&self) -> String {
+        let mut targets = self.targets.lock().unwrap();
+        let id = format!("{}-{}", self.name, targets.len() + 1);
+        targets.push(id.clone());
+        id
+    }
+
+    /// The target json.
+    fn target(&self, id: &str) -> serde_json::Value {
+        serde_json::json!({
+            "id": id,
+            "type": "page",
+            "webSocketDebuggerUrl": format!("ws://127.0.0.1:{}/devtools/page/{}", self.port, id),
+        })
+    }
+
+    /// Answer the request.
+    fn handle(&self, mut req: Request<Incoming>) -> Response<Full<Bytes>> {
+        self.requests
+            .lock()
+            .unwrap()
+            .push(format!("{} {}", req.method(), req.uri()));
+
+        if let Some(key) = req.headers().get(SEC_WEBSOCKET_KEY) {
+            let accept = derive_accept_key(key.as_bytes());
+            let on_upgrade = hyper::upgrade::on(&mut req);
+            let name = self.name.clone();
+
+            tokio::spawn(async move {
+                if let Ok(upgraded) = on_upgrade.await {
+                    let config = WebSocketConfig::default()
+                        .max_message_size(None)
+                        .max_frame_size(None);
+                    let socket = WebSocketStream::from_raw_socket(
+                        TokioIo::new(upgraded),
+                        Role::Server,
+                        Some(config),
+                    )
+                    .await;
+
+                    echo(socket, &name).await;
+                }
+            });
+
+            let mut response = reply(StatusCode::SWITCHING_PROTOCOLS, "");
+            let headers = response.headers_mut();
+            headers.insert(CONNECTION, "Upgrade".parse().unwrap());
+            headers.insert(UPGRADE, "websocket".parse().unwrap());
+            headers.insert(SEC_WEBSOCKET_ACCEPT, accept.parse().unwrap());
+            return response;
+        }
+
+        let path = req.uri().path();
+
+        match path {
+            "/json/version" => json(serde_json::json!({
+                "Browser": self.name,
+                "webSocketDebuggerUrl": format!(
+                    "ws://127.0.0.1:{}/devtools/browser/{}",
+                    self.port,
+                    self.browser_id()
+                ),
+            })),
+            "/json" | "/json/list" => {
+                let targets = self.targets.lock().unwrap().clone();
+                json(targets.iter().map(|id| self.target(id)).collect())
+            }
+            "/json/new" => {
+                let id = self.open();
+                json(self.target(&id))
+            }
+            "/json/protocol" => json(serde_json::json!({ "domains": [] })),
+            _ => {
+                let (action, id) = match path
+                    .strip_prefix("/json/close/")
+                    .map(|id| ("closing", id))
+                    .or_else(|| {
+                        path.strip_prefix("/json/activate/")
+                            .map(|id| ("activated", id))
+                    }) {
+                    Some(target) => target,
+                    _ => return reply(StatusCode::NOT_FOUND, "Not Found"),
+                };
+
+                let mut targets = self.targets.lock().unwrap();
+
+                match targets.iter().position(|target| target == id) {
+                    Some(index) => {
+                        if action == "closing" {
+                            targets.remove(index);
+                        }
+                        reply(StatusCode::OK, &format!("Target is {}", action))
+                    }
+                    _ => reply(StatusCode::NOT_FOUND, &format!("No such target id: {}", id)),
+                }
+            }
+        }
+    }
+}
+
+/// Answer `Browser.getVersion` with the browser name and echo the other messages until the socket closes.
+async fn echo<S>(mut socket: WebSocketStream<S>, name: &str)
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    while let Some(Ok(message)) = socket.next().await {
+        let reply = match &message {
+            Message::Text(text) => {
+                let command: serde_json::Value = serde_json::from_str(text).unwrap_or_default();
+
+                if command["method"] == "Browser.getVersion" {
+                    Message::text(
+                        serde_json::json!({ "id": command["id"], "result": { "product": name } })
+                            .to_string(),
+                    )
+                } else {
+                    message
+                }
+            }
+            Message::Binary(_) => message,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+
+        if socket.send(reply).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// The json response.
+fn json(body: serde_json::Value) -> Response<Full<Bytes>> {
+    let mut response = reply(StatusCode::OK, &body.to_string());
+    response.headers_mut().insert(
+        hyper::header::CONTENT_TYPE,
+        "application/json".parse().unwrap(),
+    );
+    response
+}
+
+/// The plain text response with the status.
+fn reply(status: StatusCode, body: &str) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::from(body.to_string())));
+    *response.status_mut() = status;
+    response
+}
+
+/// A port of the loopback refusing connections.
+pub(crate) fn closed_port() -> u32 {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().port().into()
+}
+
+/// A port accepting connections that never answer.
+pub(crate) async fn hung_port() -> u32 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port().into();
+
+    tokio::spawn(async move {
+        let mut held = vec![];
+
+        while let Ok((stream, _)) = listener.accept().await {
+            held.push(stream);
+        }
+    });
+
+    port
+}
+
+/// An executable shell script standing in for chrome. The script of a body is shared by the tests and replaced with a
+/// rename so a running script is never written.
+#[cfg(unix)]
+pub(crate) fn script(body: &str) -> String {
+    use std::os::unix::fs::PermissionsExt;
+
+    let name: String = body.chars().filter(char::is_ascii_alphanumeric).collect();
+    let path = std::env::temp_dir().join(format!("headless_browser_{}.sh", name));
+    let staged = path.with_extension(format!("{:016x}", rand::random::<u64>()));
+
+    std::fs::write(&staged, format!("#!/bin/sh\n{}\n", body)).unwrap();
+    std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o755)).unwrap();
+    std::fs::rename(&staged, &path).unwrap();
+    path.display().to_string()
+}
@@ -0,0 +1,273 @@
+use crate::config::Config;
+use openssl::error::ErrorStack;
+use openssl::ssl::{Ssl, SslAcceptor, SslFiletype, SslMethod};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpStream;
+use tokio_openssl::SslStream;
+
+/// The time a client has to complete the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The modified times of the certificate and key files.
+type Modified = (Option<SystemTime>, Option<SystemTime>);
+
+/// The certificate loaded.
+struct Loaded {
+    /// The modified times of the files loaded.
+    modified: Modified,
+    /// The acceptor with the certificate.
+    acceptor: Arc<SslAcceptor>,
+}
+
+/// The TLS termination of the listeners. The certificate reloads when the files change so rotated certificates apply to the new connections.
+pub(crate) struct Tls {
+    /// The PEM certificate chain.
+    cert: PathBuf,
+    /// The PEM private key.
+    key: PathBuf,
+    /// The certificate in use.
+    loaded: RwLock<Option<Loaded>>,
+    /// The modified times of the files that failed to load. The reload is retried once the files change again.
+    failed: Mutex<Option<Modified>>,
+}
+
+impl std::fmt::Debug for Tls {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tls")
+            .field("cert", &self.cert)
+            .field("key", &self.key)
+            .finish()
+    }
+}
+
+impl Tls {
+    /// The TLS termination of the config. None when the certificate is not configured.
+    pub(crate) fn new(config: &Config) -> Option<Self> {
+        match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) => Some(Self {
+                cert: cert.into(),
+                key: key.into(),
+                loaded: RwLock::new(None),
+                failed: Mutex::new(None),
+            }),
+            _ => None,
+        }
+    }
+
+    /// The modified times of the files.
+    fn modified(&self) -> Modified {
+        let modified = |path: &PathBuf| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+
+        (modified(&self.cert), modified(&self.key))
+    }
+
+    /// Load the certificate and key.
+    pub(crate) fn load(&self) -> Result<(), ErrorStack> {
+        let modified = self.modified();
+        let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())?;
+
+        builder.set_certificate_chain_file(&self.cert)?;
+        builder.set_private_key_file(&self.key, SslFiletype::PEM)?;
+        builder.check_private_key()?;
+
+        if let Ok(mut loaded) = self.loaded.write() {
+            *loaded = Some(Loaded {
+                modified,
+                acceptor: Arc::new(builder.build()),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// The acceptor reloaded when the files changed. The previous certificate is kept when the reload fails and the files
+    /// that failed are not loaded again until they change.
+    fn acceptor(&self) -> Option<Arc<SslAcceptor>> {
+        let modified = self.modified();
+        let current = self.loaded.read().ok().and_then(|loaded| {
+            loaded
+                .as_ref()
+                .map(|loaded| (loaded.modified, loaded.acceptor.clone()))
+        });
+
+        let failed = self
+            .failed
+            .lock()
+            .is_ok_and(|failed| *failed == Some(modified));
+
+        match current {
+            Some((loaded, acceptor)) if loaded == modified => Some(acceptor),
+            current if failed => current.map(|(_, acceptor)| acceptor),
+            current => match self.load() {
+                Ok(_) => {
+                    if let Ok(mut failed) = self.failed.lock() {
+                        *failed = None;
+                    }
+
+                    tracing::info!("Reloaded the TLS certificate {}", self.cert.display());
+                    self.loaded
+                        .read()
+                        .ok()
+                        .and_then(|loaded| loaded.as_ref().map(|l| l.acceptor.clone()))
+                }
+                Err(e) => {
+                    if let Ok(mut failed) = self.failed.lock() {
+                        *failed = Some(modified);
+                    }
+
+                    tracing::error!(
+                        "Failed to reload the TLS certificate {}: {}",
+                        self.cert.display(),
+                        e
+                    );
+                    current.map(|(_, acceptor)| acceptor)
+                }
+            },
+        }
+    }
+
+    /// Complete the TLS handshake of the connection.
+    pub(crate) async fn accept(&self, stream: TcpStream) -> std::io::Result<SslStream<TcpStream>> {
+        let acceptor = self
+            .acceptor()
+            .ok_or_else(|| std::io::Error::other("no TLS certificate loaded"))?;
+
+        let ssl = Ssl::new(acceptor.context()).map_err(std::io::Error::other)?;
+        let mut stream = SslStream::new(ssl, stream).map_err(std::io::Error::other)?;
+
+        match tokio::time::timeout(HANDSHAKE_TIMEOUT, Pin::new(&mut stream).accept()).await {
+            Ok(Ok(_)) => Ok(stream),
+            Ok(Err(e)) => Err(std::io::Error::other(e)),
+            Err(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "TLS handshake timed out",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+    use openssl::x509::{X509NameBuilder, X509};
+    use std::fs::File;
+
+    /// A self signed certificate and key in PEM.
+    fn certificate() -> (Vec<u8>, Vec<u8>) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+
+        (
+            cert.build().to_pem().unwrap(),
+            key.private_key_to_pem_pkcs8().unwrap(),
+        )
+    }
+
+    /// The TLS termination of the files in a new temporary directory.
+    fn tls() -> (Tls, PathBuf, PathBuf) {
+        let dir = std::env::temp_dir().join(format!(
+            "headless_browser_tls_{:016x}",
+            rand::random::<u64>()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let cert = dir.join("cert.pem");
+        let key = dir.join("key.pem");
+        let tls = Tls::new(&Config {
+            tls_cert: Some(cert.display().to_string()),
+            tls_key: Some(key.display().to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+
+        (tls, cert, key)
+    }
+
+    /// Write the file with the modified time.
+    fn write(path: &PathBuf, contents: &[u8], modified: SystemTime) {
+        std::fs::write(path, contents).unwrap();
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    #[test]
+    fn reloads_when_the_files_change() {
+        let (tls, cert, key) = tls();
+        let start = SystemTime::now() - Duration::from_secs(60);
+        let (pem, private) = certificate();
+
+        write(&cert, &pem, start);
+        write(&key, &private, start);
+        tls.load().unwrap();
+
+        let first = tls.acceptor().unwrap();
+        assert!(Arc::ptr_eq(&first, &tls.acceptor().unwrap()));
+
+        let (pem, private) = certificate();
+        write(&cert, &pem, start + Duration::from_secs(1));
+        write(&key, &private, start + Duration::from_secs(1));
+
+        let second = tls.acceptor().unwrap();
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert!(Arc::ptr_eq(&second, &tls.acceptor().unwrap()));
+
+        let _ = std::fs::remove_dir_all(cert.parent().unwrap());
+    }
+
+    #[test]
+    fn failed_reloads_wait_for_the_files_to_change() {
+        let (tls, cert, key) = tls();
+        let start = SystemTime::now() - Duration::from_secs(60);
+        let (pem, private) = certificate();
+
+        write(&cert, &pem, start);
+        write(&key, &private, start);
+        tls.load().unwrap();
+        let first = tls.acceptor().unwrap();
+
+        let broken = start + Duration::from_secs(1);
+        write(&cert, b"not a certificate", broken);
+
+        assert!(Arc::ptr_eq(&first, &tls.acceptor().unwrap()));
+        assert_eq!(*tls.failed.lock().unwrap(), Some(tls.modified()));
+
+        // a valid certificate with the modified time that failed is not loaded.
+        let (pem, private) = certificate();
+        write(&cert, &pem, broken);
+        write(&key, &private, start);
+
+        assert!(Arc::ptr_eq(&first, &tls.acceptor().unwrap()));
+
+        write(&key, &private, start + Duration::from_secs(2));
+
+        let second = tls.acceptor().unwrap();
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert_eq!(*tls.failed.lock().unwrap(), None);
+
+        let _ = std::fs::remove_dir_all(cert.parent().unwrap());
+    }
+}
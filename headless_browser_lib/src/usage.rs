@@ -0,0 +1,206 @@
+use crate::pool::Instance;
+use std::collections::HashMap;
+use sysinfo::{Pid, ProcessRefreshKind, ProcessesToUpdate, System};
+
+/// The resource usage of an instance summed across the chrome process tree.
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct InstanceUsage {
+    /// The process id.
+    pub pid: u32,
+    /// The remote debugging port.
+    pub port: u32,
+    /// The cpu usage in percent of a core since the last sample.
+    pub cpu_percent: f32,
+    /// The resident memory in bytes.
+    pub memory_bytes: u64,
+    /// The open file descriptors. None when the platform does not report them.
+    pub open_fds: Option<u64>,
+    /// The processes in the tree including chrome.
+    pub processes: usize,
+}
+
+/// Samples the process trees of the instances. The cpu usage is measured between samples so the sampler is kept.
+#[derive(Debug)]
+pub struct Sampler {
+    /// The system processes.
+    system: System,
+}
+
+impl Default for Sampler {
+    fn default() -> Self {
+        Self {
+            system: System::new(),
+        }
+    }
+}
+
+impl Sampler {
+    /// Refresh the processes and sum the usage of each instance tree.
+    pub fn sample(&mut self, instances: &[Instance]) -> Vec<InstanceUsage> {
+        self.system.refresh_processes_specifics(
+            ProcessesToUpdate::All,
+            true,
+            ProcessRefreshKind::nothing().with_cpu().with_memory(),
+        );
+
+        let mut children: HashMap<Pid, Vec<Pid>> = HashMap::new();
+
+        for (pid, process) in self.system.processes() {
+            // threads are reported as processes on linux.
+            if process.thread_kind().is_some() {
+                continue;
+            }
+            if let Some(parent) = process.parent() {
+                children.entry(parent).or_default().push(*pid);
+            }
+        }
+
+        instances
+            .iter()
+            .map(|instance| {
+                let mut usage = InstanceUsage {
+                    pid: instance.pid,
+                    port: instance.port,
+                    ..Default::default()
+                };
+                let mut stack = vec![Pid::from_u32(instance.pid)];
+
+                while let Some(pid) = stack.pop() {
+                    if let Some(process) = self.system.process(pid) {
+                        usage.cpu_percent += process.cpu_usage();
+                        usage.memory_bytes += process.memory();
+                        usage.processes += 1;
+
+                        if let Some(fds) = open_fds(pid.as_u32()) {
+                            usage.open_fds = Some(usage.open_fds.unwrap_or_default() + fds);
+                        }
+                    }
+                    if let Some(pids) = children.get(&pid) {
+                        stack.extend(pids);
+                    }
+                }
+
+                usage
+            })
+            .collect()
+    }
+}
+
+/// The open file descriptors of the process.
+#[cfg(target_os = "linux")]
+fn open_fds(pid: u32) -> Option<u64> {
+    std::fs::read_dir(format!("/proc/{}/fd", pid))
+        .ok()
+        .map(|entries| entries.count() as u64)
+}
+
+/// The open file descriptors of the process.
+#[cfg(not(target_os = "linux"))]
+fn open_fds(_pid: u32) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool::Pool;
+
+    /// The instance of the process.
+    fn instance(pid: u32) -> Instance {
+        let pool = Pool::new(9300..=9310, &[], Default::default());
+        pool.insert(pid, 9300, Default::default(), Default::default(), None);
+        pool.get(pid).unwrap()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn usage_sums_the_process_tree() {
+        use std::os::unix::process::CommandExt;
+
+        let mut child = std::process::Command::new("sh")
+            .args(["-c", "sleep 30 & sleep 30 & wait"])
+            .process_group(0)
+            .spawn()
+            .unwrap();
+        let instance = instance(child.id());
+        let mut sampler = Sampler::default();
+        let start = std::time::Instant::now();
+        let mut usage = sampler.sample(std::slice::from_ref(&instance));
+
+        while usage[0].processes < 3 && start.elapsed() < std::time::Duration::from_secs(5) {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            usage = sampler.sample(std::slice::from_ref(&instance));
+        }
+
+        crate::kill_process_group(child.id());
+        let _ = child.wait();
+
+        assert_eq!(usage[0].pid, child.id());
+        assert_eq!(usage[0].port, 9300);
+        assert_eq!(usage[0].processes, 3);
+        assert!(usage[0].memory_bytes > 0);
+
+        if cfg!(target_os = "linux") {
+            assert!(usage[0].open_fds.unwrap() >= 3 * 3);
+        }
+    }
+
+    #[test]
+    fn exited_processes_have_no_usage() {
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+
+        let usage = Sampler::default().sample(&[instance(pid)]);
+
+        assert_eq!(usage[0].processes, 0);
+        assert_eq!(usage[0].memory_bytes, 0);
+        assert_eq!(usage[0].open_fds, None);
+    }
+
+    /// The open file descriptors of a sleep started by the shell script once the script replaced itself with the sleep.
+    #[cfg(target_os = "linux")]
+    fn sleep_fds(script: &str) -> Option<u64> {
+        let mut child = std::process::Command::new("sh")
+            .args(["-c", script])
+            .spawn()
+            .unwrap();
+        let comm = format!("/proc/{}/comm", child.id());
+        let start = std::time::Instant::now();
+
+        while std::fs::read_to_string(&comm).unwrap_or_default().trim() != "sleep"
+            && start.elapsed() < std::time::Duration::from_secs(5)
+        {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        // the descriptors closed on exec can still be open right after the name changes.
+        let mut fds = open_fds(child.id());
+
+        while start.elapsed() < std::time::Duration::from_secs(5) {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+
+            let next = open_fds(child.id());
+
+            if next == fds {
+                break;
+            }
+            fds = next;
+        }
+
+        let _ = child.kill();
+        let _ = child.wait();
+
+        fds
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn open_fds_count_the_descriptors() {
+        let base = sleep_fds("exec sleep 30").unwrap();
+        let opened = sleep_fds("exec 7</dev/null 8</dev/null 9</dev/null; exec sleep 30").unwrap();
+
+        assert_eq!(opened, base + 3);
+        assert_eq!(open_fds(u32::MAX), None);
+    }
+}
@@ -13,4 +13,4 @@ echo "Starting Xvfb"
 Xvfb :0 -screen 0 1024x768x16 -nolisten tcp &
 sleep 1
 
-exec headless_browser $DEFAULT_LAUNCH_NAME $REMOTE_ADDRESS $LAUNCH $DEFAULT_PORT $DEFAULT_PORT_SERVER "false"
\ No newline at end of file
+exec headless_browser --chrome-path "$DEFAULT_LAUNCH_NAME" --address "$REMOTE_ADDRESS" --launch "$LAUNCH" --port "$DEFAULT_PORT" --server-port "$DEFAULT_PORT_SERVER" --headless false
\ No newline at end of file
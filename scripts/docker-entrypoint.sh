@@ -8,4 +8,4 @@ DEFAULT_PORT="${DEFAULT_PORT:-9223}";
 DEFAULT_PORT_SERVER="${DEFAULT_PORT_SERVER:-6000}";
 DEFAULT_LAUNCH_NAME="${DEFAULT_LAUNCH_NAME:-chromium-browser}";
 
-exec headless_browser $DEFAULT_LAUNCH_NAME $REMOTE_ADDRESS $LAUNCH $DEFAULT_PORT $DEFAULT_PORT_SERVER "true"
\ No newline at end of file
+exec headless_browser --chrome-path "$DEFAULT_LAUNCH_NAME" --address "$REMOTE_ADDRESS" --launch "$LAUNCH" --port "$DEFAULT_PORT" --server-port "$DEFAULT_PORT_SERVER"
\ No newline at end of file